@@ -0,0 +1,1238 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use libsql::{Connection, Value};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::time::Instant;
+
+use crate::batching::{BatchController, BatchOptions, MAX_BATCH_BYTES};
+use crate::change::{quote_ident, sql_literal, Change, RowChange, RowOp};
+use crate::coalesce::coalesce_changes;
+use crate::guard::GuardOptions;
+use crate::journal::JournalFile;
+use crate::prepared::{BoundStatement, StatementCache};
+use crate::protect::ProtectOptions;
+use crate::retry::{self, with_retry};
+use crate::shutdown;
+use crate::sql::make_create_statement_idempotent;
+
+/// Remote table that holds staged row changes until the final commit
+pub const STAGE_TABLE: &str = "_turso_sync_stage";
+
+/// Remote table with the statements each running apply has committed. Every batch
+/// updates its row in the same transaction, so after an error that leaves the commit
+/// unconfirmed, such as a timeout, the row tells whether the batch landed.
+pub const PROGRESS_TABLE: &str = "_turso_sync_progress";
+
+/// Statements per round trip inside an atomic transaction
+const TRANSACTION_BATCH_SIZE: usize = 500;
+
+/// Stage rows per INSERT while uploading a staged diff; five bound values each
+/// keeps a statement within SQLite's historical 999 variable limit
+const STAGE_ROWS_PER_INSERT: usize = 199;
+
+/// Options controlling how a diff is applied
+#[derive(Args, Debug, Clone)]
+pub struct ApplyOptions {
+    /// Apply the whole diff all-or-nothing instead of committing batches independently
+    #[arg(long)]
+    pub atomic: bool,
+
+    /// Largest diff applied in a single remote transaction; larger atomic diffs are staged first
+    #[arg(long, default_value = "5000", env = "TURSO_SYNC_MAX_TRANSACTION_STATEMENTS")]
+    pub max_transaction_statements: usize,
+
+    /// Continue an interrupted apply from its journal instead of starting over
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Print the plan of what would be applied without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    #[command(flatten)]
+    pub guard: GuardOptions,
+
+    #[command(flatten)]
+    pub protect: ProtectOptions,
+
+    #[command(flatten)]
+    pub batching: BatchOptions,
+}
+
+impl Default for ApplyOptions {
+    fn default() -> Self {
+        ApplyOptions {
+            atomic: false,
+            max_transaction_statements: 5000,
+            resume: false,
+            dry_run: false,
+            guard: GuardOptions::default(),
+            protect: ProtectOptions::default(),
+            batching: BatchOptions::default(),
+        }
+    }
+}
+
+/// Diffs larger than this are pushed in grouped batches rather than as one phase
+const GROUPED_PUSH_THRESHOLD: usize = 1000;
+
+/// How a diff gets executed against its target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyMode {
+    /// Coalesced statements in independently committed batches, in diff order
+    Single,
+    /// Grouped by operation and table, each batch committed independently
+    Grouped,
+    /// All statements inside one transaction
+    Transaction,
+    /// Uploaded to the staging table, then moved into place in one transaction
+    Staged,
+    /// A changeset applied by SQLite's session extension in one local transaction
+    Session,
+}
+
+impl ApplyMode {
+    /// Mode `push` uses for a diff of `len` changes
+    pub fn for_push(options: &ApplyOptions, len: usize) -> Self {
+        if options.atomic {
+            ApplyMode::atomic(options, len)
+        } else if len > GROUPED_PUSH_THRESHOLD {
+            ApplyMode::Grouped
+        } else {
+            ApplyMode::Single
+        }
+    }
+
+    /// Mode `apply-diff` uses for a diff of `len` changes; local applies never stage
+    pub fn for_apply_diff(options: &ApplyOptions, len: usize, no_sync: bool) -> Self {
+        match (options.atomic, no_sync) {
+            (true, true) => ApplyMode::Transaction,
+            (true, false) => ApplyMode::atomic(options, len),
+            (false, _) => ApplyMode::Grouped,
+        }
+    }
+
+    fn atomic(options: &ApplyOptions, len: usize) -> Self {
+        if len <= options.max_transaction_statements {
+            ApplyMode::Transaction
+        } else {
+            ApplyMode::Staged
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            ApplyMode::Single => "coalesced statements, batches committed independently",
+            ApplyMode::Grouped => "grouped by table and operation, batches committed independently",
+            ApplyMode::Transaction => "atomic, one transaction",
+            ApplyMode::Staged => "atomic, staged upload then one transaction",
+            ApplyMode::Session => "session extension, one local transaction",
+        }
+    }
+}
+
+/// What applying a diff would do, worked out without touching the target
+#[derive(Debug)]
+pub struct ApplyPlan {
+    pub mode: ApplyMode,
+    /// DELETE, UPDATE and INSERT counts per table
+    pub tables: BTreeMap<String, [usize; 3]>,
+    /// Schema statements as they would be executed
+    pub schema: Vec<String>,
+    /// Statements sent after coalescing, staging uploads included
+    pub statements: usize,
+    /// Round trips: committed batches at their starting size, or batches within the transaction
+    pub batches: usize,
+    /// Rows written, values uploaded to the staging table included
+    pub rows_written: usize,
+}
+
+/// Plan the execution of `changes` exactly as `mode` would run them
+pub fn plan(changes: &[Change], mode: ApplyMode) -> ApplyPlan {
+    let mut tables: BTreeMap<String, [usize; 3]> = BTreeMap::new();
+    let mut schema = Vec::new();
+    for change in changes {
+        match change {
+            Change::Row(row) => tables.entry(row.table.clone()).or_default()[op_rank(row.op)] += 1,
+            // Grouped execution makes CREATE statements idempotent on its own
+            Change::Schema(sql) if mode == ApplyMode::Grouped && sql.trim_start().starts_with("CREATE") => {
+                schema.push(make_create_statement_idempotent(sql))
+            }
+            Change::Schema(sql) => schema.push(sql.clone()),
+        }
+    }
+    let row_changes = changes.len() - schema.len();
+
+    let (statements, batches, rows_written) = match mode {
+        ApplyMode::Single => {
+            let coalesced = coalesce_changes(changes);
+            let batch_count = batches(&coalesced, changes.len().max(1)).len();
+            (coalesced.len(), batch_count, row_changes)
+        }
+        ApplyMode::Grouped => {
+            let phases = group_changes(changes);
+            let statements = phases.iter().map(|p| p.statements.len()).sum();
+            let batch_count = phases.iter().map(|p| batches(&p.statements, p.batch_size).len()).sum();
+            (statements, batch_count, row_changes)
+        }
+        ApplyMode::Transaction => {
+            let coalesced = coalesce_changes(changes);
+            let batch_count = batches(&coalesced, TRANSACTION_BATCH_SIZE).len();
+            (coalesced.len(), batch_count, row_changes)
+        }
+        ApplyMode::Staged => {
+            let stage_plan = StagePlan::build(changes);
+            let staged_values: usize =
+                stage_plan.groups.iter().flat_map(|g| &g.rows).map(|row| row.key.len() + row.values.len()).sum();
+            let uploads = staged_values.div_ceil(STAGE_ROWS_PER_INSERT);
+            // The final transaction runs every step and clears the staging rows
+            (uploads + stage_plan.steps.len() + 1, uploads + 1, row_changes + staged_values)
+        }
+        ApplyMode::Session => (changes.len(), 1, row_changes),
+    };
+
+    ApplyPlan { mode, tables, schema, statements, batches, rows_written }
+}
+
+impl fmt::Display for ApplyPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [mut deletes, mut updates, mut inserts] = [0; 3];
+        for (table, [delete, update, insert]) in &self.tables {
+            writeln!(f, "  # {}", table)?;
+            for (symbol, count, what) in [("+", insert, "insert"), ("~", update, "update"), ("-", delete, "delete")] {
+                if *count > 0 {
+                    writeln!(f, "      {} {} {}{}", symbol, count, what, if *count == 1 { "" } else { "s" })?;
+                }
+            }
+            deletes += delete;
+            updates += update;
+            inserts += insert;
+        }
+        if !self.schema.is_empty() {
+            writeln!(f, "  # schema")?;
+            for statement in &self.schema {
+                writeln!(f, "      ! {}", preview(statement))?;
+            }
+        }
+        writeln!(f)?;
+        writeln!(
+            f,
+            "Plan: {} to add, {} to change, {} to destroy, {} schema changes.",
+            inserts,
+            updates,
+            deletes,
+            self.schema.len()
+        )?;
+        write!(
+            f,
+            "Execution: {} statements in {} batches ({}), about {} rows written.",
+            self.statements,
+            self.batches,
+            self.mode.describe(),
+            self.rows_written
+        )
+    }
+}
+
+/// Apply changes so that either all of them land or none are visible.
+///
+/// Diffs up to `max_transaction_statements` run inside one interactive transaction.
+/// Larger diffs are uploaded into a staging table in independent batches, then
+/// moved into place by a handful of set-based statements in one short transaction.
+/// An interrupted upload continues from the journal instead of starting over.
+/// A transaction whose commit went unconfirmed is checked against the remote
+/// progress before it is run again.
+pub async fn apply_atomic(
+    conn: &Connection,
+    changes: &[Change],
+    max_transaction_statements: usize,
+    journal: &mut JournalFile,
+) -> Result<()> {
+    if changes.len() <= max_transaction_statements {
+        let apply_id = journal.apply_id();
+        create_progress_table(conn).await?;
+        if journal.resumed() && remote_progress(conn, apply_id).await? >= changes.len() {
+            info!("✅ The interrupted run already committed the transaction");
+            clear_progress(conn, apply_id).await;
+            return Ok(());
+        }
+        // A failed transaction is rolled back and run again as a whole, unless it committed
+        let progress = progress_statement(apply_id, changes.len());
+        let mut attempt = 1;
+        while let Err(e) = run_transaction(conn, changes, Some(&progress)).await {
+            let landed = landed(conn, apply_id, changes.len(), &e)
+                .await
+                .context("The transaction may have committed; rerun with --resume to check")?;
+            if landed {
+                break;
+            }
+            let Some(delay) = retry::policy().backoff("Atomic transaction", attempt, &e) else {
+                return Err(e);
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+        clear_progress(conn, apply_id).await;
+        Ok(())
+    } else {
+        info!(
+            "Diff has {} statements (single transaction limit {}), using staged apply",
+            changes.len(),
+            max_transaction_statements
+        );
+        apply_staged(conn, changes, journal).await
+    }
+}
+
+/// Execute all changes inside one transaction, rolling back on the first failure
+pub async fn apply_in_transaction(conn: &Connection, changes: &[Change]) -> Result<()> {
+    run_transaction(conn, changes, None).await
+}
+
+/// [`apply_in_transaction`], also executing `progress` before the commit
+async fn run_transaction(conn: &Connection, changes: &[Change], progress: Option<&BoundStatement>) -> Result<()> {
+    let coalesced = coalesce_changes(changes);
+    let batches = batches(&coalesced, TRANSACTION_BATCH_SIZE);
+    let total_batches = batches.len();
+
+    info!(
+        "Applying {} statements ({} after coalescing) in one transaction ({} batches)",
+        changes.len(),
+        coalesced.len(),
+        total_batches
+    );
+
+    let tx = conn.transaction().await.context("Failed to begin transaction")?;
+    let mut cache = StatementCache::new(&tx);
+    let mut failure = None;
+    for (batch_num, batch) in batches.into_iter().enumerate() {
+        info!("Transaction batch {}/{} ({} statements)", batch_num + 1, total_batches, batch.len());
+        if let Err(e) = cache.execute_all(batch).await {
+            failure = Some((batch_num, e));
+            break;
+        }
+    }
+    if let Some(progress) = progress.filter(|_| failure.is_none()) {
+        if let Err(e) = cache.execute(progress).await {
+            failure = Some((total_batches.saturating_sub(1), e));
+        }
+    }
+    debug!("Prepared {} distinct statements, sent {} requests", cache.prepared(), cache.requests());
+    drop(cache);
+
+    if let Some((batch_num, e)) = failure {
+        if let Err(rollback_err) = tx.rollback().await {
+            warn!("Rollback after failed batch also failed: {}", rollback_err);
+        }
+        return Err(e).with_context(|| {
+            format!("Failed to execute batch {}/{}, transaction rolled back", batch_num + 1, total_batches)
+        });
+    }
+
+    tx.commit().await.context("Failed to commit transaction")?;
+    info!("✅ Committed {} statements atomically", changes.len());
+    Ok(())
+}
+
+/// A run of statements executed in independently committed batches
+pub struct Phase {
+    pub label: String,
+    pub statements: Vec<BoundStatement>,
+    /// Diff statements covered, counted before coalescing
+    pub rows: usize,
+    /// Statements per batch to start with; later batches adapt to the observed latency
+    pub batch_size: usize,
+    /// Operation and table shared by every statement, for grouped row changes
+    pub target: Option<(RowOp, String)>,
+}
+
+impl Phase {
+    pub fn new(label: impl Into<String>, statements: Vec<BoundStatement>, batch_size: usize) -> Self {
+        Phase { label: label.into(), rows: statements.len(), statements, batch_size: batch_size.max(1), target: None }
+    }
+}
+
+/// Execute phases in order, each batch in its own transaction with one prepared statement
+/// per distinct SQL text. Batches are sized and paced by a [`BatchController`]. Statements
+/// the journal already records as applied are skipped, and every committed batch is recorded.
+/// A batch whose commit went unconfirmed is only run again if the remote progress shows
+/// it did not land.
+pub async fn execute_phases(
+    conn: &Connection,
+    phases: &[Phase],
+    batching: &BatchOptions,
+    journal: &mut JournalFile,
+) -> Result<()> {
+    let apply_id = journal.apply_id().to_string();
+    create_progress_table(conn).await?;
+    if journal.resumed() {
+        let committed = remote_progress(conn, &apply_id).await?;
+        if committed > journal.statements_applied() {
+            info!(
+                "The interrupted run committed {} statements it did not journal",
+                committed - journal.statements_applied()
+            );
+            journal.record_batch(committed - journal.statements_applied())?;
+        }
+    }
+    let already_applied = journal.statements_applied();
+    let mut offset = 0;
+    journal.set_total_statements(phases.iter().map(|p| p.statements.len()).sum());
+    let mut controller = BatchController::new(batching);
+
+    for phase in phases {
+        if phase.statements.is_empty() {
+            continue;
+        }
+        let total = phase.statements.len();
+        let skip = already_applied.saturating_sub(offset).min(total);
+        offset += total;
+        if skip == total {
+            debug!("{} already applied, skipping", phase.label);
+            continue;
+        }
+        info!("Executing {} {} statements in batches of {}...", total, phase.label, phase.batch_size);
+        controller.start_phase(phase.batch_size);
+
+        let mut done = skip;
+        let mut batch_num = 0;
+        let mut attempt = 1;
+        while done < total {
+            shutdown::check().with_context(|| {
+                format!(
+                    "Stopped before the next {} batch ({} statements applied so far, rerun with --resume to continue)",
+                    phase.label,
+                    journal.statements_applied()
+                )
+            })?;
+            let len = batch_len(&phase.statements[done..], controller.size(), controller.max_bytes());
+            let pending = &phase.statements[done..done + len];
+            let bytes: usize = pending.iter().map(|s| s.size()).sum();
+            if attempt == 1 {
+                batch_num += 1;
+                if let [statement] = pending {
+                    info!("{} {} ({}/{}): {}", phase.label, batch_num, done + 1, total, preview(&statement.sql));
+                } else {
+                    info!("{} batch {} ({} statements, {}/{})", phase.label, batch_num, len, done + len, total);
+                }
+            }
+
+            let started = Instant::now();
+            let applied = journal.statements_applied() + len;
+            if let Err(e) = execute_batch(conn, pending, &progress_statement(&apply_id, applied)).await {
+                // A timed-out batch may have committed; splitting or resending it would apply it twice
+                let landed = landed(conn, &apply_id, applied, &e).await.with_context(|| {
+                    format!(
+                        "{} batch {} may not have committed ({} statements applied before it, \
+                         rerun with --resume to check and continue)",
+                        phase.label,
+                        batch_num,
+                        journal.statements_applied()
+                    )
+                })?;
+                if landed {
+                    journal.record_batch(len)?;
+                    done += len;
+                    attempt = 1;
+                    continue;
+                }
+                // Oversized or slow batches are split before being retried as they are
+                if controller.shrink_after(len, bytes, &e) {
+                    tokio::time::sleep(controller.pause()).await;
+                    continue;
+                }
+                let what = format!("{} batch {}", phase.label, batch_num);
+                if let Some(delay) = retry::policy().backoff(&what, attempt, &e) {
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                    continue;
+                }
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to execute {} batch {} ({} statements applied so far, rerun with --resume to continue)",
+                        phase.label,
+                        batch_num,
+                        journal.statements_applied()
+                    )
+                });
+            }
+            controller.record_success(len, started.elapsed());
+            journal.record_batch(len)?;
+            done += len;
+            attempt = 1;
+
+            if done < total && !controller.pause().is_zero() {
+                debug!("Pausing {} ms for the server to catch up", controller.pause().as_millis());
+                tokio::time::sleep(controller.pause()).await;
+            }
+        }
+        info!("✅ Completed {} {} statements in {} batches", total, phase.label, batch_num);
+    }
+
+    clear_progress(conn, &apply_id).await;
+    Ok(())
+}
+
+/// Execute statements and then `progress` in one transaction, rolling back if any of them fails
+async fn execute_batch(conn: &Connection, statements: &[BoundStatement], progress: &BoundStatement) -> Result<()> {
+    let tx = conn.transaction().await.context("Failed to begin transaction")?;
+    let mut cache = StatementCache::new(&tx);
+    let result = match cache.execute_all(statements).await {
+        Ok(()) => cache.execute(progress).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    drop(cache);
+
+    match result {
+        Ok(()) => tx.commit().await.context("Failed to commit transaction"),
+        Err(e) => {
+            if let Err(rollback_err) = tx.rollback().await {
+                warn!("Rollback after failed batch also failed: {}", rollback_err);
+            }
+            Err(e)
+        }
+    }
+}
+
+async fn create_progress_table(conn: &Connection) -> Result<()> {
+    let sql =
+        format!("CREATE TABLE IF NOT EXISTS {} (apply_id TEXT PRIMARY KEY, applied INTEGER NOT NULL)", PROGRESS_TABLE);
+    with_retry("Creating the progress table", || async { Ok(conn.execute(&sql, ()).await?) })
+        .await
+        .context("Failed to create the progress table")?;
+    Ok(())
+}
+
+/// Statement recording that `apply_id` has committed its first `applied` statements
+fn progress_statement(apply_id: &str, applied: usize) -> BoundStatement {
+    BoundStatement {
+        sql: format!("INSERT OR REPLACE INTO {} (apply_id, applied) VALUES (?, ?)", PROGRESS_TABLE),
+        params: vec![Value::Text(apply_id.to_string()), Value::Integer(applied as i64)],
+    }
+}
+
+/// Statements `apply_id` has committed according to the remote, 0 when it has no row yet
+async fn remote_progress(conn: &Connection, apply_id: &str) -> Result<usize> {
+    let sql = format!("SELECT applied FROM {} WHERE apply_id = ?", PROGRESS_TABLE);
+    with_retry("Reading the apply progress", || async {
+        let mut rows = conn.query(&sql, [apply_id]).await?;
+        Ok(match rows.next().await? {
+            Some(row) => row.get::<i64>(0)? as usize,
+            None => 0,
+        })
+    })
+    .await
+    .context("Failed to read the apply progress")
+}
+
+/// Whether the batch that brings `apply_id` to `applied` statements committed even
+/// though it failed with `error`. Permanent errors come from a statement and roll the
+/// batch back; transient ones, such as a timeout, may have hit the commit.
+async fn landed(conn: &Connection, apply_id: &str, applied: usize, error: &anyhow::Error) -> Result<bool> {
+    if !retry::is_retryable(error) {
+        return Ok(false);
+    }
+    let landed = remote_progress(conn, apply_id).await? >= applied;
+    if landed {
+        warn!("The batch committed although the commit was not confirmed ({:#}); not sending it again", error);
+    }
+    Ok(landed)
+}
+
+/// Remove the progress row of a completed apply, and the table once no other apply uses it.
+/// The apply itself succeeded, so a failure here is only logged.
+async fn clear_progress(conn: &Connection, apply_id: &str) {
+    let clear = async {
+        let tx = conn.transaction().await?;
+        tx.execute(&format!("DELETE FROM {} WHERE apply_id = ?", PROGRESS_TABLE), [apply_id]).await?;
+        drop_if_empty(&tx, PROGRESS_TABLE).await?;
+        Ok::<_, anyhow::Error>(tx.commit().await?)
+    };
+    if let Err(e) = clear.await {
+        warn!("Could not remove the progress of apply {} from {}: {}", apply_id, PROGRESS_TABLE, e);
+    }
+}
+
+/// Execute changes grouped by operation and table, committing each batch independently
+pub async fn apply_grouped(
+    conn: &Connection,
+    changes: &[Change],
+    batching: &BatchOptions,
+    journal: &mut JournalFile,
+) -> Result<()> {
+    info!("Analyzing {} statements for batch optimization...", changes.len());
+
+    let phases = group_changes(changes);
+    let (table_counts, other_count) = phase_counts(&phases);
+
+    info!(
+        "Statement grouping complete ({} groups, {} statements after coalescing):",
+        phases.len(),
+        phases.iter().map(|p| p.statements.len()).sum::<usize>()
+    );
+    for (table, [deletes, updates, inserts]) in &table_counts {
+        info!("  - {}: {} DELETE, {} UPDATE, {} INSERT", table, deletes, updates, inserts);
+    }
+    info!("  - Schema and other statements: {}", other_count);
+
+    info!("Starting optimized execution...");
+    execute_phases(conn, &phases, batching, journal).await
+}
+
+/// Split changes into phases of row changes sharing operation and table.
+///
+/// Row changes between two schema (or unrecognized) statements are grouped; within
+/// each such run, deletes come first, then updates, then inserts, each in order of
+/// first appearance, and coalesced into set-based statements. Other statements run
+/// one at a time at their original position.
+fn group_changes(changes: &[Change]) -> Vec<Phase> {
+    let mut phases = Vec::new();
+    let mut run: Vec<(RowOp, &str, Vec<&Change>)> = Vec::new();
+
+    for change in changes {
+        match change {
+            Change::Row(row) => match run.iter_mut().find(|(op, table, _)| *op == row.op && *table == row.table) {
+                Some((_, _, rows)) => rows.push(change),
+                None => run.push((row.op, &row.table, vec![change])),
+            },
+            Change::Schema(sql) => {
+                flush_run(&mut phases, &mut run);
+                let statement = if sql.trim_start().starts_with("CREATE") {
+                    make_create_statement_idempotent(sql)
+                } else {
+                    sql.clone()
+                };
+                match phases.last_mut() {
+                    Some(phase) if phase.target.is_none() => {
+                        phase.statements.push(BoundStatement::raw(statement));
+                        phase.rows += 1;
+                    }
+                    _ => phases.push(Phase::new("SCHEMA", vec![BoundStatement::raw(statement)], 1)),
+                }
+            }
+        }
+    }
+    flush_run(&mut phases, &mut run);
+    phases
+}
+
+/// Per-table counts of the DELETE, UPDATE and INSERT statements `phases` cover, and
+/// the number of other statements
+fn phase_counts(phases: &[Phase]) -> (BTreeMap<&str, [usize; 3]>, usize) {
+    let mut table_counts: BTreeMap<&str, [usize; 3]> = BTreeMap::new();
+    let mut other_count = 0;
+    for phase in phases {
+        match phase.target {
+            Some((op, ref table)) => {
+                table_counts.entry(table.as_str()).or_default()[op_rank(op)] += phase.rows;
+            }
+            None => other_count += phase.rows,
+        }
+    }
+    (table_counts, other_count)
+}
+
+fn flush_run(phases: &mut Vec<Phase>, run: &mut Vec<(RowOp, &str, Vec<&Change>)>) {
+    let mut groups = std::mem::take(run);
+    groups.sort_by_key(|(op, _, _)| op_rank(*op));
+    for (op, table, rows) in groups {
+        // Simple DELETEs use much larger batches than INSERTs and UPDATEs
+        let batch_size = if op == RowOp::Delete { 1000 } else { 500 };
+        let mut phase =
+            Phase::new(format!("{} {}", op_label(op), table), coalesce_changes(rows.iter().copied()), batch_size);
+        phase.rows = rows.len();
+        phase.target = Some((op, table.to_string()));
+        phases.push(phase);
+    }
+}
+
+/// Split statements into batches of at most `max_count` statements and about
+/// `MAX_BATCH_BYTES` of payload; a larger statement gets a batch of its own
+fn batches(statements: &[BoundStatement], max_count: usize) -> Vec<&[BoundStatement]> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    while start < statements.len() {
+        let len = batch_len(&statements[start..], max_count, MAX_BATCH_BYTES);
+        batches.push(&statements[start..start + len]);
+        start += len;
+    }
+    batches
+}
+
+/// Number of leading statements that fit in a batch of at most `max_count`
+/// statements and `max_bytes` of payload; always at least one
+fn batch_len(statements: &[BoundStatement], max_count: usize, max_bytes: usize) -> usize {
+    let mut bytes = 0;
+    for (i, statement) in statements.iter().enumerate() {
+        if i > 0 && (i == max_count || bytes + statement.size() > max_bytes) {
+            return i;
+        }
+        bytes += statement.size();
+    }
+    statements.len()
+}
+
+/// Execution order of row operations within a run
+fn op_rank(op: RowOp) -> usize {
+    match op {
+        RowOp::Delete => 0,
+        RowOp::Update => 1,
+        RowOp::Insert => 2,
+    }
+}
+
+fn op_label(op: RowOp) -> &'static str {
+    match op {
+        RowOp::Delete => "DELETE",
+        RowOp::Update => "UPDATE",
+        RowOp::Insert => "INSERT",
+    }
+}
+
+/// First 100 characters of a statement for logging
+fn preview(statement: &str) -> String {
+    match statement.char_indices().nth(100) {
+        Some((end, _)) => format!("{}...", &statement[..end]),
+        None => statement.to_string(),
+    }
+}
+
+/// One step of the final staged commit
+enum StageStep<'a> {
+    /// Statement executed as-is (schema changes, unrecognized statements)
+    Raw(&'a str),
+    /// A staged group of row changes moved into place with set-based SQL
+    Group(usize),
+}
+
+/// Row changes sharing table, operation and column layout
+struct StageGroup {
+    table: String,
+    op: RowOp,
+    key: Vec<String>,
+    columns: Vec<String>,
+    rows: Vec<RowChange>,
+}
+
+/// A diff broken down into staged groups and in-order steps
+struct StagePlan<'a> {
+    steps: Vec<StageStep<'a>>,
+    groups: Vec<StageGroup>,
+}
+
+impl<'a> StagePlan<'a> {
+    /// Group consecutive row changes between raw statements. Within each run, deletes
+    /// come first, then updates, then inserts, each in order of first appearance.
+    fn build(changes: &'a [Change]) -> Self {
+        let mut plan = StagePlan { steps: Vec::new(), groups: Vec::new() };
+        let mut run: Vec<StageGroup> = Vec::new();
+        let mut index: HashMap<(RowOp, String, Vec<String>, Vec<String>), usize> = HashMap::new();
+
+        for change in changes {
+            match change {
+                Change::Row(change) => {
+                    let key: Vec<String> = change.key.iter().map(|(c, _)| c.clone()).collect();
+                    let columns: Vec<String> = change.values.iter().map(|(c, _)| c.clone()).collect();
+                    let id = (change.op, change.table.clone(), key.clone(), columns.clone());
+                    let slot = *index.entry(id).or_insert_with(|| {
+                        run.push(StageGroup {
+                            table: change.table.clone(),
+                            op: change.op,
+                            key,
+                            columns,
+                            rows: Vec::new(),
+                        });
+                        run.len() - 1
+                    });
+                    run[slot].rows.push(change.clone());
+                }
+                Change::Schema(sql) => {
+                    plan.flush_run(&mut run);
+                    index.clear();
+                    plan.steps.push(StageStep::Raw(sql));
+                }
+            }
+        }
+        plan.flush_run(&mut run);
+        plan
+    }
+
+    fn flush_run(&mut self, run: &mut Vec<StageGroup>) {
+        let mut groups = std::mem::take(run);
+        groups.sort_by_key(|g| op_rank(g.op));
+        for group in groups {
+            self.steps.push(StageStep::Group(self.groups.len()));
+            self.groups.push(group);
+        }
+    }
+}
+
+/// Upload row changes to the staging table, then apply them in one short transaction
+async fn apply_staged(conn: &Connection, changes: &[Change], journal: &mut JournalFile) -> Result<()> {
+    let plan = StagePlan::build(changes);
+    // Derived from the diff so a resumed run finds the rows it already uploaded
+    let apply_id = journal.diff_hash()[..16].to_string();
+    let apply_id_literal = sql_literal(&Value::Text(apply_id.clone()));
+
+    let raw_count = plan.steps.iter().filter(|s| matches!(s, StageStep::Raw(_))).count();
+    let staged_rows: usize = plan.groups.iter().map(|g| g.rows.len()).sum();
+    info!(
+        "Staged plan: {} row changes in {} groups, {} statements applied directly",
+        staged_rows,
+        plan.groups.len(),
+        raw_count
+    );
+
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (apply_id TEXT NOT NULL, grp INTEGER NOT NULL, seq INTEGER NOT NULL, \
+             col TEXT NOT NULL, val, PRIMARY KEY (apply_id, grp, seq, col))",
+            STAGE_TABLE
+        ),
+        (),
+    )
+    .await
+    .context("Failed to create staging table")?;
+
+    let already_uploaded = journal.stage_rows_uploaded();
+    if already_uploaded == 0 {
+        // Leftovers of an earlier run of this diff that was not resumed
+        conn.execute(&format!("DELETE FROM {} WHERE apply_id = {}", STAGE_TABLE, apply_id_literal), ())
+            .await
+            .context("Failed to clear stale staging rows")?;
+    }
+
+    // Upload: each batch commits on its own but stays invisible until the final step
+    let stage_rows: Vec<[Value; 5]> = plan
+        .groups
+        .iter()
+        .enumerate()
+        .flat_map(|(grp, group)| {
+            let apply_id = &apply_id;
+            group.rows.iter().enumerate().flat_map(move |(seq, row)| {
+                row.key.iter().chain(row.values.iter()).map(move |(col, val)| {
+                    [
+                        Value::Text(apply_id.clone()),
+                        Value::Integer(grp as i64),
+                        Value::Integer(seq as i64),
+                        Value::Text(col.clone()),
+                        val.clone(),
+                    ]
+                })
+            })
+        })
+        .collect();
+
+    let pending = &stage_rows[already_uploaded.min(stage_rows.len())..];
+    if already_uploaded > 0 {
+        info!("Resuming staged upload: {}/{} values already staged", already_uploaded, stage_rows.len());
+        if pending.is_empty() && !stage_rows.is_empty() && count_stage_rows(conn, &apply_id_literal).await? == 0 {
+            // The final transaction deletes the staged rows, so it already committed
+            info!("✅ Staged diff was already committed by the interrupted run");
+            return Ok(());
+        }
+    }
+
+    let total_batches = pending.len().div_ceil(STAGE_ROWS_PER_INSERT);
+    let mut cache = StatementCache::new(conn);
+    for (batch_num, batch) in pending.chunks(STAGE_ROWS_PER_INSERT).enumerate() {
+        shutdown::check().with_context(|| {
+            format!(
+                "Stopped before staging batch {}/{}, nothing was applied (rerun with --resume to continue the upload)",
+                batch_num + 1,
+                total_batches
+            )
+        })?;
+        info!("Staging batch {}/{} ({} values)", batch_num + 1, total_batches, batch.len());
+        // OR REPLACE keeps a batch idempotent if it committed but was not journaled
+        let statement = BoundStatement {
+            sql: format!(
+                "INSERT OR REPLACE INTO {} (apply_id, grp, seq, col, val) VALUES {}",
+                STAGE_TABLE,
+                vec!["(?,?,?,?,?)"; batch.len()].join(",")
+            ),
+            params: batch.iter().flatten().cloned().collect(),
+        };
+        let mut attempt = 1;
+        while let Err(e) = cache.execute(&statement).await {
+            let what = format!("Staging batch {}/{}", batch_num + 1, total_batches);
+            let Some(delay) = retry::policy().backoff(&what, attempt, &e) else {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Failed to upload staging batch {}/{}, nothing was applied \
+                         (rerun with --resume to continue the upload)",
+                        batch_num + 1,
+                        total_batches
+                    )
+                });
+            };
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+        journal.record_stage_rows(batch.len())?;
+    }
+
+    // Final step: one transaction moves everything into place
+    let final_statements: Vec<String> = plan
+        .steps
+        .iter()
+        .map(|step| match step {
+            StageStep::Raw(sql) => sql.to_string(),
+            StageStep::Group(grp) => group_sql(&plan.groups[*grp], *grp, &apply_id),
+        })
+        .chain(std::iter::once(format!("DELETE FROM {} WHERE apply_id = {}", STAGE_TABLE, apply_id_literal)))
+        .collect();
+
+    info!("Committing staged diff ({} statements in final transaction)", final_statements.len());
+    // The final transaction deletes the staged rows, so once they are gone it committed
+    let mut attempt = 1;
+    while let Err(e) = commit_staged(conn, &final_statements).await {
+        if retry::is_retryable(&e) {
+            let remaining = count_stage_rows(conn, &apply_id_literal)
+                .await
+                .context("The staged diff may have committed; rerun with --resume to check")?;
+            if remaining == 0 {
+                warn!("The staged diff committed although the commit was not confirmed ({:#})", e);
+                break;
+            }
+        }
+        let Some(delay) = retry::policy().backoff("Final staged transaction", attempt, &e) else {
+            return Err(e);
+        };
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+
+    info!("✅ Committed {} staged row changes atomically", staged_rows);
+    Ok(())
+}
+
+/// Run the final statements of a staged apply in one transaction, rolling back on failure.
+/// The staging table is dropped with it unless other applies still have rows in it.
+async fn commit_staged(conn: &Connection, final_statements: &[String]) -> Result<()> {
+    let tx = conn.transaction().await.context("Failed to begin final staged transaction")?;
+    for statement in final_statements {
+        if let Err(e) = tx.execute(statement, ()).await {
+            if let Err(rollback_err) = tx.rollback().await {
+                warn!("Rollback of final staged transaction failed: {}", rollback_err);
+            }
+            return Err(e).with_context(|| {
+                format!(
+                    "Failed to apply staged statement, nothing was applied (staged rows are kept for --resume): {}",
+                    statement
+                )
+            });
+        }
+    }
+    if let Err(e) = drop_if_empty(&tx, STAGE_TABLE).await {
+        if let Err(rollback_err) = tx.rollback().await {
+            warn!("Rollback of final staged transaction failed: {}", rollback_err);
+        }
+        return Err(e).context("Failed to drop the empty staging table, nothing was applied");
+    }
+    tx.commit().await.context("Failed to commit staged diff")
+}
+
+/// Drop `table` unless it still holds rows, which belong to other applies
+async fn drop_if_empty(conn: &Connection, table: &str) -> Result<()> {
+    let mut rows = conn.query(&format!("SELECT 1 FROM {} LIMIT 1", table), ()).await?;
+    let empty = rows.next().await?.is_none();
+    drop(rows);
+    if empty {
+        conn.execute(&format!("DROP TABLE {}", table), ()).await?;
+    }
+    Ok(())
+}
+
+/// Number of staged values remaining for an apply; none once the staging table is gone
+async fn count_stage_rows(conn: &Connection, apply_id_literal: &str) -> Result<i64> {
+    let mut tables = conn
+        .query("SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = ?", [STAGE_TABLE])
+        .await
+        .context("Failed to look for the staging table")?;
+    if tables.next().await?.is_none() {
+        return Ok(0);
+    }
+    drop(tables);
+    let mut rows = conn
+        .query(&format!("SELECT COUNT(*) FROM {} WHERE apply_id = {}", STAGE_TABLE, apply_id_literal), ())
+        .await
+        .context("Failed to count staged rows")?;
+    match rows.next().await? {
+        Some(row) => Ok(row.get::<i64>(0)?),
+        None => Ok(0),
+    }
+}
+
+/// Set-based statement applying one staged group to its target table
+fn group_sql(group: &StageGroup, grp: usize, apply_id: &str) -> String {
+    let table = quote_ident(&group.table);
+    let all_columns: Vec<&String> = group.key.iter().chain(group.columns.iter()).collect();
+
+    // Pivot the staged (seq, col, val) rows back into one row per change
+    let pivot = format!(
+        "SELECT seq, {} FROM {} WHERE apply_id = {} AND grp = {} GROUP BY seq",
+        all_columns
+            .iter()
+            .enumerate()
+            .map(|(i, col)| format!(
+                "MAX(CASE WHEN col = {} THEN val END) AS c{}",
+                sql_literal(&Value::Text((*col).clone())),
+                i
+            ))
+            .collect::<Vec<_>>()
+            .join(", "),
+        STAGE_TABLE,
+        sql_literal(&Value::Text(apply_id.to_string())),
+        grp
+    );
+    let key_aliases: Vec<String> = (0..group.key.len()).map(|i| format!("c{}", i)).collect();
+    let key_columns: Vec<String> = group.key.iter().map(|c| quote_ident(c)).collect();
+
+    match group.op {
+        RowOp::Delete => format!(
+            "DELETE FROM {} WHERE ({}) IN (SELECT {} FROM ({}))",
+            table,
+            key_columns.join(", "),
+            key_aliases.join(", "),
+            pivot
+        ),
+        RowOp::Update => {
+            let assignments: Vec<String> = group
+                .columns
+                .iter()
+                .enumerate()
+                .map(|(i, col)| format!("{} = s.c{}", quote_ident(col), group.key.len() + i))
+                .collect();
+            let matches: Vec<String> = key_columns
+                .iter()
+                .zip(key_aliases.iter())
+                .map(|(col, alias)| format!("{}.{} = s.{}", table, col, alias))
+                .collect();
+            format!(
+                "UPDATE {} SET {} FROM ({}) AS s WHERE {}",
+                table,
+                assignments.join(", "),
+                pivot,
+                matches.join(" AND ")
+            )
+        }
+        RowOp::Insert => {
+            let columns: Vec<String> = all_columns.iter().map(|c| quote_ident(c)).collect();
+            let aliases: Vec<String> = (0..all_columns.len()).map(|i| format!("c{}", i)).collect();
+            format!(
+                "INSERT INTO {}({}) SELECT {} FROM ({}) ORDER BY seq",
+                table,
+                columns.join(", "),
+                aliases.join(", "),
+                pivot
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{exec, open, temp_dir};
+
+    #[test]
+    fn groups_by_operation_and_table_between_schema_changes() {
+        let statements = [
+            "INSERT INTO a(id,name) VALUES(1,'x')",
+            "DELETE FROM b WHERE id=1",
+            "UPDATE a SET name='y' WHERE id=2",
+            "INSERT INTO a(id,name) VALUES(3,'z')",
+            "UPDATE b SET name='w' WHERE id=2",
+            "DELETE FROM a WHERE id=4",
+            "CREATE INDEX a_name ON a(name)",
+            "ANALYZE",
+            "INSERT INTO b(id,name) VALUES(5,'v')",
+            "DELETE FROM a WHERE id=6",
+        ];
+        let changes: Vec<Change> = statements.iter().map(|s| Change::parse(s)).collect();
+        let phases = group_changes(&changes);
+
+        let summary: Vec<(&str, usize, usize)> =
+            phases.iter().map(|p| (p.label.as_str(), p.rows, p.statements.len())).collect();
+        assert_eq!(
+            summary,
+            vec![
+                // Deletes, then updates, then inserts, tables in order of first appearance
+                ("DELETE b", 1, 1),
+                ("DELETE a", 1, 1),
+                ("UPDATE a", 1, 1),
+                ("UPDATE b", 1, 1),
+                ("INSERT a", 2, 1),
+                // Consecutive schema changes share a phase, one statement at a time
+                ("SCHEMA", 2, 2),
+                ("DELETE a", 1, 1),
+                ("INSERT b", 1, 1),
+            ]
+        );
+        assert_eq!(phases[5].batch_size, 1);
+        assert_eq!((phases[0].batch_size, phases[2].batch_size), (1000, 500));
+
+        let (tables, other) = phase_counts(&phases);
+        assert_eq!(tables.into_iter().collect::<Vec<_>>(), vec![("a", [2, 1, 2]), ("b", [1, 1, 1])]);
+        assert_eq!(other, 2);
+    }
+
+    #[test]
+    fn plans_batches_the_way_each_mode_executes() {
+        let mut changes: Vec<Change> = vec![Change::parse("CREATE TABLE log(x)")];
+        changes.extend((1..=1200).map(|id| Change::parse(&format!("INSERT INTO a(id,name) VALUES({},'x')", id))));
+        changes.extend((1..=3).map(|id| Change::parse(&format!("UPDATE a SET name='y' WHERE id={}", id))));
+        changes.push(Change::parse("DELETE FROM b WHERE id=1"));
+
+        let grouped = plan(&changes, ApplyMode::Grouped);
+        assert_eq!(grouped.tables.get("a"), Some(&[0, 3, 1200]));
+        assert_eq!(grouped.tables.get("b"), Some(&[1, 0, 0]));
+        assert_eq!(grouped.schema, vec!["CREATE TABLE IF NOT EXISTS log(x)".to_string()]);
+        // Schema, one DELETE, three UPDATEs, three coalesced INSERTs of at most 500 rows
+        assert_eq!((grouped.statements, grouped.batches, grouped.rows_written), (8, 4, 1204));
+
+        let transaction = plan(&changes, ApplyMode::Transaction);
+        assert_eq!(transaction.schema, vec!["CREATE TABLE log(x)".to_string()]);
+        assert_eq!((transaction.statements, transaction.batches), (8, 1));
+
+        // 1200 INSERTs of two values, three UPDATEs of two and one DELETE key
+        let staged = plan(&changes, ApplyMode::Staged);
+        assert_eq!(staged.rows_written, 1204 + 2407);
+        assert_eq!((staged.statements, staged.batches), (13 + 5, 14));
+
+        let options = ApplyOptions {
+            atomic: true,
+            max_transaction_statements: 5000,
+            resume: false,
+            dry_run: true,
+            guard: GuardOptions::default(),
+            protect: ProtectOptions::default(),
+            batching: BatchOptions::default(),
+        };
+        assert_eq!(ApplyMode::for_push(&options, changes.len()), ApplyMode::Transaction);
+        assert_eq!(ApplyMode::for_apply_diff(&options, 10_000, true), ApplyMode::Transaction);
+        assert_eq!(ApplyMode::for_apply_diff(&options, 10_000, false), ApplyMode::Staged);
+
+        let output = grouped.to_string();
+        assert!(output.contains("  # a\n      + 1200 inserts\n      ~ 3 updates\n"), "{}", output);
+        assert!(output.contains("Plan: 1200 to add, 3 to change, 1 to destroy, 1 schema changes."), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn resume_skips_journaled_statements_inside_a_phase() {
+        let dir = temp_dir();
+        let path = dir.path("target.db");
+        exec(&path, "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT);").await;
+        // One schema phase, then an INSERT phase of several batches
+        let inserts: Vec<BoundStatement> = (1..=5)
+            .map(|id| BoundStatement {
+                sql: "INSERT INTO t VALUES (?, 'n')".to_string(),
+                params: vec![Value::Integer(id)],
+            })
+            .collect();
+        let phases = [
+            Phase::new("SCHEMA", vec![BoundStatement::raw("CREATE TABLE log(x)")], 1),
+            Phase::new("INSERT", inserts, 2),
+        ];
+
+        // An earlier run committed the schema statement and two INSERT statements
+        let diff_file = dir.path("diff.sql");
+        JournalFile::open(&diff_file, "diff", 6, false).unwrap().record_batch(3).unwrap();
+        let mut journal = JournalFile::open(&diff_file, "diff", 6, true).unwrap();
+
+        let (_db, conn) = open(&path).await;
+        execute_phases(&conn, &phases, &BatchOptions::default(), &mut journal).await.unwrap();
+        assert_eq!(journal.statements_applied(), 6);
+
+        let mut rows = conn.query("SELECT COUNT(*), MIN(id), MAX(id) FROM t", ()).await.unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        let (count, min, max): (i64, i64, i64) = (row.get(0).unwrap(), row.get(1).unwrap(), row.get(2).unwrap());
+        assert_eq!((count, min, max), (3, 3, 5));
+        let mut log = conn.query("SELECT 1 FROM sqlite_schema WHERE name = 'log'", ()).await.unwrap();
+        assert!(log.next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn a_batch_that_committed_unconfirmed_is_not_sent_again() {
+        let dir = temp_dir();
+        let path = dir.path("target.db");
+        exec(&path, "CREATE TABLE t(id INTEGER PRIMARY KEY)").await;
+        let inserts: Vec<BoundStatement> = (1..=5)
+            .map(|id| BoundStatement { sql: "INSERT INTO t VALUES (?)".to_string(), params: vec![Value::Integer(id)] })
+            .collect();
+        let phases = [Phase::new("INSERT", inserts, 2)];
+
+        // The second batch committed, but its confirmation timed out before it was journaled
+        let diff_file = dir.path("diff.sql");
+        let mut interrupted = JournalFile::open(&diff_file, "diff", 5, false).unwrap();
+        interrupted.record_batch(2).unwrap();
+        let (_db, conn) = open(&path).await;
+        create_progress_table(&conn).await.unwrap();
+        execute_batch(&conn, &phases[0].statements[..4], &progress_statement(interrupted.apply_id(), 4)).await.unwrap();
+        let timeout = anyhow::Error::new(libsql::Error::Hrana("http error: `operation timed out`".into()));
+        assert!(landed(&conn, interrupted.apply_id(), 4, &timeout).await.unwrap());
+        assert!(!landed(&conn, interrupted.apply_id(), 5, &timeout).await.unwrap());
+        let constraint = anyhow::Error::new(libsql::Error::SqliteFailure(19, "UNIQUE constraint failed".into()));
+        assert!(!landed(&conn, interrupted.apply_id(), 4, &constraint).await.unwrap());
+
+        // Resuming picks up the remote progress instead of inserting ids 3 and 4 again
+        let mut journal = JournalFile::open(&diff_file, "diff", 5, true).unwrap();
+        execute_phases(&conn, &phases, &BatchOptions::default(), &mut journal).await.unwrap();
+        assert_eq!(journal.statements_applied(), 5);
+        let mut rows = conn.query("SELECT COUNT(*) FROM t", ()).await.unwrap();
+        assert_eq!(rows.next().await.unwrap().unwrap().get::<i64>(0).unwrap(), 5);
+        drop(rows);
+        let mut progress = conn.query("SELECT 1 FROM sqlite_schema WHERE name = ?", [PROGRESS_TABLE]).await.unwrap();
+        assert!(progress.next().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn a_staged_apply_drops_the_staging_table_it_leaves_empty() {
+        let dir = temp_dir();
+        let path = dir.path("target.db");
+        exec(&path, "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT)").await;
+        let insert = |id: i64| {
+            Change::Row(RowChange {
+                table: "t".to_string(),
+                op: RowOp::Insert,
+                key: vec![("id".to_string(), Value::Integer(id))],
+                values: vec![("name".to_string(), Value::Text(format!("n{}", id)))],
+            })
+        };
+        let (_db, conn) = open(&path).await;
+        let staged = |ids: std::ops::Range<i64>, diff: &'static str| {
+            let (conn, diff_file) = (conn.clone(), dir.path(diff));
+            async move {
+                let changes: Vec<Change> = ids.map(insert).collect();
+                let mut journal = JournalFile::open(&diff_file, diff, changes.len(), false).unwrap();
+                apply_atomic(&conn, &changes, 2, &mut journal).await.unwrap();
+            }
+        };
+        let stage_exists = || async {
+            let mut rows = conn.query("SELECT 1 FROM sqlite_schema WHERE name = ?", [STAGE_TABLE]).await.unwrap();
+            rows.next().await.unwrap().is_some()
+        };
+
+        // Rows of another apply keep the table; the last apply to finish drops it
+        conn.execute_batch(&format!(
+            "CREATE TABLE {} (apply_id TEXT NOT NULL, grp INTEGER NOT NULL, seq INTEGER NOT NULL, \
+             col TEXT NOT NULL, val, PRIMARY KEY (apply_id, grp, seq, col));
+             INSERT INTO {} VALUES ('other', 0, 0, 'id', 1);",
+            STAGE_TABLE, STAGE_TABLE
+        ))
+        .await
+        .unwrap();
+        staged(1..6, "first.sql").await;
+        assert!(stage_exists().await);
+        conn.execute(&format!("DELETE FROM {}", STAGE_TABLE), ()).await.unwrap();
+        staged(6..11, "second.sql").await;
+        assert!(!stage_exists().await);
+
+        let mut rows = conn.query("SELECT COUNT(*), MAX(name) FROM t", ()).await.unwrap();
+        let row = rows.next().await.unwrap().unwrap();
+        assert_eq!((row.get::<i64>(0).unwrap(), row.get::<String>(1).unwrap()), (10, "n9".to_string()));
+    }
+}
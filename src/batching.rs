@@ -0,0 +1,176 @@
+use clap::Args;
+use log::{debug, info};
+use std::time::Duration;
+
+/// Largest batch committed in one transaction, in bytes of SQL and bound values
+pub const MAX_BATCH_BYTES: usize = 1024 * 1024;
+
+/// Smallest byte limit payload errors can shrink batches to
+const MIN_BATCH_BYTES: usize = 16 * 1024;
+
+/// How batches that commit independently are sized and paced
+#[derive(Args, Debug, Clone)]
+pub struct BatchOptions {
+    /// Batch latency the adaptive batch size aims for, in milliseconds
+    #[arg(long, default_value = "1000", env = "TURSO_SYNC_BATCH_TARGET_MS")]
+    pub batch_target_ms: u64,
+
+    /// Largest number of statements the adaptive batch size grows to
+    #[arg(long, default_value = "5000", env = "TURSO_SYNC_MAX_BATCH_SIZE")]
+    pub max_batch_size: usize,
+
+    /// Use this many statements per batch instead of adapting to the observed latency
+    #[arg(long, env = "TURSO_SYNC_BATCH_SIZE")]
+    pub batch_size: Option<usize>,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        BatchOptions { batch_target_ms: 1000, max_batch_size: 5000, batch_size: None }
+    }
+}
+
+/// Sizes the next batch from how the previous ones went.
+///
+/// Batches that commit in well under the target latency grow by half, slower ones
+/// shrink in proportion to how far they overshot. A batch rejected as too large or
+/// timing out is split in half and retried; payload errors also halve the byte
+/// limit. After a slow or failed batch the controller asks for a pause, so an
+/// overloaded server gets time to catch up instead of a fixed sleep after every batch.
+#[derive(Debug)]
+pub struct BatchController {
+    size: usize,
+    max_size: usize,
+    max_bytes: usize,
+    target: Duration,
+    fixed: bool,
+    pause: Duration,
+}
+
+impl BatchController {
+    pub fn new(options: &BatchOptions) -> Self {
+        BatchController {
+            size: options.batch_size.unwrap_or(1).max(1),
+            max_size: options.max_batch_size.max(1),
+            max_bytes: MAX_BATCH_BYTES,
+            target: Duration::from_millis(options.batch_target_ms.max(1)),
+            fixed: options.batch_size.is_some(),
+            pause: Duration::ZERO,
+        }
+    }
+
+    /// Start a phase whose statements suggest `hint` statements per batch. The byte
+    /// limit learned from payload errors carries over.
+    pub fn start_phase(&mut self, hint: usize) {
+        if !self.fixed {
+            self.size = hint.clamp(1, self.max_size);
+        }
+        self.pause = Duration::ZERO;
+    }
+
+    /// Statements in the next batch
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Bytes of SQL and bound values in the next batch
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Pause before the next batch
+    pub fn pause(&self) -> Duration {
+        self.pause
+    }
+
+    /// Record a batch of `statements` that committed after `latency`
+    pub fn record_success(&mut self, statements: usize, latency: Duration) {
+        // Time the server needs beyond the target, at most one target's worth
+        self.pause = latency.saturating_sub(self.target).min(self.target);
+        if self.fixed {
+            return;
+        }
+        let before = self.size;
+        if latency > self.target {
+            let scaled = self.size as f64 * self.target.as_secs_f64() / latency.as_secs_f64();
+            self.size = (scaled as usize).max(self.size / 2).max(1);
+        } else if latency * 2 < self.target && statements >= self.size {
+            // Only a full batch shows that a larger one would be fine
+            self.size = (self.size + self.size / 2).max(self.size + 1).min(self.max_size);
+        }
+        if self.size != before {
+            debug!("Batch of {} took {} ms, batch size {} -> {}", statements, latency.as_millis(), before, self.size);
+        }
+    }
+
+    /// Shrink after a batch of `statements` and `bytes` failed with `error`. Returns
+    /// whether the same statements should be retried in smaller batches; errors that
+    /// are not about the batch size, or a batch of one, are for the caller to handle.
+    /// A timed-out batch may have committed; the caller checks that it did not first.
+    pub fn shrink_after(&mut self, statements: usize, bytes: usize, error: &anyhow::Error) -> bool {
+        let message = format!("{:#}", error).to_lowercase();
+        let too_large = ["413", "too large", "too big", "payload", "message size", "length limit"]
+            .iter()
+            .any(|p| message.contains(p));
+        let timed_out = message.contains("timed out") || message.contains("timeout");
+        if statements <= 1 || !(too_large || timed_out) {
+            return false;
+        }
+        self.size = (statements / 2).max(1);
+        if too_large {
+            self.max_bytes = (bytes / 2).clamp(MIN_BATCH_BYTES, self.max_bytes);
+        }
+        self.pause = self.target;
+        info!(
+            "Batch of {} statements ({} bytes) {}, retrying in batches of {}",
+            statements,
+            bytes,
+            if too_large { "was too large" } else { "timed out" },
+            self.size
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_when_fast_and_shrinks_when_slow_or_rejected() {
+        let mut controller = BatchController::new(&BatchOptions { max_batch_size: 1200, ..BatchOptions::default() });
+        controller.start_phase(500);
+        controller.record_success(500, Duration::from_millis(100));
+        assert_eq!(controller.size(), 750);
+        // A partial batch at the end of a phase says nothing about larger ones
+        controller.record_success(20, Duration::from_millis(100));
+        assert_eq!(controller.size(), 750);
+        controller.record_success(750, Duration::from_millis(200));
+        assert_eq!(controller.size(), 1125);
+        controller.record_success(1125, Duration::from_millis(300));
+        assert_eq!((controller.size(), controller.pause()), (1200, Duration::ZERO));
+
+        // Twice the target halves the batch and pauses for the overshoot
+        controller.record_success(1200, Duration::from_millis(2000));
+        assert_eq!((controller.size(), controller.pause()), (600, Duration::from_secs(1)));
+        controller.record_success(600, Duration::from_millis(1250));
+        assert_eq!((controller.size(), controller.pause()), (480, Duration::from_millis(250)));
+
+        let too_large = anyhow::anyhow!("Hrana: `http error: 413 Payload Too Large`");
+        assert!(controller.shrink_after(480, 400_000, &too_large));
+        assert_eq!((controller.size(), controller.max_bytes()), (240, 200_000));
+        assert!(controller.shrink_after(240, 200_000, &anyhow::anyhow!("operation timed out")));
+        assert_eq!((controller.size(), controller.max_bytes()), (120, 200_000));
+        assert!(!controller.shrink_after(1, 100, &too_large));
+        assert!(!controller.shrink_after(120, 100, &anyhow::anyhow!("UNIQUE constraint failed")));
+
+        // The byte limit survives a new phase, the size starts over from its hint
+        controller.start_phase(1000);
+        assert_eq!((controller.size(), controller.max_bytes()), (1000, 200_000));
+
+        let mut fixed = BatchController::new(&BatchOptions { batch_size: Some(100), ..BatchOptions::default() });
+        fixed.start_phase(1000);
+        fixed.record_success(100, Duration::from_millis(10));
+        assert_eq!(fixed.size(), 100);
+    }
+}
@@ -0,0 +1,458 @@
+use libsql::Value;
+
+use crate::prepared::BoundStatement;
+use crate::sql::{tokenize, Token, TokenKind};
+
+/// A single change needed to turn one database into another
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// Schema statement (CREATE/DROP/ALTER) applied verbatim
+    Schema(String),
+    /// Row-level INSERT/UPDATE/DELETE
+    Row(RowChange),
+}
+
+/// Kind of row-level change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RowOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// A row-level change keyed by primary key (or rowid)
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowChange {
+    pub table: String,
+    pub op: RowOp,
+    /// Key columns identifying the row, in key order
+    pub key: Vec<(String, Value)>,
+    /// Non-key column values: every column for INSERT, only changed columns for UPDATE
+    pub values: Vec<(String, Value)>,
+}
+
+impl Change {
+    /// Render the change as a single SQL statement without trailing semicolon
+    pub fn to_sql(&self) -> String {
+        match self {
+            Change::Schema(sql) => sql.clone(),
+            Change::Row(row) => row.to_sql(),
+        }
+    }
+
+    /// Classify a diff statement: row changes in sqldiff shape become `Row`, anything
+    /// else is kept verbatim as `Schema`
+    pub fn parse(statement: &str) -> Change {
+        match RowChange::parse(statement) {
+            Some(row) => Change::Row(row),
+            None => Change::Schema(statement.to_string()),
+        }
+    }
+}
+
+impl RowChange {
+    /// Render the change in the same shape sqldiff uses
+    pub fn to_sql(&self) -> String {
+        self.render(&mut sql_literal)
+    }
+
+    /// Render the change in sqldiff shape with a `?` placeholder for every value
+    pub fn to_bound(&self) -> BoundStatement {
+        let mut params = Vec::new();
+        let sql = self.render(&mut |value| {
+            params.push(value.clone());
+            "?".to_string()
+        });
+        BoundStatement { sql, params }
+    }
+
+    /// Parse an INSERT/UPDATE/DELETE statement in the shape sqldiff (and `to_sql`) emits.
+    ///
+    /// Returns `None` for anything else. Without the table schema an INSERT's key
+    /// columns cannot be told apart, so parsed INSERTs carry every column in `values`.
+    pub fn parse(statement: &str) -> Option<RowChange> {
+        let tokens: Vec<Token> = tokenize(statement).into_iter().filter(|t| !t.is_trivia()).collect();
+        let mut parser = Parser { tokens: &tokens, pos: 0 };
+        let change = parser.row_change()?;
+        parser.at_end().then_some(change)
+    }
+
+    /// Render with `value` producing the SQL for each value, in statement order
+    fn render(&self, value: &mut dyn FnMut(&Value) -> String) -> String {
+        let table = quote_ident(&self.table);
+        match self.op {
+            RowOp::Insert => {
+                let columns: Vec<String> =
+                    self.key.iter().chain(self.values.iter()).map(|(name, _)| quote_ident(name)).collect();
+                let values: Vec<String> = self.key.iter().chain(self.values.iter()).map(|(_, v)| value(v)).collect();
+                format!("INSERT INTO {}({}) VALUES({})", table, columns.join(","), values.join(","))
+            }
+            RowOp::Update => {
+                let assignments: Vec<String> =
+                    self.values.iter().map(|(name, v)| format!("{}={}", quote_ident(name), value(v))).collect();
+                let predicate = self.key_predicate(value);
+                format!("UPDATE {} SET {} WHERE {}", table, assignments.join(", "), predicate)
+            }
+            RowOp::Delete => format!("DELETE FROM {} WHERE {}", table, self.key_predicate(value)),
+        }
+    }
+
+    fn key_predicate(&self, value: &mut dyn FnMut(&Value) -> String) -> String {
+        self.key.iter().map(|(name, v)| format!("{}={}", quote_ident(name), value(v))).collect::<Vec<_>>().join(" AND ")
+    }
+}
+
+/// Render a value as an SQL literal that round-trips through SQLite
+pub fn sql_literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::Real(f) if f.is_nan() => "NULL".to_string(),
+        Value::Real(f) if f.is_infinite() => {
+            if *f > 0.0 {
+                "1e999".to_string()
+            } else {
+                "-1e999".to_string()
+            }
+        }
+        // Debug formatting always keeps a '.' or exponent, so SQLite reads it back as REAL
+        Value::Real(f) => format!("{:?}", f),
+        Value::Text(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Blob(bytes) => {
+            let hex: String = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("X'{}'", hex)
+        }
+    }
+}
+
+/// Quote an identifier only when it is not a plain, non-keyword name (like sqldiff does)
+pub fn quote_ident(name: &str) -> String {
+    let mut chars = name.chars();
+    let plain = match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    };
+    if plain && !is_keyword(name) {
+        name.to_string()
+    } else {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    }
+}
+
+fn is_keyword(name: &str) -> bool {
+    SQL_KEYWORDS.iter().any(|k| k.eq_ignore_ascii_case(name))
+}
+
+const SQL_KEYWORDS: &[&str] = &[
+    "ABORT",
+    "ACTION",
+    "ADD",
+    "AFTER",
+    "ALL",
+    "ALTER",
+    "ALWAYS",
+    "ANALYZE",
+    "AND",
+    "AS",
+    "ASC",
+    "ATTACH",
+    "AUTOINCREMENT",
+    "BEFORE",
+    "BEGIN",
+    "BETWEEN",
+    "BY",
+    "CASCADE",
+    "CASE",
+    "CAST",
+    "CHECK",
+    "COLLATE",
+    "COLUMN",
+    "COMMIT",
+    "CONFLICT",
+    "CONSTRAINT",
+    "CREATE",
+    "CROSS",
+    "CURRENT",
+    "CURRENT_DATE",
+    "CURRENT_TIME",
+    "CURRENT_TIMESTAMP",
+    "DATABASE",
+    "DEFAULT",
+    "DEFERRABLE",
+    "DEFERRED",
+    "DELETE",
+    "DESC",
+    "DETACH",
+    "DISTINCT",
+    "DO",
+    "DROP",
+    "EACH",
+    "ELSE",
+    "END",
+    "ESCAPE",
+    "EXCEPT",
+    "EXCLUDE",
+    "EXCLUSIVE",
+    "EXISTS",
+    "EXPLAIN",
+    "FAIL",
+    "FILTER",
+    "FIRST",
+    "FOLLOWING",
+    "FOR",
+    "FOREIGN",
+    "FROM",
+    "FULL",
+    "GENERATED",
+    "GLOB",
+    "GROUP",
+    "GROUPS",
+    "HAVING",
+    "IF",
+    "IGNORE",
+    "IMMEDIATE",
+    "IN",
+    "INDEX",
+    "INDEXED",
+    "INITIALLY",
+    "INNER",
+    "INSERT",
+    "INSTEAD",
+    "INTERSECT",
+    "INTO",
+    "IS",
+    "ISNULL",
+    "JOIN",
+    "KEY",
+    "LAST",
+    "LEFT",
+    "LIKE",
+    "LIMIT",
+    "MATCH",
+    "MATERIALIZED",
+    "NATURAL",
+    "NO",
+    "NOT",
+    "NOTHING",
+    "NOTNULL",
+    "NULL",
+    "NULLS",
+    "OF",
+    "OFFSET",
+    "ON",
+    "OR",
+    "ORDER",
+    "OTHERS",
+    "OUTER",
+    "OVER",
+    "PARTITION",
+    "PLAN",
+    "PRAGMA",
+    "PRECEDING",
+    "PRIMARY",
+    "QUERY",
+    "RAISE",
+    "RANGE",
+    "RECURSIVE",
+    "REFERENCES",
+    "REGEXP",
+    "REINDEX",
+    "RELEASE",
+    "RENAME",
+    "REPLACE",
+    "RESTRICT",
+    "RETURNING",
+    "RIGHT",
+    "ROLLBACK",
+    "ROW",
+    "ROWS",
+    "SAVEPOINT",
+    "SELECT",
+    "SET",
+    "TABLE",
+    "TEMP",
+    "TEMPORARY",
+    "THEN",
+    "TIES",
+    "TO",
+    "TRANSACTION",
+    "TRIGGER",
+    "UNBOUNDED",
+    "UNION",
+    "UNIQUE",
+    "UPDATE",
+    "USING",
+    "VACUUM",
+    "VALUES",
+    "VIEW",
+    "VIRTUAL",
+    "WHEN",
+    "WHERE",
+    "WINDOW",
+    "WITH",
+    "WITHOUT",
+];
+
+struct Parser<'t, 'a> {
+    tokens: &'t [Token<'a>],
+    pos: usize,
+}
+
+impl<'t, 'a> Parser<'t, 'a> {
+    fn row_change(&mut self) -> Option<RowChange> {
+        if self.word("INSERT") {
+            self.expect_word("INTO")?;
+            let table = self.ident()?;
+            self.expect_punct("(")?;
+            let columns = self.list(Self::ident)?;
+            self.expect_punct(")")?;
+            self.expect_word("VALUES")?;
+            self.expect_punct("(")?;
+            let values = self.list(Self::literal)?;
+            self.expect_punct(")")?;
+            if columns.len() != values.len() {
+                return None;
+            }
+            Some(RowChange {
+                table,
+                op: RowOp::Insert,
+                key: Vec::new(),
+                values: columns.into_iter().zip(values).collect(),
+            })
+        } else if self.word("UPDATE") {
+            let table = self.ident()?;
+            self.expect_word("SET")?;
+            let values = self.list(Self::assignment)?;
+            self.expect_word("WHERE")?;
+            let key = self.key_predicate()?;
+            Some(RowChange { table, op: RowOp::Update, key, values })
+        } else if self.word("DELETE") {
+            self.expect_word("FROM")?;
+            let table = self.ident()?;
+            self.expect_word("WHERE")?;
+            let key = self.key_predicate()?;
+            Some(RowChange { table, op: RowOp::Delete, key, values: Vec::new() })
+        } else {
+            None
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos == self.tokens.len()
+    }
+
+    fn peek(&self) -> Option<&'t Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    fn word(&mut self, word: &str) -> bool {
+        let matched = self.peek().is_some_and(|t| t.is_word(word));
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn expect_word(&mut self, word: &str) -> Option<()> {
+        self.word(word).then_some(())
+    }
+
+    fn punct(&mut self, punct: &str) -> bool {
+        let matched = self.peek().is_some_and(|t| t.kind == TokenKind::Punct && t.text == punct);
+        if matched {
+            self.pos += 1;
+        }
+        matched
+    }
+
+    fn expect_punct(&mut self, punct: &str) -> Option<()> {
+        self.punct(punct).then_some(())
+    }
+
+    fn list<T>(&mut self, item: fn(&mut Self) -> Option<T>) -> Option<Vec<T>> {
+        let mut items = vec![item(self)?];
+        while self.punct(",") {
+            items.push(item(self)?);
+        }
+        Some(items)
+    }
+
+    fn ident(&mut self) -> Option<String> {
+        let token = *self.peek()?;
+        let name = match token.kind {
+            TokenKind::Word if !is_keyword(token.text) || is_rowid_alias(token.text) => token.text.to_string(),
+            TokenKind::QuotedIdent => unquote_ident(token.text),
+            _ => return None,
+        };
+        self.pos += 1;
+        Some(name)
+    }
+
+    fn assignment(&mut self) -> Option<(String, Value)> {
+        let column = self.ident()?;
+        self.expect_punct("=")?;
+        Some((column, self.literal()?))
+    }
+
+    fn key_predicate(&mut self) -> Option<Vec<(String, Value)>> {
+        let mut key = vec![self.assignment()?];
+        while self.word("AND") {
+            key.push(self.assignment()?);
+        }
+        Some(key)
+    }
+
+    fn literal(&mut self) -> Option<Value> {
+        let token = *self.peek()?;
+        let value = match token.kind {
+            TokenKind::Word if token.is_word("NULL") => Value::Null,
+            TokenKind::Number => parse_number(token.text, false)?,
+            TokenKind::Punct if token.text == "-" || token.text == "+" => {
+                self.pos += 1;
+                let number = self.peek().filter(|t| t.kind == TokenKind::Number)?;
+                parse_number(number.text, token.text == "-")?
+            }
+            TokenKind::String => Value::Text(token.text[1..token.text.len() - 1].replace("''", "'")),
+            TokenKind::Blob => Value::Blob(decode_hex(&token.text[2..token.text.len() - 1])?),
+            _ => return None,
+        };
+        self.pos += 1;
+        Some(value)
+    }
+}
+
+fn is_rowid_alias(name: &str) -> bool {
+    ["rowid", "_rowid_", "oid"].iter().any(|a| a.eq_ignore_ascii_case(name))
+}
+
+fn unquote_ident(text: &str) -> String {
+    let inner = &text[1..text.len() - 1];
+    match text.as_bytes()[0] {
+        b'"' => inner.replace("\"\"", "\""),
+        b'`' => inner.replace("``", "`"),
+        _ => inner.to_string(),
+    }
+}
+
+fn parse_number(text: &str, negative: bool) -> Option<Value> {
+    let sign = if negative { "-" } else { "" };
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        let value = u64::from_str_radix(hex, 16).ok()? as i64;
+        return Some(Value::Integer(if negative { value.wrapping_neg() } else { value }));
+    }
+    if text.contains(['.', 'e', 'E']) {
+        return format!("{}{}", sign, text).parse().ok().map(Value::Real);
+    }
+    // Integers too large for i64 are read as REAL, as SQLite does
+    match format!("{}{}", sign, text).parse::<i64>() {
+        Ok(i) => Some(Value::Integer(i)),
+        Err(_) => format!("{}{}", sign, text).parse().ok().map(Value::Real),
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
@@ -0,0 +1,586 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use libsql::{Connection, Value};
+use rusqlite::hooks::Action;
+use rusqlite::session::{ConflictAction, ConflictType, Session};
+use rusqlite::types::ValueRef;
+use rusqlite::{DatabaseName, OpenFlags};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::change::{quote_ident, sql_literal, Change, RowChange, RowOp};
+use crate::conflict::{Conflict, ConflictKind, ConflictPolicy, RowSnapshot, RowValues};
+use crate::diff::{self, compare_values};
+
+/// Format of a diff file
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffFormat {
+    /// SQL statements in `sqldiff --transaction` format
+    Sql,
+    /// SQLite session changeset: binary, carries old values for conflict detection
+    Changeset,
+    /// SQLite session patchset: smaller than a changeset, but without old values
+    Patchset,
+}
+
+impl DiffFormat {
+    /// Format of diff file contents. Binary formats start with a table header and
+    /// hold NUL-terminated table names, which SQL text never contains.
+    pub fn detect(data: &[u8]) -> DiffFormat {
+        match data.first() {
+            Some(b'T') if data.contains(&0) => DiffFormat::Changeset,
+            Some(b'P') if data.contains(&0) => DiffFormat::Patchset,
+            _ => DiffFormat::Sql,
+        }
+    }
+}
+
+/// One row change read from a changeset or patchset
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangesetRow {
+    pub table: String,
+    pub op: RowOp,
+    /// Position of each column in the primary key (from 1), 0 for other columns
+    pub pk: Vec<u8>,
+    /// old.* values, `None` for columns the record leaves out
+    pub old: Vec<Option<Value>>,
+    /// new.* values, `None` for columns the record leaves out
+    pub new: Vec<Option<Value>>,
+}
+
+impl ChangesetRow {
+    /// Primary key values: old.* for UPDATE and DELETE, new.* for INSERT
+    fn key_values(&self) -> Vec<&Value> {
+        let values = if self.op == RowOp::Insert { &self.new } else { &self.old };
+        self.pk.iter().zip(values).filter(|(pk, _)| **pk != 0).filter_map(|(_, v)| v.as_ref()).collect()
+    }
+}
+
+/// Compute the changeset (or patchset) that turns `replica_path` into `working_path`
+/// with the session extension.
+///
+/// Changesets only carry row changes of tables with a PRIMARY KEY, so this fails when
+/// the schemas differ or a table has no declared key; the SQL format covers both.
+pub fn create(replica_path: &str, working_path: &str, format: DiffFormat) -> Result<Vec<u8>> {
+    let conn = rusqlite::Connection::open_with_flags(working_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open {}", working_path))?;
+    conn.execute("ATTACH DATABASE ?1 AS base", [replica_path])
+        .with_context(|| format!("Failed to attach {}", replica_path))?;
+
+    if schema(&conn, "main")? != schema(&conn, "base")? {
+        return Err(anyhow::anyhow!(
+            "Schema of {} differs from {}; changesets carry row changes only, use --format sql",
+            working_path,
+            replica_path
+        ));
+    }
+
+    let tables: Vec<String> = schema(&conn, "main")?
+        .into_iter()
+        .filter(|(kind, _, sql)| kind == "table" && !diff::is_virtual_table(sql))
+        .map(|(_, name, _)| name)
+        .collect();
+    let mut session = Session::new(&conn).context("Failed to create session")?;
+    let mut keyless = Vec::new();
+    for table in &tables {
+        let key_columns: i64 = conn.query_row(
+            &format!(
+                "SELECT count(*) FROM pragma_table_info({}) WHERE pk > 0",
+                sql_literal(&Value::Text(table.clone()))
+            ),
+            [],
+            |row| row.get(0),
+        )?;
+        if key_columns == 0 {
+            keyless.push(table.as_str());
+            continue;
+        }
+        session.attach(Some(table))?;
+        session
+            .diff(DatabaseName::Attached("base"), table)
+            .with_context(|| format!("Failed to diff table {}", table))?;
+    }
+    if !keyless.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Tables without a PRIMARY KEY cannot be put in a changeset: {}; use --format sql",
+            keyless.join(", ")
+        ));
+    }
+
+    let mut output = Vec::new();
+    match format {
+        DiffFormat::Patchset => session.patchset_strm(&mut output)?,
+        _ => session.changeset_strm(&mut output)?,
+    }
+    Ok(output)
+}
+
+/// Read every row change of a changeset or patchset
+pub fn decode(data: &[u8]) -> Result<Vec<ChangesetRow>> {
+    let mut reader = Reader { data, pos: 0 };
+    let mut rows = Vec::new();
+    let mut table: Option<(String, Vec<u8>, bool)> = None;
+
+    while !reader.at_end() {
+        let op = reader.byte()?;
+        if op == b'T' || op == b'P' {
+            let columns = reader.varint()? as usize;
+            let pk = reader.bytes(columns)?.to_vec();
+            let name = reader.c_string()?;
+            table = Some((name, pk, op == b'P'));
+            continue;
+        }
+        let Some((name, pk, patchset)) = &table else {
+            return Err(reader.corrupt());
+        };
+        let op = match op {
+            SQLITE_INSERT => RowOp::Insert,
+            SQLITE_UPDATE => RowOp::Update,
+            SQLITE_DELETE => RowOp::Delete,
+            _ => return Err(reader.corrupt()),
+        };
+        reader.byte()?; // indirect flag
+
+        let columns = pk.len();
+        let (mut old, mut new) = (vec![None; columns], vec![None; columns]);
+        match (op, *patchset) {
+            (RowOp::Insert, _) => new = reader.record(columns, None)?,
+            (RowOp::Delete, false) => old = reader.record(columns, None)?,
+            // Patchsets keep only the key of deleted rows
+            (RowOp::Delete, true) => old = reader.record(columns, Some(pk))?,
+            (RowOp::Update, false) => {
+                old = reader.record(columns, None)?;
+                new = reader.record(columns, None)?;
+            }
+            // Patchset UPDATEs hold one record with the key and the new values
+            (RowOp::Update, true) => {
+                new = reader.record(columns, None)?;
+                for (i, _) in pk.iter().enumerate().filter(|(_, pk)| **pk != 0) {
+                    old[i] = new[i].take();
+                }
+            }
+        }
+        rows.push(ChangesetRow { table: name.clone(), op, pk: pk.clone(), old, new });
+    }
+    Ok(rows)
+}
+
+/// Write row changes as a changeset (or patchset) that `decode` and SQLite's session
+/// extension read
+pub fn encode(rows: &[ChangesetRow], format: DiffFormat) -> Vec<u8> {
+    let patchset = format == DiffFormat::Patchset;
+    let mut data = Vec::new();
+    let mut table: Option<(&str, &[u8])> = None;
+
+    for row in rows {
+        if table != Some((row.table.as_str(), row.pk.as_slice())) {
+            data.push(if patchset { b'P' } else { b'T' });
+            put_varint(&mut data, row.pk.len() as u64);
+            data.extend_from_slice(&row.pk);
+            data.extend_from_slice(row.table.as_bytes());
+            data.push(0);
+            table = Some((row.table.as_str(), row.pk.as_slice()));
+        }
+        let key = |i: usize| row.pk[i] != 0;
+        let (op, records): (u8, Vec<Vec<Option<&Value>>>) = match (row.op, patchset) {
+            (RowOp::Insert, _) => (SQLITE_INSERT, vec![row.new.iter().map(Option::as_ref).collect()]),
+            (RowOp::Update, false) => (
+                SQLITE_UPDATE,
+                vec![row.old.iter().map(Option::as_ref).collect(), row.new.iter().map(Option::as_ref).collect()],
+            ),
+            (RowOp::Delete, false) => (SQLITE_DELETE, vec![row.old.iter().map(Option::as_ref).collect()]),
+            // Patchsets keep the key of deleted rows and one record of key and new values for updates
+            (RowOp::Update, true) => (
+                SQLITE_UPDATE,
+                vec![(0..row.pk.len())
+                    .map(|i| if key(i) { row.old[i].as_ref() } else { row.new[i].as_ref() })
+                    .collect()],
+            ),
+            (RowOp::Delete, true) => {
+                let values = row.old.iter().enumerate().filter(|(i, _)| key(*i)).map(|(_, v)| v.as_ref()).collect();
+                (SQLITE_DELETE, vec![values])
+            }
+        };
+        data.extend_from_slice(&[op, 0]);
+        for value in records.into_iter().flatten() {
+            put_value(&mut data, value);
+        }
+    }
+    data
+}
+
+/// Turn decoded rows into changes, naming columns after the tables in `conn`.
+///
+/// Also returns the old row values the records carry, aligned with the changes, as a
+/// base for conflict detection. Only changesets have them: patchset records keep
+/// nothing of the old row but its key.
+pub async fn to_changes(rows: &[ChangesetRow], conn: &Connection) -> Result<(Vec<Change>, RowSnapshot)> {
+    let mut columns: HashMap<&str, Vec<String>> = HashMap::new();
+    let mut changes = Vec::with_capacity(rows.len());
+    let mut base: RowSnapshot = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        if !columns.contains_key(row.table.as_str()) {
+            columns.insert(&row.table, diff::table_info(conn, &row.table).await?.columns);
+        }
+        let names = &columns[row.table.as_str()];
+        if names.len() != row.pk.len() {
+            return Err(anyhow::anyhow!(
+                "Changeset has {} columns for table {} but the database has {}",
+                row.pk.len(),
+                row.table,
+                names.len()
+            ));
+        }
+
+        let named = |values: &[Option<Value>], key: Option<bool>| -> RowValues {
+            names
+                .iter()
+                .zip(values)
+                .zip(&row.pk)
+                .filter(|(_, pk)| key.is_none_or(|key| (**pk != 0) == key))
+                .filter_map(|((name, value), _)| value.clone().map(|v| (name.clone(), v)))
+                .collect()
+        };
+        let key_source = if row.op == RowOp::Insert { &row.new } else { &row.old };
+        changes.push(Change::Row(RowChange {
+            table: row.table.clone(),
+            op: row.op,
+            key: named(key_source, Some(true)),
+            values: if row.op == RowOp::Delete { Vec::new() } else { named(&row.new, Some(false)) },
+        }));
+        base.push((row.op != RowOp::Insert).then(|| named(&row.old, None)));
+    }
+    Ok((changes, base))
+}
+
+/// Apply a changeset or patchset to the local database at `db_path` in one transaction.
+///
+/// SQLite calls back for every row whose current state does not match the change;
+/// those rows are skipped and returned as conflicts. With `ConflictPolicy::Abort` the
+/// whole apply is rolled back when there are any.
+pub fn apply_local(db_path: &str, data: &[u8], policy: ConflictPolicy) -> Result<Vec<Conflict>> {
+    let rows = decode(data)?;
+    let mut conn = rusqlite::Connection::open(db_path).with_context(|| format!("Failed to open {}", db_path))?;
+
+    // What each change expects, looked up by table and key from the conflict callback
+    let mut names: HashMap<String, Vec<String>> = HashMap::new();
+    let mut lookup: HashMap<(String, String), Expected> = HashMap::new();
+    for (index, row) in rows.iter().enumerate() {
+        let table = row.table.to_lowercase();
+        if !names.contains_key(&table) {
+            names.insert(table.clone(), column_names(&conn, &row.table)?);
+        }
+        let columns = &names[&table];
+        let key_source = if row.op == RowOp::Insert { &row.new } else { &row.old };
+        let key: RowValues = columns
+            .iter()
+            .zip(key_source)
+            .zip(&row.pk)
+            .filter(|(_, pk)| **pk != 0)
+            .filter_map(|((name, value), _)| value.clone().map(|v| (name.clone(), v)))
+            .collect();
+        let old = columns
+            .iter()
+            .zip(&row.old)
+            .enumerate()
+            .filter_map(|(i, (name, value))| value.clone().map(|v| (i, name.clone(), v)))
+            .collect();
+        lookup.insert((table, key_string(row.key_values())), Expected { index, key, old });
+    }
+
+    let conflicts: Arc<Mutex<Vec<Conflict>>> = Arc::default();
+    let tx = conn.transaction().context("Failed to begin transaction")?;
+    let result = {
+        let conflicts = Arc::clone(&conflicts);
+        let mut input: &[u8] = data;
+        tx.apply_strm(&mut input, None::<fn(&str) -> bool>, move |kind, item| {
+            if kind == ConflictType::SQLITE_CHANGESET_FOREIGN_KEY {
+                return ConflictAction::SQLITE_CHANGESET_ABORT;
+            }
+            let Ok(op) = item.op() else { return ConflictAction::SQLITE_CHANGESET_ABORT };
+            let (table, code, columns) = (op.table_name().to_string(), op.code(), op.number_of_columns() as usize);
+            let Ok(pk) = item.pk().map(|pk| pk.to_vec()) else { return ConflictAction::SQLITE_CHANGESET_ABORT };
+            let key: Vec<Value> = (0..columns)
+                .filter(|&i| pk[i] != 0)
+                .filter_map(|i| match code {
+                    Action::SQLITE_INSERT => item.new_value(i).ok().map(to_value),
+                    _ => item.old_value(i).ok().map(to_value),
+                })
+                .collect();
+            let Some(expected) = lookup.get(&(table.to_lowercase(), key_string(key.iter()))) else {
+                return ConflictAction::SQLITE_CHANGESET_ABORT;
+            };
+
+            let kind = match kind {
+                ConflictType::SQLITE_CHANGESET_NOTFOUND => ConflictKind::Deleted,
+                ConflictType::SQLITE_CHANGESET_CONFLICT => ConflictKind::Inserted,
+                ConflictType::SQLITE_CHANGESET_DATA => {
+                    // Compare the row as it is now with the old values the change expected
+                    let changed = expected
+                        .old
+                        .iter()
+                        .filter(|(i, _, expected)| {
+                            item.conflict(*i)
+                                .map_or(true, |now| compare_values(&to_value(now), expected) != Ordering::Equal)
+                        })
+                        .map(|(_, name, _)| name.clone())
+                        .collect();
+                    ConflictKind::Updated(changed)
+                }
+                _ => ConflictKind::Constraint,
+            };
+            let op = match code {
+                Action::SQLITE_INSERT => RowOp::Insert,
+                Action::SQLITE_UPDATE => RowOp::Update,
+                _ => RowOp::Delete,
+            };
+            if let Ok(mut conflicts) = conflicts.lock() {
+                conflicts.push(Conflict { index: expected.index, op, table, key: expected.key.clone(), kind });
+            }
+            ConflictAction::SQLITE_CHANGESET_OMIT
+        })
+    };
+    let conflicts = std::mem::take(&mut *conflicts.lock().expect("conflict list"));
+
+    if let Err(e) = result {
+        return Err(anyhow::anyhow!(e).context("Failed to apply changeset (foreign key violations roll it back)"));
+    }
+    if conflicts.is_empty() || policy == ConflictPolicy::Rebase {
+        tx.commit().context("Failed to commit changeset")?;
+    }
+    Ok(conflicts)
+}
+
+/// A change as `apply_local` reports it when SQLite calls back with a conflict
+struct Expected {
+    /// Position of the change in the changeset
+    index: usize,
+    key: RowValues,
+    /// Old values the change expects, with their column positions
+    old: Vec<(usize, String, Value)>,
+}
+
+/// SQLite's codes for the operations in a changeset record
+const SQLITE_INSERT: u8 = 18;
+const SQLITE_UPDATE: u8 = 23;
+const SQLITE_DELETE: u8 = 9;
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn at_end(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn corrupt(&self) -> anyhow::Error {
+        anyhow::anyhow!("Corrupt changeset at byte {}", self.pos)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self.data.get(self.pos).ok_or_else(|| self.corrupt())?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos + len).ok_or_else(|| self.corrupt())?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// SQLite varint: big-endian 7-bit groups, the ninth byte contributes all 8 bits
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for _ in 0..8 {
+            let byte = self.byte()?;
+            value = (value << 7) | u64::from(byte & 0x7f);
+            if byte < 0x80 {
+                return Ok(value);
+            }
+        }
+        Ok((value << 8) | u64::from(self.byte()?))
+    }
+
+    fn c_string(&mut self) -> Result<String> {
+        let len = self.data[self.pos..].iter().position(|&b| b == 0).ok_or_else(|| self.corrupt())?;
+        let text = String::from_utf8_lossy(self.bytes(len)?).into_owned();
+        self.pos += 1;
+        Ok(text)
+    }
+
+    /// One value per column, or only the key columns when `only` is given
+    fn record(&mut self, columns: usize, only: Option<&[u8]>) -> Result<Vec<Option<Value>>> {
+        let mut values = vec![None; columns];
+        for (i, value) in values.iter_mut().enumerate() {
+            if only.is_some_and(|pk| pk[i] == 0) {
+                continue;
+            }
+            *value = self.value()?;
+        }
+        Ok(values)
+    }
+
+    fn value(&mut self) -> Result<Option<Value>> {
+        let value = match self.byte()? {
+            0 => return Ok(None),
+            1 => Value::Integer(i64::from_be_bytes(self.bytes(8)?.try_into()?)),
+            2 => Value::Real(f64::from_be_bytes(self.bytes(8)?.try_into()?)),
+            3 => {
+                let len = self.varint()? as usize;
+                Value::Text(String::from_utf8_lossy(self.bytes(len)?).into_owned())
+            }
+            4 => {
+                let len = self.varint()? as usize;
+                Value::Blob(self.bytes(len)?.to_vec())
+            }
+            5 => Value::Null,
+            _ => return Err(self.corrupt()),
+        };
+        Ok(Some(value))
+    }
+}
+
+/// SQLite varint as `Reader::varint` reads it; lengths never need the nine byte form
+fn put_varint(data: &mut Vec<u8>, mut value: u64) {
+    let mut groups = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        groups.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    data.extend(groups.iter().rev());
+}
+
+fn put_value(data: &mut Vec<u8>, value: Option<&Value>) {
+    match value {
+        None => data.push(0),
+        Some(Value::Integer(i)) => {
+            data.push(1);
+            data.extend_from_slice(&i.to_be_bytes());
+        }
+        Some(Value::Real(f)) => {
+            data.push(2);
+            data.extend_from_slice(&f.to_be_bytes());
+        }
+        Some(Value::Text(text)) => {
+            data.push(3);
+            put_varint(data, text.len() as u64);
+            data.extend_from_slice(text.as_bytes());
+        }
+        Some(Value::Blob(bytes)) => {
+            data.push(4);
+            put_varint(data, bytes.len() as u64);
+            data.extend_from_slice(bytes);
+        }
+        Some(Value::Null) => data.push(5),
+    }
+}
+
+/// Object type, name and SQL of the user schema of database `db`
+fn schema(conn: &rusqlite::Connection, db: &str) -> Result<Vec<(String, String, String)>> {
+    let mut statement = conn.prepare(&format!(
+        "SELECT type, name, sql FROM {}.sqlite_schema \
+         WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\' \
+         AND name NOT LIKE 'libsql\\_%' ESCAPE '\\' \
+         AND tbl_name NOT LIKE '\\_turso\\_sync\\_%' ESCAPE '\\' \
+         AND name NOT LIKE '\\_turso\\_sync\\_%' ESCAPE '\\' ORDER BY type, name",
+        db
+    ))?;
+    let objects =
+        statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?.collect::<rusqlite::Result<_>>()?;
+    Ok(objects)
+}
+
+fn column_names(conn: &rusqlite::Connection, table: &str) -> Result<Vec<String>> {
+    let mut statement = conn.prepare(&format!("PRAGMA table_info({})", quote_ident(table)))?;
+    let names = statement.query_map([], |row| row.get(1))?.collect::<rusqlite::Result<_>>()?;
+    Ok(names)
+}
+
+fn to_value(value: ValueRef<'_>) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => Value::Integer(i),
+        ValueRef::Real(f) => Value::Real(f),
+        ValueRef::Text(text) => Value::Text(String::from_utf8_lossy(text).into_owned()),
+        ValueRef::Blob(bytes) => Value::Blob(bytes.to_vec()),
+    }
+}
+
+fn key_string<'v>(values: impl IntoIterator<Item = &'v Value>) -> String {
+    values.into_iter().map(sql_literal).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::diff_databases;
+    use crate::testutil::{apply, exec, open, temp_dir};
+
+    #[tokio::test]
+    async fn changesets_round_trip_and_report_conflicts() {
+        let dir = temp_dir();
+        let (replica, working) = (dir.path("replica.db"), dir.path("working.db"));
+        exec(
+            &replica,
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, score REAL, data BLOB);
+             INSERT INTO t VALUES (1, 'a', 0.1, X'00'), (2, 'b', 2.5, NULL), (3, 'c', NULL, X'FF27');
+             CREATE TABLE m(a TEXT, b INTEGER, v, PRIMARY KEY (a, b)) WITHOUT ROWID;
+             INSERT INTO m VALUES ('x', 1, 'one'), ('y', 2, 'two');",
+        )
+        .await;
+        std::fs::copy(&replica, &working).unwrap();
+        exec(
+            &working,
+            "UPDATE t SET score = 0.1 + 0.2, data = X'0102' WHERE id = 2;
+             DELETE FROM t WHERE id = 3;
+             INSERT INTO t VALUES (4, 'it''s', -1e-300, X'');
+             UPDATE m SET v = 'ONE' WHERE a = 'x';
+             INSERT INTO m VALUES ('z', 3, NULL);",
+        )
+        .await;
+
+        let (_db, conn) = open(&working).await;
+        for format in [DiffFormat::Changeset, DiffFormat::Patchset] {
+            let data = create(&replica, &working, format).unwrap();
+            assert_eq!(DiffFormat::detect(&data), format);
+            let rows = decode(&data).unwrap();
+            assert_eq!(encode(&rows, format), data);
+            let (changes, _) = to_changes(&rows, &conn).await.unwrap();
+            assert_eq!(changes.len(), 5);
+
+            let copy = dir.path("copy.db");
+            std::fs::copy(&replica, &copy).unwrap();
+            apply(&copy, &changes).await;
+            assert!(diff_databases(&copy, &working).await.unwrap().is_empty(), "{:?}", format);
+        }
+
+        let data = create(&replica, &working, DiffFormat::Changeset).unwrap();
+        let target = dir.path("target.db");
+        std::fs::copy(&replica, &target).unwrap();
+        assert!(apply_local(&target, &data, ConflictPolicy::Abort).unwrap().is_empty());
+        assert!(diff_databases(&target, &working).await.unwrap().is_empty());
+
+        // Edited since the changeset was made: the updated row and the deleted one
+        std::fs::copy(&replica, &target).unwrap();
+        exec(&target, "UPDATE t SET score = 9.0 WHERE id = 2; DELETE FROM t WHERE id = 3;").await;
+        let conflicts = apply_local(&target, &data, ConflictPolicy::Abort).unwrap();
+        let kinds: Vec<(RowOp, &ConflictKind)> = conflicts.iter().map(|c| (c.op, &c.kind)).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (RowOp::Update, &ConflictKind::Updated(vec!["score".to_string()])),
+                (RowOp::Delete, &ConflictKind::Deleted)
+            ]
+        );
+        assert_eq!(diff_databases(&target, &working).await.unwrap().len(), 4, "aborted apply left changes");
+
+        assert_eq!(apply_local(&target, &data, ConflictPolicy::Rebase).unwrap().len(), 2);
+        assert_eq!(diff_databases(&target, &working).await.unwrap().len(), 1, "only the conflicting update is left");
+    }
+}
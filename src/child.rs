@@ -0,0 +1,96 @@
+use anyhow::{Context, Result};
+use log::{info, log, warn, Level};
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::Command;
+
+use crate::shutdown;
+
+/// Run `command`, a program followed by its arguments, to completion. Every line it
+/// writes is logged with the program name in front: stdout at info level, stderr at
+/// warn level. A shutdown request is passed on to it as SIGTERM, and it is waited
+/// for until it exits or the shutdown timeout ends the process.
+pub async fn run_logged(command: &[String]) -> Result<ExitStatus> {
+    let (program, args) = command.split_first().context("No command to run")?;
+    let name =
+        Path::new(program).file_name().map_or_else(|| program.clone(), |name| name.to_string_lossy().into_owned());
+    let mut child = Command::new(program)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to start {}", program))?;
+
+    let stdout = child.stdout.take().context("Child stdout is not piped")?;
+    let stderr = child.stderr.take().context("Child stderr is not piped")?;
+    let wait = async {
+        let exited = tokio::select! {
+            status = child.wait() => Some(status),
+            _ = shutdown::wait() => None,
+        };
+        match exited {
+            Some(status) => status,
+            None => {
+                terminate(&child, &name);
+                child.wait().await
+            }
+        }
+    };
+    let (status, _, _) =
+        tokio::join!(wait, log_lines(stdout, &name, Level::Info), log_lines(stderr, &name, Level::Warn));
+    status.with_context(|| format!("Failed to wait for {}", program))
+}
+
+/// Ask `child` to exit with SIGTERM, so it can stop the way it would when the machine shuts down
+fn terminate(child: &tokio::process::Child, name: &str) {
+    let Some(pid) = child.id() else { return };
+    info!("Sending SIGTERM to {} (pid {})", name, pid);
+    #[cfg(unix)]
+    // SAFETY: kill() only sends a signal; the pid belongs to a child that has not been reaped yet
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        warn!("Could not send SIGTERM to {}: {}", name, std::io::Error::last_os_error());
+    }
+}
+
+/// Exit code that passes `status` on: the child's own, or 128 plus the signal that
+/// killed it, the way shells report it
+pub fn exit_code(status: ExitStatus) -> u8 {
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        return 128u8.saturating_add(signal as u8);
+    }
+    status.code().map_or(1, |code| code as u8)
+}
+
+/// Log `reader` line by line until it is closed. Output that is not UTF-8 is logged
+/// lossily rather than ending the loop, which would leave the child blocked on a full pipe.
+async fn log_lines(reader: impl AsyncRead + Unpin, name: &str, level: Level) {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => log!(level, "[{}] {}", name, String::from_utf8_lossy(&line).trim_end()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn passes_on_the_exit_status_of_the_command() {
+        let script = "echo out; printf 'no newline \\377' >&2; exit 3";
+        let status = run_logged(&["sh".to_string(), "-c".to_string(), script.to_string()]).await.unwrap();
+        assert_eq!(exit_code(status), 3);
+
+        let killed = run_logged(&["sh".to_string(), "-c".to_string(), "kill -TERM $$".to_string()]).await.unwrap();
+        assert_eq!(exit_code(killed), 128 + 15);
+        assert!(run_logged(&["./no-such-program".to_string()]).await.is_err());
+        assert!(run_logged(&[]).await.is_err());
+    }
+}
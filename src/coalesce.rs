@@ -0,0 +1,240 @@
+use libsql::Value;
+
+use crate::change::{quote_ident, Change, RowOp};
+use crate::prepared::{value_size, BoundStatement};
+
+/// Largest number of rows merged into one statement
+const MAX_ROWS_PER_STATEMENT: usize = 500;
+
+/// Largest number of bound values per statement, SQLite's historical variable limit
+const MAX_VALUES_PER_STATEMENT: usize = 999;
+
+/// Largest coalesced statement payload, SQL text plus bound values
+const MAX_STATEMENT_BYTES: usize = 256 * 1024;
+
+/// Consecutive row changes that can be merged into one statement
+struct Run {
+    table: String,
+    op: RowOp,
+    columns: Vec<String>,
+    rows: Vec<Vec<Value>>,
+    /// The first change on its own, used when the run ends up with a single row
+    first: BoundStatement,
+    values: usize,
+    bytes: usize,
+}
+
+impl Run {
+    fn accepts(&self, op: RowOp, table: &str, columns: &[String], row_bytes: usize) -> bool {
+        self.op == op
+            && self.table == table
+            && self.columns == columns
+            && self.rows.len() < MAX_ROWS_PER_STATEMENT
+            && self.values + columns.len() <= MAX_VALUES_PER_STATEMENT
+            && self.bytes + row_bytes <= MAX_STATEMENT_BYTES
+    }
+
+    fn into_bound(self) -> BoundStatement {
+        if self.rows.len() == 1 {
+            return self.first;
+        }
+        let table = quote_ident(&self.table);
+        let columns: Vec<String> = self.columns.iter().map(|c| quote_ident(c)).collect();
+        let tuple = format!("({})", vec!["?"; columns.len()].join(","));
+        let tuples = vec![tuple.as_str(); self.rows.len()].join(",");
+
+        let sql = match self.op {
+            RowOp::Delete if columns.len() == 1 => {
+                format!("DELETE FROM {} WHERE {} IN ({})", table, columns[0], vec!["?"; self.rows.len()].join(","))
+            }
+            RowOp::Delete => format!("DELETE FROM {} WHERE ({}) IN (VALUES{})", table, columns.join(","), tuples),
+            _ => format!("INSERT INTO {}({}) VALUES{}", table, columns.join(","), tuples),
+        };
+        BoundStatement { sql, params: self.rows.into_iter().flatten().collect() }
+    }
+}
+
+/// Turn changes into bound statements, merging runs of consecutive same-table DELETEs
+/// into `DELETE ... WHERE id IN (?,...)` and runs of INSERTs with the same columns
+/// into multi-row INSERTs.
+///
+/// Order is preserved. Identical SQL is produced for runs of the same table, operation,
+/// columns and length, so the executor can reuse one prepared statement for them.
+/// UPDATEs are bound one row at a time and schema changes pass through without parameters.
+pub fn coalesce_changes<'a>(changes: impl IntoIterator<Item = &'a Change>) -> Vec<BoundStatement> {
+    let mut coalesced = Vec::new();
+    let mut run: Option<Run> = None;
+
+    for change in changes {
+        let row = match change {
+            Change::Row(row) if row.op != RowOp::Update => row,
+            Change::Row(row) => {
+                coalesced.extend(run.take().map(Run::into_bound));
+                coalesced.push(row.to_bound());
+                continue;
+            }
+            Change::Schema(sql) => {
+                coalesced.extend(run.take().map(Run::into_bound));
+                coalesced.push(BoundStatement::raw(sql.clone()));
+                continue;
+            }
+        };
+
+        // DELETEs match on their key; parsed INSERTs carry every column in `values`
+        let fields: Vec<&(String, Value)> = match row.op {
+            RowOp::Delete => row.key.iter().collect(),
+            _ => row.key.iter().chain(row.values.iter()).collect(),
+        };
+        let columns: Vec<String> = fields.iter().map(|(c, _)| c.clone()).collect();
+        let values: Vec<Value> = fields.iter().map(|(_, v)| v.clone()).collect();
+        let row_bytes = values.iter().map(value_size).sum::<usize>() + 2 * values.len() + 2;
+
+        match run.as_mut() {
+            Some(r) if r.accepts(row.op, &row.table, &columns, row_bytes) => {
+                r.rows.push(values);
+                r.values += columns.len();
+                r.bytes += row_bytes;
+            }
+            _ => {
+                coalesced.extend(run.take().map(Run::into_bound));
+                run = Some(Run {
+                    table: row.table.clone(),
+                    op: row.op,
+                    values: columns.len(),
+                    columns,
+                    rows: vec![values],
+                    first: row.to_bound(),
+                    bytes: row_bytes,
+                });
+            }
+        }
+    }
+    coalesced.extend(run.map(Run::into_bound));
+    coalesced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::change::RowChange;
+    use crate::prepared::StatementCache;
+    use crate::testutil::{exec, open, temp_dir};
+
+    fn row(op: RowOp, id: i64, values: &[(&str, Value)]) -> Change {
+        Change::Row(RowChange {
+            table: "t".to_string(),
+            op,
+            key: vec![("id".to_string(), Value::Integer(id))],
+            values: values.iter().map(|(c, v)| (c.to_string(), v.clone())).collect(),
+        })
+    }
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    fn bound(change: &Change) -> BoundStatement {
+        match change {
+            Change::Row(row) => row.to_bound(),
+            Change::Schema(sql) => BoundStatement::raw(sql.clone()),
+        }
+    }
+
+    #[test]
+    fn chunks_at_exactly_999_values() {
+        // Three values per row: 333 rows fill a statement exactly
+        let changes: Vec<Change> =
+            (1..=334).map(|id| row(RowOp::Insert, id, &[("a", Value::Integer(id)), ("b", text("x"))])).collect();
+        let coalesced = coalesce_changes(&changes);
+        assert_eq!(coalesced.len(), 2);
+        assert_eq!(coalesced[0].params.len(), 999);
+        assert_eq!(coalesced[0].sql.matches(",?)").count(), 333);
+        // A run of one keeps the sqldiff shape
+        assert_eq!(coalesced[1], bound(&changes[333]));
+        assert_eq!(coalesced[1].sql, "INSERT INTO t(id,a,b) VALUES(?,?,?)");
+    }
+
+    #[test]
+    fn caps_rows_and_bytes_per_statement() {
+        let deletes: Vec<Change> = (1..=1001).map(|id| row(RowOp::Delete, id, &[])).collect();
+        let coalesced = coalesce_changes(&deletes);
+        assert_eq!(coalesced.len(), 3);
+        assert!(coalesced[0].sql.starts_with("DELETE FROM t WHERE id IN (?,?,"));
+        // Full runs produce identical SQL and share one prepared statement
+        assert_eq!(coalesced[0].sql, coalesced[1].sql);
+        assert_eq!(coalesced[1].params.last(), Some(&Value::Integer(1000)));
+
+        let big = "x".repeat(100 * 1024);
+        let inserts: Vec<Change> = (1..=5).map(|id| row(RowOp::Insert, id, &[("body", text(&big))])).collect();
+        let coalesced = coalesce_changes(&inserts);
+        let rows: Vec<usize> =
+            coalesced.iter().map(|s| s.params.iter().filter(|v| **v == text(&big)).count()).collect();
+        assert_eq!(rows, vec![2, 2, 1]);
+        assert!(coalesced.iter().all(|s| s.size() < MAX_STATEMENT_BYTES + 1024));
+    }
+
+    #[test]
+    fn different_column_sets_and_schema_changes_split_runs() {
+        let changes = vec![
+            row(RowOp::Insert, 1, &[("name", text("a"))]),
+            row(RowOp::Insert, 2, &[("name", text("b"))]),
+            row(RowOp::Insert, 3, &[("name", text("c")), ("email", text("c@x"))]),
+            row(RowOp::Update, 1, &[("name", text("A"))]),
+            row(RowOp::Update, 2, &[("email", text("b@x"))]),
+            Change::Schema("CREATE INDEX t_name ON t(name)".to_string()),
+            row(RowOp::Delete, 3, &[]),
+            row(RowOp::Delete, 4, &[]),
+        ];
+        let coalesced = coalesce_changes(&changes);
+        assert_eq!(coalesced.len(), 6);
+        assert!(coalesced[0].sql.ends_with("VALUES(?,?),(?,?)"));
+        assert_eq!(coalesced[0].params, vec![Value::Integer(1), text("a"), Value::Integer(2), text("b")]);
+        assert_eq!(coalesced[1..5], changes[2..6].iter().map(bound).collect::<Vec<_>>());
+        assert!(coalesced[4].params.is_empty());
+        assert!(coalesced[5].sql.ends_with("IN (?,?)"));
+    }
+
+    #[tokio::test]
+    async fn coalesced_statements_apply_like_the_originals() {
+        let dir = temp_dir();
+        let path = dir.path("t.db");
+        exec(
+            &path,
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, n INTEGER);
+             INSERT INTO t VALUES (1, 'a', 1), (2, 'b', 2), (3, 'c', 3), (4, 'd', 4);
+             CREATE TABLE m(a TEXT, b INTEGER, v TEXT, PRIMARY KEY (a, b)) WITHOUT ROWID;
+             INSERT INTO m VALUES ('x', 1, 'keep'), ('x', 2, 'drop'), ('y', 1, 'drop');",
+        )
+        .await;
+        let mut changes = vec![
+            row(RowOp::Delete, 4, &[]),
+            row(RowOp::Delete, 3, &[]),
+            row(RowOp::Update, 1, &[("name", text("A")), ("n", Value::Integer(10))]),
+            row(RowOp::Insert, 5, &[("name", text("e")), ("n", Value::Integer(5))]),
+            row(RowOp::Insert, 6, &[("name", text("it's")), ("n", Value::Null)]),
+        ];
+        for (a, b) in [("x", 2), ("y", 1)] {
+            changes.push(Change::Row(RowChange {
+                table: "m".to_string(),
+                op: RowOp::Delete,
+                key: vec![("a".to_string(), text(a)), ("b".to_string(), Value::Integer(b))],
+                values: Vec::new(),
+            }));
+        }
+        let coalesced = coalesce_changes(&changes);
+        assert_eq!(coalesced.len(), 4);
+        assert!(coalesced[3].sql.ends_with("IN (VALUES(?,?),(?,?))"));
+
+        let (_db, conn) = open(&path).await;
+        StatementCache::new(&conn).execute_all(&coalesced).await.unwrap();
+        let mut rows = conn
+            .query("SELECT group_concat(id || ':' || name || ':' || ifnull(n, '-'), ' ') FROM t", ())
+            .await
+            .unwrap();
+        let t: String = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(t, "1:A:10 2:b:2 5:e:5 6:it's:-");
+        let mut rows = conn.query("SELECT group_concat(v) FROM m", ()).await.unwrap();
+        let m: String = rows.next().await.unwrap().unwrap().get(0).unwrap();
+        assert_eq!(m, "keep");
+    }
+}
@@ -0,0 +1,331 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use libsql::{Connection, Value};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use crate::change::{quote_ident, sql_literal, Change, RowChange, RowOp};
+use crate::diff::compare_values;
+
+/// Largest number of key values looked up by one query
+const MAX_KEY_VALUES_PER_QUERY: usize = 999;
+
+/// What push does when rows it would change were also changed remotely
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Apply nothing and report the conflicts
+    Abort,
+    /// Apply only the changes that do not overlap remote edits; the remote keeps its values for the rest
+    Rebase,
+}
+
+/// Column values of a row, by name
+pub type RowValues = Vec<(String, Value)>;
+
+/// The version of every row touched by a diff in one database, aligned with the changes.
+/// `None` means the row (or its table) does not exist; schema changes are always `None`.
+pub type RowSnapshot = Vec<Option<RowValues>>;
+
+/// How a remote edit overlaps a change
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConflictKind {
+    /// The row was deleted remotely
+    Deleted,
+    /// The row was inserted remotely with other values
+    Inserted,
+    /// These columns were changed remotely to values other than ours
+    Updated(Vec<String>),
+    /// Applying the change would violate a constraint
+    Constraint,
+}
+
+/// A change that overlaps an edit made remotely since the replica was synced
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    /// Position of the change in the diff
+    pub index: usize,
+    pub op: RowOp,
+    pub table: String,
+    pub key: RowValues,
+    pub kind: ConflictKind,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            RowOp::Insert => "INSERT",
+            RowOp::Update => "UPDATE",
+            RowOp::Delete => "DELETE",
+        };
+        let key: Vec<String> = self.key.iter().map(|(c, v)| format!("{}={}", c, sql_literal(v))).collect();
+        write!(f, "{} {} {}: ", op, self.table, key.join(" AND "))?;
+        match &self.kind {
+            ConflictKind::Deleted => write!(f, "row was deleted remotely"),
+            ConflictKind::Inserted => write!(f, "row was inserted remotely with different values"),
+            ConflictKind::Updated(columns) if self.op == RowOp::Update => {
+                write!(f, "{} changed remotely", columns.join(", "))
+            }
+            ConflictKind::Updated(columns) => write!(f, "row was updated remotely ({})", columns.join(", ")),
+            ConflictKind::Constraint => write!(f, "change violates a constraint"),
+        }
+    }
+}
+
+/// Outcome of comparing touched rows between the replica and the remote
+#[derive(Debug, Default)]
+pub struct ConflictCheck {
+    pub conflicts: Vec<Conflict>,
+    /// Changes the remote already contains, by position in the diff
+    pub redundant: Vec<usize>,
+}
+
+impl ConflictCheck {
+    /// Classify every row change against the row as it was in the replica (`base`)
+    /// and as it is now on the remote (`current`).
+    ///
+    /// A change conflicts when the remote edited the row since the base and the edit
+    /// does not already match ours. UPDATEs are compared column by column, so remote
+    /// edits to columns the change leaves alone are not conflicts.
+    pub fn detect(changes: &[Change], base: &RowSnapshot, current: &RowSnapshot) -> Self {
+        let mut check = ConflictCheck::default();
+        for (index, change) in changes.iter().enumerate() {
+            let Change::Row(row) = change else { continue };
+            let (before, now) = (&base[index], &current[index]);
+            let kind = match row.op {
+                RowOp::Update => match now {
+                    None if before.is_some() => Some(ConflictKind::Deleted),
+                    None => None,
+                    Some(now) => {
+                        if matches_values(now, &row.values) {
+                            check.redundant.push(index);
+                            continue;
+                        }
+                        let changed: Vec<String> = row
+                            .values
+                            .iter()
+                            .filter(|(column, ours)| {
+                                let old = before.as_ref().and_then(|b| value(b, column));
+                                let new = value(now, column);
+                                !same(old, new) && !same(new, Some(ours))
+                            })
+                            .map(|(column, _)| column.clone())
+                            .collect();
+                        (!changed.is_empty()).then_some(ConflictKind::Updated(changed))
+                    }
+                },
+                RowOp::Delete => match now {
+                    None => {
+                        check.redundant.push(index);
+                        continue;
+                    }
+                    Some(now) => match before {
+                        Some(before) => {
+                            let changed = changed_columns(before, now);
+                            (!changed.is_empty()).then_some(ConflictKind::Updated(changed))
+                        }
+                        None => Some(ConflictKind::Inserted),
+                    },
+                },
+                RowOp::Insert => match (before, now) {
+                    (_, Some(now)) if matches_values(now, &row.values) => {
+                        check.redundant.push(index);
+                        continue;
+                    }
+                    (None, None) => None,
+                    (Some(_), None) => Some(ConflictKind::Deleted),
+                    (None, Some(_)) => Some(ConflictKind::Inserted),
+                    (Some(before), Some(now)) => {
+                        let changed = changed_columns(before, now);
+                        (!changed.is_empty()).then_some(ConflictKind::Updated(changed))
+                    }
+                },
+            };
+            if let Some(kind) = kind {
+                check.conflicts.push(Conflict {
+                    index,
+                    op: row.op,
+                    table: row.table.clone(),
+                    key: row.key.clone(),
+                    kind,
+                });
+            }
+        }
+        check
+    }
+
+    /// Positions of the conflicting and redundant changes
+    pub fn dropped(&self) -> HashSet<usize> {
+        self.conflicts.iter().map(|c| c.index).chain(self.redundant.iter().copied()).collect()
+    }
+
+    /// Changes left once conflicting and redundant ones are dropped
+    pub fn rebase(&self, changes: Vec<Change>) -> Vec<Change> {
+        let dropped = self.dropped();
+        changes.into_iter().enumerate().filter(|(i, _)| !dropped.contains(i)).map(|(_, change)| change).collect()
+    }
+
+    /// Conflicts one per line, listing at most `limit` of them
+    pub fn report(&self, limit: usize) -> String {
+        let mut lines: Vec<String> = self.conflicts.iter().take(limit).map(|c| format!("  - {}", c)).collect();
+        if self.conflicts.len() > limit {
+            lines.push(format!("  ... and {} more", self.conflicts.len() - limit));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Read the version of every row touched by `changes` from `conn`.
+///
+/// Rows are looked up per table with `WHERE key IN (...)` queries rather than one
+/// query per row, so checking a remote database costs a few round trips.
+pub async fn snapshot_rows(conn: &Connection, changes: &[Change]) -> Result<RowSnapshot> {
+    let tables = existing_tables(conn).await?;
+    let mut snapshot: RowSnapshot = vec![None; changes.len()];
+
+    let mut groups: BTreeMap<(&str, Vec<&str>), Vec<usize>> = BTreeMap::new();
+    for (index, change) in changes.iter().enumerate() {
+        if let Change::Row(row) = change {
+            if !row.key.is_empty() && tables.contains(&row.table.to_lowercase()) {
+                let key: Vec<&str> = row.key.iter().map(|(c, _)| c.as_str()).collect();
+                groups.entry((row.table.as_str(), key)).or_default().push(index);
+            }
+        }
+    }
+
+    for ((table, key), indices) in groups {
+        let key_columns: Vec<String> = key.iter().map(|c| quote_ident(c)).collect();
+        let rows_per_query = (MAX_KEY_VALUES_PER_QUERY / key.len()).max(1);
+
+        for chunk in indices.chunks(rows_per_query) {
+            let rows: Vec<&RowChange> = chunk
+                .iter()
+                .filter_map(|&i| match &changes[i] {
+                    Change::Row(row) => Some(row),
+                    Change::Schema(_) => None,
+                })
+                .collect();
+            let filter = if key.len() == 1 {
+                format!("{} IN ({})", key_columns[0], vec!["?"; rows.len()].join(","))
+            } else {
+                let tuple = format!("({})", vec!["?"; key.len()].join(","));
+                format!("({}) IN (VALUES{})", key_columns.join(","), vec![tuple.as_str(); rows.len()].join(","))
+            };
+            let sql = format!("SELECT {}, * FROM {} WHERE {}", key_columns.join(", "), quote_ident(table), filter);
+            let params: Vec<Value> = rows.iter().flat_map(|r| r.key.iter().map(|(_, v)| v.clone())).collect();
+
+            let mut result =
+                conn.query(&sql, params).await.with_context(|| format!("Failed to read touched rows of {}", table))?;
+            let mut found: HashMap<String, RowValues> = HashMap::new();
+            while let Some(row) = result.next().await? {
+                let values = (0..row.column_count()).map(|i| row.get_value(i)).collect::<libsql::Result<Vec<_>>>()?;
+                let columns: RowValues = (key.len()..values.len())
+                    .map(|i| (row.column_name(i as i32).unwrap_or_default().to_string(), values[i].clone()))
+                    .collect();
+                found.insert(key_string(&values[..key.len()]), columns);
+            }
+
+            for (&index, row) in chunk.iter().zip(rows) {
+                let key_values: Vec<Value> = row.key.iter().map(|(_, v)| v.clone()).collect();
+                snapshot[index] = found.get(&key_string(&key_values)).cloned();
+            }
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Lowercased names of the tables in a database
+async fn existing_tables(conn: &Connection) -> Result<HashSet<String>> {
+    let mut rows =
+        conn.query("SELECT name FROM sqlite_schema WHERE type = 'table'", ()).await.context("Failed to list tables")?;
+    let mut tables = HashSet::new();
+    while let Some(row) = rows.next().await? {
+        tables.insert(row.get::<String>(0)?.to_lowercase());
+    }
+    Ok(tables)
+}
+
+fn key_string(values: &[Value]) -> String {
+    values.iter().map(sql_literal).collect::<Vec<_>>().join(",")
+}
+
+fn value<'a>(row: &'a RowValues, column: &str) -> Option<&'a Value> {
+    row.iter().find(|(c, _)| c.eq_ignore_ascii_case(column)).map(|(_, v)| v)
+}
+
+fn same(a: Option<&Value>, b: Option<&Value>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => compare_values(a, b) == Ordering::Equal,
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+/// Whether `row` already holds every value in `values`; columns it lacks are ignored
+fn matches_values(row: &RowValues, values: &RowValues) -> bool {
+    values.iter().all(|(column, ours)| value(row, column).is_none_or(|v| compare_values(v, ours) == Ordering::Equal))
+}
+
+/// Columns whose values differ between two versions of a row
+fn changed_columns(before: &RowValues, now: &RowValues) -> Vec<String> {
+    let mut changed: Vec<String> = now
+        .iter()
+        .filter(|(column, v)| !same(value(before, column), Some(v)))
+        .map(|(column, _)| column.clone())
+        .collect();
+    changed.extend(before.iter().filter(|(c, _)| value(now, c).is_none()).map(|(c, _)| c.clone()));
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::diff_databases;
+    use crate::testutil::{exec, open, temp_dir};
+
+    #[tokio::test]
+    async fn overlapping_remote_edits_conflict_and_others_rebase() {
+        let dir = temp_dir();
+        let (base, ours, remote) = (dir.path("base.db"), dir.path("ours.db"), dir.path("remote.db"));
+        let schema = "CREATE TABLE c(id INTEGER PRIMARY KEY, email TEXT, phone TEXT);
+                      INSERT INTO c VALUES (1, 'a', '1'), (2, 'b', '2'), (3, 'c', '3'), (4, 'd', '4'), (5, 'e', '5');";
+        exec(&base, schema).await;
+        exec(&ours, schema).await;
+        exec(&remote, schema).await;
+        exec(
+            &ours,
+            "UPDATE c SET email = 'A' WHERE id = 1; UPDATE c SET email = 'B' WHERE id = 2;
+             DELETE FROM c WHERE id = 3; UPDATE c SET email = 'D' WHERE id = 4;
+             INSERT INTO c VALUES (6, 'f', '6'), (7, 'g', '7');",
+        )
+        .await;
+        exec(
+            &remote,
+            "UPDATE c SET phone = '9' WHERE id = 1; UPDATE c SET email = 'X' WHERE id = 2;
+             UPDATE c SET phone = '0' WHERE id = 3; DELETE FROM c WHERE id = 4;
+             INSERT INTO c VALUES (6, 'f', '6'), (7, 'other', '7');",
+        )
+        .await;
+
+        let changes = diff_databases(&base, &ours).await.unwrap();
+        let (_base_db, base_conn) = open(&base).await;
+        let (_remote_db, remote_conn) = open(&remote).await;
+        let before = snapshot_rows(&base_conn, &changes).await.unwrap();
+        let now = snapshot_rows(&remote_conn, &changes).await.unwrap();
+        let check = ConflictCheck::detect(&changes, &before, &now);
+
+        let report: Vec<String> = check.conflicts.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            report,
+            vec![
+                "UPDATE c id=2: email changed remotely",
+                "DELETE c id=3: row was updated remotely (phone)",
+                "UPDATE c id=4: row was deleted remotely",
+                "INSERT c id=7: row was inserted remotely with different values",
+            ]
+        );
+        // Row 6 was inserted identically; row 1 only had another column changed
+        let rebased = check.rebase(changes);
+        assert_eq!(rebased.len(), 1);
+        assert!(matches!(&rebased[0], Change::Row(row) if row.key[0].1 == Value::Integer(1)));
+    }
+}
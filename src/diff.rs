@@ -0,0 +1,562 @@
+use anyhow::{Context, Result};
+use libsql::{Builder, Connection, Database, OpenFlags, Rows, Value};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::path::Path;
+
+use crate::change::{quote_ident, Change, RowChange, RowOp};
+use crate::sql;
+
+/// An entry from sqlite_schema
+struct SchemaObject {
+    kind: String,
+    table: String,
+    sql: String,
+}
+
+/// Column layout and row identity of a table
+pub struct TableInfo {
+    pub columns: Vec<String>,
+    decl_types: Vec<String>,
+    /// Columns identifying a row: the rowid (or its alias) for rowid tables,
+    /// the declared PRIMARY KEY for WITHOUT ROWID tables
+    pub key: Vec<String>,
+}
+
+impl TableInfo {
+    /// Columns that are not part of the key, in declaration order
+    pub fn value_columns(&self) -> Vec<String> {
+        self.columns.iter().filter(|c| !self.key.iter().any(|k| k.eq_ignore_ascii_case(c))).cloned().collect()
+    }
+}
+
+/// A WHERE clause restricting the rows a diff reads, with the values bound to it
+pub struct RowFilter {
+    pub condition: String,
+    pub params: Vec<Value>,
+}
+
+/// A read-only handle on one side of the diff
+struct DiffSide {
+    _db: Database,
+    conn: Connection,
+}
+
+/// Compute the changes that transform `source_path` into `dest_path`.
+///
+/// Mirrors `sqldiff` semantics: rows are matched by rowid (or INTEGER PRIMARY KEY)
+/// for rowid tables and by PRIMARY KEY for WITHOUT ROWID tables, columns appended
+/// to a table become `ALTER TABLE ... ADD COLUMN`, and any other table schema
+/// mismatch drops and recreates the table.
+pub async fn diff_databases(source_path: &str, dest_path: &str) -> Result<Vec<Change>> {
+    let source = open_side(source_path).await?;
+    let dest = open_side(dest_path).await?;
+
+    let source_schema = read_schema(&source.conn).await?;
+    let dest_schema = read_schema(&dest.conn).await?;
+
+    let mut changes = Vec::new();
+    // Tables whose dependent indexes and triggers were dropped with them
+    let mut dropped_tables: HashSet<String> = HashSet::new();
+    let mut rebuilt_tables: HashSet<String> = HashSet::new();
+
+    let table_names: BTreeSet<&String> = source_schema
+        .iter()
+        .chain(dest_schema.iter())
+        .filter(|(_, obj)| obj.kind == "table" && !is_virtual_table(&obj.sql))
+        .map(|(name, _)| name)
+        .collect();
+
+    for name in table_names {
+        let before = changes.len();
+        match (source_schema.get(name), dest_schema.get(name)) {
+            (Some(_), None) => {
+                changes.push(Change::Schema(format!("DROP TABLE {}", quote_ident(name))));
+                dropped_tables.insert(name.clone());
+            }
+            (None, Some(dest_obj)) => {
+                dump_table(&dest.conn, name, &dest_obj.sql, &mut changes).await?;
+                rebuilt_tables.insert(name.clone());
+            }
+            (Some(_), Some(dest_obj)) => {
+                let source_info = table_info(&source.conn, name).await?;
+                let dest_info = table_info(&dest.conn, name).await?;
+
+                if !schemas_compatible(&source_info, &dest_info) {
+                    debug!("Schema mismatch for table {}, recreating", name);
+                    changes.push(Change::Schema(format!("DROP TABLE {}", quote_ident(name))));
+                    dump_table(&dest.conn, name, &dest_obj.sql, &mut changes).await?;
+                    rebuilt_tables.insert(name.clone());
+                } else {
+                    for (column, decl_type) in
+                        dest_info.columns.iter().zip(dest_info.decl_types.iter()).skip(source_info.columns.len())
+                    {
+                        let definition = if decl_type.is_empty() {
+                            quote_ident(column)
+                        } else {
+                            format!("{} {}", quote_ident(column), decl_type)
+                        };
+                        changes.push(Change::Schema(format!(
+                            "ALTER TABLE {} ADD COLUMN {}",
+                            quote_ident(name),
+                            definition
+                        )));
+                    }
+                    diff_table_rows(&source.conn, &dest.conn, name, &source_info, &dest_info, None, &mut changes)
+                        .await?;
+                }
+            }
+            (None, None) => unreachable!(),
+        }
+        if changes.len() > before {
+            debug!("Table {}: {} changes", name, changes.len() - before);
+        }
+    }
+
+    // Indexes, views and triggers
+    let object_names: BTreeSet<&String> = source_schema
+        .iter()
+        .chain(dest_schema.iter())
+        .filter(|(_, obj)| obj.kind != "table")
+        .map(|(name, _)| name)
+        .collect();
+
+    for name in object_names {
+        match (source_schema.get(name), dest_schema.get(name)) {
+            (Some(source_obj), None) => {
+                if !dropped_tables.contains(&source_obj.table) && !rebuilt_tables.contains(&source_obj.table) {
+                    changes.push(drop_object(source_obj, name));
+                }
+            }
+            (None, Some(dest_obj)) => changes.push(Change::Schema(dest_obj.sql.clone())),
+            (Some(source_obj), Some(dest_obj)) => {
+                if rebuilt_tables.contains(&dest_obj.table) && source_obj.kind != "view" {
+                    changes.push(Change::Schema(dest_obj.sql.clone()));
+                } else if source_obj.sql != dest_obj.sql || source_obj.kind != dest_obj.kind {
+                    changes.push(drop_object(source_obj, name));
+                    changes.push(Change::Schema(dest_obj.sql.clone()));
+                }
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+
+    info!("Native diff found {} changes", changes.len());
+    Ok(changes)
+}
+
+/// Row changes that transform the rows of `table` matching `filter` in `source` into
+/// those in `dest`. Both tables must have the columns described by `info`.
+pub async fn diff_rows(
+    source: &Connection,
+    dest: &Connection,
+    table: &str,
+    info: &TableInfo,
+    filter: &RowFilter,
+) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    diff_table_rows(source, dest, table, info, info, Some(filter), &mut changes).await?;
+    Ok(changes)
+}
+
+/// Render changes in `sqldiff --transaction` format
+pub fn render_sql(changes: &[Change]) -> String {
+    if changes.is_empty() {
+        return String::new();
+    }
+    let mut sql = String::from("BEGIN TRANSACTION;\n");
+    for change in changes {
+        sql.push_str(&change.to_sql());
+        sql.push_str(";\n");
+    }
+    sql.push_str("COMMIT;\n");
+    sql
+}
+
+/// Read a diff back into changes, the inverse of `render_sql`. Transaction control
+/// statements are dropped; the apply decides how statements are grouped.
+pub fn parse_sql(diff_sql: &str) -> Vec<Change> {
+    sql::split_statements(diff_sql)
+        .iter()
+        .filter(|s| !sql::is_transaction_control(s))
+        .map(|s| Change::parse(s))
+        .collect()
+}
+
+async fn open_side(path: &str) -> Result<DiffSide> {
+    if !Path::new(path).exists() {
+        return Err(anyhow::anyhow!("Database {} does not exist", path));
+    }
+    let db = Builder::new_local(path)
+        .flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .build()
+        .await
+        .with_context(|| format!("Failed to open {} for diffing", path))?;
+    let conn = db.connect().context("Failed to get connection")?;
+    Ok(DiffSide { _db: db, conn })
+}
+
+/// Hash over the schema objects `diff_databases` compares; internal objects are left out
+pub async fn schema_hash(conn: &Connection) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    for (name, obj) in read_schema(conn).await? {
+        hasher.update(format!("{}\n{}\n{}\n", obj.kind, name, obj.sql));
+    }
+    Ok(hasher.finalize().into())
+}
+
+async fn read_schema(conn: &Connection) -> Result<BTreeMap<String, SchemaObject>> {
+    let mut rows = conn
+        .query(
+            "SELECT type, name, tbl_name, sql FROM sqlite_schema \
+             WHERE sql IS NOT NULL AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\' \
+             AND name NOT LIKE 'libsql\\_%' ESCAPE '\\' \
+             AND tbl_name NOT LIKE '\\_turso\\_sync\\_%' ESCAPE '\\' \
+             AND name NOT LIKE '\\_turso\\_sync\\_%' ESCAPE '\\'",
+            (),
+        )
+        .await
+        .context("Failed to read schema")?;
+
+    let mut schema = BTreeMap::new();
+    while let Some(row) = rows.next().await? {
+        let kind: String = row.get(0)?;
+        let name: String = row.get(1)?;
+        let table: String = row.get(2)?;
+        let sql: String = row.get(3)?;
+        schema.insert(name, SchemaObject { kind, table, sql });
+    }
+    Ok(schema)
+}
+
+pub async fn table_info(conn: &Connection, table: &str) -> Result<TableInfo> {
+    let mut rows = conn
+        .query(&format!("PRAGMA table_info({})", quote_ident(table)), ())
+        .await
+        .with_context(|| format!("Failed to read columns of {}", table))?;
+
+    let mut columns = Vec::new();
+    let mut decl_types = Vec::new();
+    let mut pk: Vec<(i64, String)> = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let name: String = row.get(1)?;
+        let decl_type: String = row.get::<Option<String>>(2)?.unwrap_or_default();
+        let pk_index: i64 = row.get(5)?;
+        if pk_index > 0 {
+            pk.push((pk_index, name.clone()));
+        }
+        columns.push(name);
+        decl_types.push(decl_type);
+    }
+    pk.sort();
+
+    let without_rowid = conn.query(&format!("SELECT rowid FROM {} LIMIT 0", quote_ident(table)), ()).await.is_err();
+
+    let key = if without_rowid {
+        pk.into_iter().map(|(_, name)| name).collect()
+    } else if pk.len() == 1 && is_rowid_alias(&columns, &decl_types, &pk[0].1) {
+        vec![pk[0].1.clone()]
+    } else {
+        let rowid = ["rowid", "_rowid_", "oid"]
+            .into_iter()
+            .find(|alias| !columns.iter().any(|c| c.eq_ignore_ascii_case(alias)))
+            .ok_or_else(|| anyhow::anyhow!("Table {} shadows every rowid alias", table))?;
+        vec![rowid.to_string()]
+    };
+
+    Ok(TableInfo { columns, decl_types, key })
+}
+
+fn is_rowid_alias(columns: &[String], decl_types: &[String], pk_column: &str) -> bool {
+    columns.iter().zip(decl_types.iter()).any(|(c, t)| c == pk_column && t.eq_ignore_ascii_case("INTEGER"))
+}
+
+pub fn is_virtual_table(sql: &str) -> bool {
+    sql.trim_start().get(..14).is_some_and(|prefix| prefix.eq_ignore_ascii_case("CREATE VIRTUAL"))
+}
+
+/// Row diffing is possible when keys agree and the destination only appends columns
+fn schemas_compatible(source: &TableInfo, dest: &TableInfo) -> bool {
+    source.key.len() == dest.key.len()
+        && source.key.iter().zip(dest.key.iter()).all(|(a, b)| a.eq_ignore_ascii_case(b))
+        && source.columns.len() <= dest.columns.len()
+        && source.columns.iter().zip(dest.columns.iter()).all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+fn drop_object(obj: &SchemaObject, name: &str) -> Change {
+    Change::Schema(format!("DROP {} {}", obj.kind.to_uppercase(), quote_ident(name)))
+}
+
+/// Emit the CREATE statement and every row of a table
+async fn dump_table(conn: &Connection, table: &str, create_sql: &str, changes: &mut Vec<Change>) -> Result<()> {
+    changes.push(Change::Schema(create_sql.to_string()));
+    let info = table_info(conn, table).await?;
+    let value_columns = info.value_columns();
+    let mut rows = select_ordered(conn, table, &info.key, &value_columns, None).await?;
+    while let Some(row) = next_values(&mut rows).await? {
+        changes.push(Change::Row(row_change(table, RowOp::Insert, &info.key, &value_columns, &row)));
+    }
+    Ok(())
+}
+
+/// Merge-join both tables in key order and emit the row changes between them
+async fn diff_table_rows(
+    source: &Connection,
+    dest: &Connection,
+    table: &str,
+    source_info: &TableInfo,
+    dest_info: &TableInfo,
+    filter: Option<&RowFilter>,
+    changes: &mut Vec<Change>,
+) -> Result<()> {
+    let key = &dest_info.key;
+    let source_columns = source_info.value_columns();
+    let dest_columns = dest_info.value_columns();
+
+    let mut source_rows = select_ordered(source, table, key, &source_columns, filter).await?;
+    let mut dest_rows = select_ordered(dest, table, key, &dest_columns, filter).await?;
+
+    let mut source_row = next_values(&mut source_rows).await?;
+    let mut dest_row = next_values(&mut dest_rows).await?;
+
+    loop {
+        let ordering = match (&source_row, &dest_row) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(s), Some(d)) => compare_keys(&s[..key.len()], &d[..key.len()]),
+        };
+
+        match ordering {
+            Ordering::Less => {
+                let row = source_row.take().expect("source row present");
+                changes.push(Change::Row(row_change(table, RowOp::Delete, key, &[], &row)));
+                source_row = next_values(&mut source_rows).await?;
+            }
+            Ordering::Greater => {
+                let row = dest_row.take().expect("dest row present");
+                changes.push(Change::Row(row_change(table, RowOp::Insert, key, &dest_columns, &row)));
+                dest_row = next_values(&mut dest_rows).await?;
+            }
+            Ordering::Equal => {
+                let s = source_row.take().expect("source row present");
+                let d = dest_row.take().expect("dest row present");
+                // Columns added to the destination read as NULL on the source side
+                let changed: Vec<(String, Value)> = dest_columns
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, column)| {
+                        let old = s.get(key.len() + i).unwrap_or(&Value::Null);
+                        let new = &d[key.len() + i];
+                        (compare_values(old, new) != Ordering::Equal).then(|| (column.clone(), new.clone()))
+                    })
+                    .collect();
+                if !changed.is_empty() {
+                    changes.push(Change::Row(RowChange {
+                        table: table.to_string(),
+                        op: RowOp::Update,
+                        key: key.iter().cloned().zip(d[..key.len()].iter().cloned()).collect(),
+                        values: changed,
+                    }));
+                }
+                source_row = next_values(&mut source_rows).await?;
+                dest_row = next_values(&mut dest_rows).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn select_ordered(
+    conn: &Connection,
+    table: &str,
+    key: &[String],
+    columns: &[String],
+    filter: Option<&RowFilter>,
+) -> Result<Rows> {
+    let select: Vec<String> = key.iter().chain(columns.iter()).map(|c| quote_ident(c)).collect();
+    let order: Vec<String> = key.iter().map(|c| format!("{} COLLATE BINARY", quote_ident(c))).collect();
+    let sql = format!(
+        "SELECT {} FROM {} WHERE {} ORDER BY {}",
+        select.join(", "),
+        quote_ident(table),
+        filter.map_or("1", |f| f.condition.as_str()),
+        order.join(", ")
+    );
+    let params = filter.map(|f| f.params.clone()).unwrap_or_default();
+    conn.query(&sql, params).await.with_context(|| format!("Failed to read rows of {}", table))
+}
+
+async fn next_values(rows: &mut Rows) -> Result<Option<Vec<Value>>> {
+    match rows.next().await? {
+        Some(row) => {
+            let values = (0..row.column_count()).map(|i| row.get_value(i)).collect::<libsql::Result<Vec<_>>>()?;
+            Ok(Some(values))
+        }
+        None => Ok(None),
+    }
+}
+
+fn row_change(table: &str, op: RowOp, key: &[String], columns: &[String], row: &[Value]) -> RowChange {
+    RowChange {
+        table: table.to_string(),
+        op,
+        key: key.iter().cloned().zip(row.iter().cloned()).collect(),
+        values: columns.iter().cloned().zip(row[key.len()..].iter().cloned()).collect(),
+    }
+}
+
+fn compare_keys(a: &[Value], b: &[Value]) -> Ordering {
+    a.iter().zip(b.iter()).map(|(x, y)| compare_values(x, y)).find(|o| *o != Ordering::Equal).unwrap_or(Ordering::Equal)
+}
+
+/// Order values the way SQLite does under BINARY collation:
+/// NULL < INTEGER/REAL (numerically) < TEXT < BLOB
+pub fn compare_values(a: &Value, b: &Value) -> Ordering {
+    fn class(v: &Value) -> u8 {
+        match v {
+            Value::Null => 0,
+            Value::Integer(_) | Value::Real(_) => 1,
+            Value::Text(_) => 2,
+            Value::Blob(_) => 3,
+        }
+    }
+    match (a, b) {
+        (Value::Integer(x), Value::Integer(y)) => x.cmp(y),
+        (Value::Integer(x), Value::Real(y)) => (*x as f64).partial_cmp(y).unwrap_or(Ordering::Equal),
+        (Value::Real(x), Value::Integer(y)) => x.partial_cmp(&(*y as f64)).unwrap_or(Ordering::Equal),
+        (Value::Real(x), Value::Real(y)) => x.partial_cmp(y).unwrap_or(Ordering::Equal),
+        (Value::Text(x), Value::Text(y)) => x.as_bytes().cmp(y.as_bytes()),
+        (Value::Blob(x), Value::Blob(y)) => x.cmp(y),
+        _ => class(a).cmp(&class(b)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{apply, exec, temp_dir, TempDir};
+
+    /// Databases `source` and `dest` set up by the given SQL
+    async fn pair(source_sql: &str, dest_sql: &str) -> (TempDir, String, String) {
+        let dir = temp_dir();
+        let (source, dest) = (dir.path("source.db"), dir.path("dest.db"));
+        exec(&source, source_sql).await;
+        exec(&dest, dest_sql).await;
+        (dir, source, dest)
+    }
+
+    /// Diff `source` into `dest`, apply the diff to a copy of `source` and check that
+    /// nothing is left to diff
+    async fn diff_and_apply(dir: &TempDir, source: &str, dest: &str) -> Vec<Change> {
+        let changes = diff_databases(source, dest).await.unwrap();
+        let copy = dir.path("copy.db");
+        std::fs::copy(source, &copy).unwrap();
+        apply(&copy, &changes).await;
+        let remaining = diff_databases(&copy, dest).await.unwrap();
+        assert!(remaining.is_empty(), "left after applying: {:?}", remaining);
+        changes
+    }
+
+    fn rows(changes: &[Change]) -> Vec<&RowChange> {
+        changes
+            .iter()
+            .filter_map(|c| match c {
+                Change::Row(row) => Some(row),
+                Change::Schema(_) => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn rowid_table_insert_update_delete() {
+        let schema = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, score REAL);";
+        let (dir, source, dest) = pair(
+            &format!("{} INSERT INTO t VALUES (1, 'a', 1.5), (2, 'b', 2.5), (3, 'c', 3.5);", schema),
+            &format!("{} INSERT INTO t VALUES (1, 'a', 1.5), (2, 'B', 2.5), (4, 'd', NULL);", schema),
+        )
+        .await;
+
+        let changes = diff_and_apply(&dir, &source, &dest).await;
+        let rows = rows(&changes);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(
+            (rows[0].op, &rows[0].values),
+            (RowOp::Update, &vec![("name".to_string(), Value::Text("B".into()))])
+        );
+        assert_eq!((rows[1].op, &rows[1].key), (RowOp::Delete, &vec![("id".to_string(), Value::Integer(3))]));
+        assert_eq!((rows[2].op, &rows[2].key), (RowOp::Insert, &vec![("id".to_string(), Value::Integer(4))]));
+    }
+
+    #[tokio::test]
+    async fn without_rowid_composite_key() {
+        let schema = "CREATE TABLE m(a TEXT, b INTEGER, v, PRIMARY KEY (a, b)) WITHOUT ROWID;";
+        let (dir, source, dest) = pair(
+            &format!("{} INSERT INTO m VALUES ('x', 1, 'one'), ('x', 2, 'two'), ('y', 1, 'gone');", schema),
+            &format!("{} INSERT INTO m VALUES ('x', 1, 'one'), ('x', 2, 'TWO'), ('z', 1, 'new');", schema),
+        )
+        .await;
+
+        let changes = diff_and_apply(&dir, &source, &dest).await;
+        let rows = rows(&changes);
+        assert_eq!(rows.len(), 3);
+        let key =
+            |a: &str, b: i64| vec![("a".to_string(), Value::Text(a.into())), ("b".to_string(), Value::Integer(b))];
+        assert_eq!((rows[0].op, &rows[0].key), (RowOp::Update, &key("x", 2)));
+        assert_eq!((rows[1].op, &rows[1].key), (RowOp::Delete, &key("y", 1)));
+        assert_eq!((rows[2].op, &rows[2].key), (RowOp::Insert, &key("z", 1)));
+    }
+
+    #[tokio::test]
+    async fn appended_column_is_added_not_rebuilt() {
+        let schema = "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT); INSERT INTO t VALUES (1, 'a'), (2, 'b');";
+        let (dir, source, dest) = pair(
+            schema,
+            &format!("{} ALTER TABLE t ADD COLUMN note TEXT; UPDATE t SET note = 'hi' WHERE id = 2;", schema),
+        )
+        .await;
+
+        let changes = diff_and_apply(&dir, &source, &dest).await;
+        assert!(
+            matches!(&changes[0], Change::Schema(sql) if sql.starts_with("ALTER TABLE") && sql.contains("ADD COLUMN"))
+        );
+        assert!(!changes.iter().any(|c| matches!(c, Change::Schema(sql) if sql.starts_with("DROP"))));
+        let rows = rows(&changes);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].values, vec![("note".to_string(), Value::Text("hi".into()))]);
+    }
+
+    #[tokio::test]
+    async fn incompatible_schema_drops_and_recreates() {
+        let (dir, source, dest) = pair(
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, old TEXT); INSERT INTO t VALUES (1, 'a', 'x');",
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, title TEXT); INSERT INTO t VALUES (1, 'a'), (2, 'b');
+             CREATE INDEX t_title ON t(title);",
+        )
+        .await;
+
+        let changes = diff_and_apply(&dir, &source, &dest).await;
+        assert!(matches!(&changes[0], Change::Schema(sql) if sql.starts_with("DROP TABLE")));
+        assert!(matches!(&changes[1], Change::Schema(sql) if sql.starts_with("CREATE TABLE")));
+        assert_eq!(rows(&changes).iter().filter(|r| r.op == RowOp::Insert).count(), 2);
+        assert!(matches!(changes.last(), Some(Change::Schema(sql)) if sql.starts_with("CREATE INDEX")));
+    }
+
+    #[tokio::test]
+    async fn numeric_and_null_comparisons() {
+        let (dir, source, dest) = pair(
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, v);
+             INSERT INTO t VALUES (1, 1), (2, NULL), (3, NULL), (4, 1), (5, 0);",
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, v);
+             INSERT INTO t VALUES (1, 1.0), (2, NULL), (3, 0), (4, '1'), (5, NULL);",
+        )
+        .await;
+
+        let changes = diff_and_apply(&dir, &source, &dest).await;
+        // 1 = 1.0 and NULL = NULL, as with sqldiff's IS comparison; the rest differ
+        let updated: Vec<&Value> = rows(&changes).iter().map(|r| &r.key[0].1).collect();
+        assert_eq!(updated, vec![&Value::Integer(3), &Value::Integer(4), &Value::Integer(5)]);
+    }
+}
@@ -0,0 +1,358 @@
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use libsql::{Connection, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fmt;
+
+use crate::change::{quote_ident, sql_literal, Change, RowOp};
+use crate::diff::{self, RowFilter, TableInfo};
+use crate::track;
+use crate::verify;
+
+/// Ranges holding at most this many rows on both sides are compared row by row
+const LEAF_ROWS: u64 = 64;
+
+/// Number of subranges a mismatching range is split into
+const FANOUT: u64 = 16;
+
+/// Side whose data is correct
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriftSource {
+    /// The local database; repairing writes to Turso
+    Local,
+    /// Turso; repairing writes to the local database
+    Remote,
+}
+
+/// Which tables `drift` compares and what it does about differences
+#[derive(Args, Debug, Clone)]
+pub struct DriftOptions {
+    /// Tables to compare, comma separated (default: every table)
+    #[arg(long, value_delimiter = ',')]
+    pub tables: Vec<String>,
+
+    /// Apply the changes that bring the other side in line with the source of truth
+    #[arg(long)]
+    pub repair: bool,
+
+    /// Side holding the correct data when repairing
+    #[arg(long, value_enum, default_value = "local")]
+    pub source: DriftSource,
+}
+
+/// Rows of one table that differ between the two sides
+#[derive(Debug)]
+pub struct TableDrift {
+    pub table: String,
+    /// Changes turning the target's rows into the source's, in key order
+    pub changes: Vec<Change>,
+    /// Key ranges hashed on both sides
+    pub ranges: usize,
+    /// Rows read from both sides to list the differences
+    pub rows_read: u64,
+}
+
+/// Outcome of comparing two databases table by table
+#[derive(Debug, Default)]
+pub struct DriftReport {
+    /// Tables compared, including the ones without drift
+    pub tables: Vec<TableDrift>,
+    /// Tables that could not be compared row by row, with the reason
+    pub skipped: Vec<(String, String)>,
+}
+
+impl DriftReport {
+    pub fn changes(&self) -> Vec<Change> {
+        self.tables.iter().flat_map(|t| t.changes.iter().cloned()).collect()
+    }
+}
+
+impl fmt::Display for TableDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = |op| self.changes.iter().filter(|c| matches!(c, Change::Row(r) if r.op == op)).count();
+        write!(
+            f,
+            "{}: {} rows differ ({} missing, {} changed, {} extra) - {} ranges hashed, {} rows read",
+            self.table,
+            self.changes.len(),
+            count(RowOp::Insert),
+            count(RowOp::Update),
+            count(RowOp::Delete),
+            self.ranges,
+            self.rows_read
+        )
+    }
+}
+
+/// Key range `(after, upto]`; `None` leaves that end open
+#[derive(Debug, Clone, Default)]
+struct KeyRange {
+    after: Option<Vec<Value>>,
+    upto: Option<Vec<Value>>,
+}
+
+/// Row count and SHA-256 of a key range
+#[derive(Debug, PartialEq, Eq)]
+struct RangeHash {
+    rows: u64,
+    hash: [u8; 32],
+}
+
+/// Find the rows that differ between `source` and `target`.
+///
+/// Each table is compared as a tree of key ranges: both sides hash the whole table,
+/// and only ranges whose hashes differ are split into [`FANOUT`] subranges and
+/// hashed again, down to ranges small enough to diff row by row. Tables missing
+/// on one side or with different columns are skipped.
+pub async fn detect(source: &Connection, target: &Connection, tables: &[String]) -> Result<DriftReport> {
+    let source_tables = track::user_tables(source).await?;
+    let target_tables = track::user_tables(target).await?;
+    let names: BTreeSet<&String> = source_tables
+        .iter()
+        .chain(&target_tables)
+        .filter(|t| tables.is_empty() || tables.iter().any(|name| name.eq_ignore_ascii_case(t)))
+        .collect();
+
+    let mut report = DriftReport::default();
+    for table in names {
+        if !source_tables.contains(table) {
+            report.skipped.push((table.clone(), "only exists on the target".to_string()));
+            continue;
+        }
+        if !target_tables.contains(table) {
+            report.skipped.push((table.clone(), "missing on the target".to_string()));
+            continue;
+        }
+        let info = diff::table_info(source, table).await?;
+        let target_info = diff::table_info(target, table).await?;
+        if !same_columns(&info.key, &target_info.key) || !same_columns(&info.columns, &target_info.columns) {
+            report.skipped.push((table.clone(), "columns differ on the two sides".to_string()));
+            continue;
+        }
+        report.tables.push(compare_table(source, target, table, &info).await?);
+    }
+    Ok(report)
+}
+
+/// Differing rows one per line, listing at most `limit` of them
+pub fn report(changes: &[Change], limit: usize) -> String {
+    let mut lines: Vec<String> = changes
+        .iter()
+        .take(limit)
+        .filter_map(|change| match change {
+            Change::Row(row) => {
+                let key: Vec<String> = row.key.iter().map(|(c, v)| format!("{}={}", c, sql_literal(v))).collect();
+                Some(match row.op {
+                    RowOp::Insert => format!("  + {} {}: missing on the target", row.table, key.join(" AND ")),
+                    RowOp::Delete => format!("  - {} {}: only on the target", row.table, key.join(" AND ")),
+                    RowOp::Update => {
+                        let columns: Vec<&str> = row.values.iter().map(|(c, _)| c.as_str()).collect();
+                        format!("  ~ {} {}: {} differ", row.table, key.join(" AND "), columns.join(", "))
+                    }
+                })
+            }
+            Change::Schema(_) => None,
+        })
+        .collect();
+    if changes.len() > limit {
+        lines.push(format!("  ... and {} more", changes.len() - limit));
+    }
+    lines.join("\n")
+}
+
+async fn compare_table(source: &Connection, target: &Connection, table: &str, info: &TableInfo) -> Result<TableDrift> {
+    let mut drift = TableDrift { table: table.to_string(), changes: Vec::new(), ranges: 0, rows_read: 0 };
+    // Subranges are pushed in reverse, so differences are found in key order
+    let mut pending = vec![KeyRange::default()];
+
+    while let Some(range) = pending.pop() {
+        let filter = range_filter(&info.key, &range);
+        let source_hash = range_hash(source, table, info, &filter).await?;
+        let target_hash = range_hash(target, table, info, &filter).await?;
+        drift.ranges += 1;
+        if source_hash == target_hash {
+            continue;
+        }
+
+        if source_hash.rows.max(target_hash.rows) <= LEAF_ROWS {
+            drift.changes.extend(diff::diff_rows(target, source, table, info, &filter).await?);
+            drift.rows_read += source_hash.rows + target_hash.rows;
+            continue;
+        }
+
+        // Split where the side with more rows puts its boundaries, so no subrange stays as large
+        let (conn, rows) =
+            if source_hash.rows >= target_hash.rows { (source, source_hash.rows) } else { (target, target_hash.rows) };
+        let mut after = range.after;
+        let mut subranges = Vec::new();
+        for boundary in split_keys(conn, table, &info.key, &filter, rows).await? {
+            subranges.push(KeyRange { after, upto: Some(boundary.clone()) });
+            after = Some(boundary);
+        }
+        subranges.push(KeyRange { after, upto: range.upto });
+        pending.extend(subranges.into_iter().rev());
+    }
+    Ok(drift)
+}
+
+/// Count the rows matching `filter` and hash them in key order.
+///
+/// Rows are hashed like [`verify::checksums`] does, except that integral reals are
+/// hashed as integers, since `diff` treats them as equal. Reading the rows in key
+/// order makes the hash depend on which row holds which values, not just on the
+/// values present in the range.
+async fn range_hash(conn: &Connection, table: &str, info: &TableInfo, filter: &RowFilter) -> Result<RangeHash> {
+    let key: Vec<String> = info.key.iter().map(|c| quote_ident(c)).collect();
+    let columns: Vec<String> = key.iter().cloned().chain(info.value_columns().iter().map(|c| quote_ident(c))).collect();
+    let order: Vec<String> = key.iter().map(|c| format!("{} COLLATE BINARY", c)).collect();
+    let sql = format!(
+        "SELECT {} FROM {} WHERE {} ORDER BY {}",
+        columns.join(", "),
+        quote_ident(table),
+        filter.condition,
+        order.join(", ")
+    );
+    let mut rows =
+        conn.query(&sql, filter.params.clone()).await.with_context(|| format!("Failed to hash rows of {}", table))?;
+    let mut hasher = Sha256::new();
+    let mut count = 0;
+    while let Some(row) = rows.next().await? {
+        for i in 0..row.column_count() {
+            let value = match row.get_value(i)? {
+                Value::Real(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => Value::Integer(f as i64),
+                value => value,
+            };
+            verify::hash_value(&mut hasher, &value);
+        }
+        count += 1;
+    }
+    Ok(RangeHash { rows: count, hash: hasher.finalize().into() })
+}
+
+/// Keys splitting the `rows` rows matching `filter` into [`FANOUT`] ranges of about
+/// the same size; each key is the last one of its range
+async fn split_keys(
+    conn: &Connection,
+    table: &str,
+    key: &[String],
+    filter: &RowFilter,
+    rows: u64,
+) -> Result<Vec<Vec<Value>>> {
+    let columns: Vec<String> = key.iter().map(|c| quote_ident(c)).collect();
+    let order: Vec<String> = columns.iter().map(|c| format!("{} COLLATE BINARY", c)).collect();
+    let step = rows.div_ceil(FANOUT);
+    let sql = format!(
+        "SELECT * FROM (SELECT {}, row_number() OVER (ORDER BY {}) AS n FROM {} WHERE {}) \
+         WHERE n % {} = 0 AND n < {} ORDER BY n",
+        columns.join(", "),
+        order.join(", "),
+        quote_ident(table),
+        filter.condition,
+        step,
+        rows
+    );
+    let mut result = conn
+        .query(&sql, filter.params.clone())
+        .await
+        .with_context(|| format!("Failed to split key range of {}", table))?;
+    let mut keys = Vec::new();
+    while let Some(row) = result.next().await? {
+        keys.push((0..key.len()).map(|i| row.get_value(i as i32)).collect::<libsql::Result<Vec<_>>>()?);
+    }
+    Ok(keys)
+}
+
+/// WHERE clause selecting the rows of `range`, comparing keys as `ORDER BY` does
+fn range_filter(key: &[String], range: &KeyRange) -> RowFilter {
+    let columns: Vec<String> = key.iter().map(|c| format!("{} COLLATE BINARY", quote_ident(c))).collect();
+    let tuple = format!("({})", vec!["?"; key.len()].join(", "));
+    let mut conditions = Vec::new();
+    let mut params = Vec::new();
+    if let Some(after) = &range.after {
+        conditions.push(format!("({}) > {}", columns.join(", "), tuple));
+        params.extend(after.iter().cloned());
+    }
+    if let Some(upto) = &range.upto {
+        conditions.push(format!("({}) <= {}", columns.join(", "), tuple));
+        params.extend(upto.iter().cloned());
+    }
+    let condition = if conditions.is_empty() { "1".to_string() } else { conditions.join(" AND ") };
+    RowFilter { condition, params }
+}
+
+fn same_columns(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.eq_ignore_ascii_case(y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diff::diff_databases;
+    use crate::testutil::{apply, exec, open, temp_dir};
+
+    #[tokio::test]
+    async fn narrows_to_the_rows_that_differ_and_repairs_them() {
+        let dir = temp_dir();
+        let (source, target) = (dir.path("source.db"), dir.path("target.db"));
+        exec(
+            &source,
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, score REAL);
+             WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 3000)
+             INSERT INTO t SELECT i, 'row ' || i, i / 7.0 FROM s;
+             CREATE TABLE m(a TEXT, b INTEGER, v, PRIMARY KEY (b, a)) WITHOUT ROWID;
+             INSERT INTO m VALUES ('x', 1, 'one'), ('y', 2, X'02');
+             CREATE TABLE k(v TEXT);
+             INSERT INTO k VALUES ('keyless');",
+        )
+        .await;
+        std::fs::copy(&source, &target).unwrap();
+        exec(
+            &target,
+            "UPDATE t SET name = 'drifted' WHERE id = 1500;
+             UPDATE t SET score = 2 WHERE id = 14;
+             UPDATE t SET score = 3.5 WHERE id = 21;
+             UPDATE t SET name = CASE id WHEN 220 THEN 'row 361' ELSE 'row 220' END WHERE id IN (220, 361);
+             DELETE FROM t WHERE id = 2999;
+             INSERT INTO t VALUES (5000, 'extra', NULL);
+             UPDATE m SET v = X'03' WHERE a = 'y';
+             CREATE TABLE only_target(x);",
+        )
+        .await;
+
+        let (_s, source_conn) = open(&source).await;
+        let (_t, target_conn) = open(&target).await;
+        let report = detect(&source_conn, &target_conn, &[]).await.unwrap();
+        assert_eq!(report.skipped, vec![("only_target".to_string(), "only exists on the target".to_string())]);
+        let t = report.tables.iter().find(|d| d.table == "t").unwrap();
+        // A REAL equal to an INTEGER is the same value to diff, so 2.0 -> 2 is no drift,
+        // while values swapped between two rows are
+        let ops: Vec<(RowOp, &Value)> = t
+            .changes
+            .iter()
+            .map(|c| match c {
+                Change::Row(row) => (row.op, &row.key[0].1),
+                Change::Schema(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            ops,
+            vec![
+                (RowOp::Update, &Value::Integer(21)),
+                (RowOp::Update, &Value::Integer(220)),
+                (RowOp::Update, &Value::Integer(361)),
+                (RowOp::Update, &Value::Integer(1500)),
+                (RowOp::Insert, &Value::Integer(2999)),
+                (RowOp::Delete, &Value::Integer(5000)),
+            ]
+        );
+        assert!(t.rows_read < 1000, "only mismatching ranges are read: {}", t.rows_read);
+        assert!(report.tables.iter().find(|d| d.table == "k").unwrap().changes.is_empty());
+
+        apply(&target, &report.changes()).await;
+        assert_eq!(diff_databases(&source, &target).await.unwrap().len(), 1, "only the extra table is left");
+        let again = detect(&source_conn, &target_conn, &["T".to_string(), "m".to_string()]).await.unwrap();
+        assert_eq!(again.tables.len(), 2);
+        assert!(again.changes().is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use libsql::Connection;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use crate::change::{quote_ident, Change, RowOp};
+
+/// Tables smaller than this are exempt from the percentage guard, where a handful
+/// of rows would already be a large share
+const MIN_ROWS_FOR_PERCENT: u64 = 100;
+
+/// Limits a diff must stay within before anything is written
+#[derive(Args, Debug, Clone)]
+pub struct GuardOptions {
+    /// Most rows a single table may lose in one apply
+    #[arg(long, default_value = "1000", env = "TURSO_SYNC_MAX_DELETED_ROWS")]
+    pub max_deleted_rows: usize,
+
+    /// Largest share of a table's existing rows one apply may update or delete, in percent
+    #[arg(long, default_value = "50", env = "TURSO_SYNC_MAX_CHANGED_PERCENT")]
+    pub max_changed_percent: f64,
+
+    /// Statement kinds that are refused, comma separated (e.g. "DROP TABLE,DROP INDEX,DELETE")
+    #[arg(long, value_delimiter = ',', default_value = "DROP TABLE", env = "TURSO_SYNC_FORBID")]
+    pub forbid: Vec<String>,
+
+    /// Apply even when the diff exceeds the limits above
+    #[arg(long)]
+    pub force: bool,
+}
+
+impl Default for GuardOptions {
+    fn default() -> Self {
+        GuardOptions {
+            max_deleted_rows: 1000,
+            max_changed_percent: 50.0,
+            forbid: vec!["DROP TABLE".to_string()],
+            force: false,
+        }
+    }
+}
+
+/// A limit a diff exceeds
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    TooManyDeletes { table: String, deleted: usize, limit: usize },
+    TooMuchChanged { table: String, changed: usize, rows: u64, limit: f64 },
+    Forbidden { kind: String, statement: String },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::TooManyDeletes { table, deleted, limit } => {
+                write!(f, "{}: deletes {} rows, limit is {} (--max-deleted-rows)", table, deleted, limit)
+            }
+            Violation::TooMuchChanged { table, changed, rows, limit } => write!(
+                f,
+                "{}: updates or deletes {} of {} rows ({:.1}%), limit is {}% (--max-changed-percent)",
+                table,
+                changed,
+                rows,
+                percent(*changed, *rows),
+                limit
+            ),
+            Violation::Forbidden { kind, statement } => {
+                write!(f, "{} statements are forbidden (--forbid): {}", kind, preview(statement))
+            }
+        }
+    }
+}
+
+/// Tables whose existing rows `changes` update or delete
+pub fn touched_tables(changes: &[Change]) -> Vec<String> {
+    let mut tables: Vec<String> = changes
+        .iter()
+        .filter_map(|change| match change {
+            Change::Row(row) if row.op != RowOp::Insert => Some(row.table.clone()),
+            _ => None,
+        })
+        .collect();
+    tables.sort();
+    tables.dedup();
+    tables
+}
+
+/// Row count of each of `tables` in `conn`; tables that do not exist are left out
+pub async fn table_rows(conn: &Connection, tables: &[String]) -> Result<HashMap<String, u64>> {
+    let mut counts = HashMap::new();
+    for table in tables {
+        let Ok(mut rows) = conn.query(&format!("SELECT count(*) FROM {}", quote_ident(table)), ()).await else {
+            continue;
+        };
+        if let Some(row) = rows.next().await.with_context(|| format!("Failed to count rows of {}", table))? {
+            counts.insert(table.clone(), row.get::<i64>(0)? as u64);
+        }
+    }
+    Ok(counts)
+}
+
+/// Check `changes` against the limits, given the row counts of the tables they
+/// touch before the apply. Violations are listed per table, in table order.
+pub fn check(changes: &[Change], rows: &HashMap<String, u64>, options: &GuardOptions) -> Vec<Violation> {
+    let forbidden: Vec<String> = options.forbid.iter().map(|kind| normalize(kind)).filter(|k| !k.is_empty()).collect();
+    let mut violations = Vec::new();
+    // One violation per forbidden kind is enough when a diff holds thousands of them
+    let mut reported = HashSet::new();
+    // Deleted and updated rows per table
+    let mut counts: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+
+    for change in changes {
+        let statement = match change {
+            Change::Row(row) => {
+                let entry = counts.entry(row.table.as_str()).or_default();
+                match row.op {
+                    RowOp::Delete => entry.0 += 1,
+                    RowOp::Update => entry.1 += 1,
+                    RowOp::Insert => {}
+                }
+                change.to_sql()
+            }
+            Change::Schema(sql) => sql.clone(),
+        };
+        let normalized = normalize(&statement);
+        if let Some(kind) = forbidden.iter().find(|kind| starts_with_words(&normalized, kind)) {
+            if reported.insert(kind.clone()) {
+                violations.push(Violation::Forbidden { kind: kind.clone(), statement });
+            }
+        }
+    }
+
+    for (table, (deleted, updated)) in counts {
+        if deleted > options.max_deleted_rows {
+            violations.push(Violation::TooManyDeletes {
+                table: table.to_string(),
+                deleted,
+                limit: options.max_deleted_rows,
+            });
+        }
+        let existing = rows.get(table).copied().unwrap_or(0);
+        let changed = deleted + updated;
+        if existing >= MIN_ROWS_FOR_PERCENT && percent(changed, existing) > options.max_changed_percent {
+            violations.push(Violation::TooMuchChanged {
+                table: table.to_string(),
+                changed,
+                rows: existing,
+                limit: options.max_changed_percent,
+            });
+        }
+    }
+    violations
+}
+
+/// Violations one per line
+pub fn report(violations: &[Violation]) -> String {
+    violations.iter().map(|v| format!("  - {}", v)).collect::<Vec<_>>().join("\n")
+}
+
+fn percent(part: usize, whole: u64) -> f64 {
+    part as f64 * 100.0 / whole as f64
+}
+
+/// Uppercase with single spaces, so "drop  table" matches "DROP TABLE"
+fn normalize(sql: &str) -> String {
+    sql.split_whitespace().collect::<Vec<_>>().join(" ").to_uppercase()
+}
+
+fn starts_with_words(statement: &str, kind: &str) -> bool {
+    statement.starts_with(kind) && statement[kind.len()..].chars().next().is_none_or(|c| !c.is_alphanumeric())
+}
+
+fn preview(statement: &str) -> String {
+    match statement.char_indices().nth(80) {
+        Some((end, _)) => format!("{}...", &statement[..end]),
+        None => statement.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_mass_deletes_large_changes_and_forbidden_statements() {
+        let mut changes: Vec<Change> =
+            (1..=150).map(|id| Change::parse(&format!("DELETE FROM email_schedules WHERE id={}", id))).collect();
+        changes.extend((1..=30).map(|id| Change::parse(&format!("UPDATE contacts SET email='x' WHERE id={}", id))));
+        changes
+            .extend((1..=500).map(|id| Change::parse(&format!("INSERT INTO contacts(id,email) VALUES({},'y')", id))));
+        changes.push(Change::parse("drop  table old_contacts"));
+        changes.push(Change::parse("DROP TABLE older_contacts"));
+        changes.push(Change::parse("DROP TABLEX"));
+        let rows = HashMap::from([("email_schedules".to_string(), 160), ("contacts".to_string(), 1000)]);
+
+        let options = GuardOptions { max_deleted_rows: 100, ..GuardOptions::default() };
+        let violations = check(&changes, &rows, &options);
+        assert_eq!(
+            violations,
+            vec![
+                Violation::Forbidden {
+                    kind: "DROP TABLE".to_string(),
+                    statement: "drop  table old_contacts".to_string()
+                },
+                Violation::TooManyDeletes { table: "email_schedules".to_string(), deleted: 150, limit: 100 },
+                Violation::TooMuchChanged {
+                    table: "email_schedules".to_string(),
+                    changed: 150,
+                    rows: 160,
+                    limit: 50.0
+                },
+            ]
+        );
+        assert!(
+            report(&violations).contains("email_schedules: updates or deletes 150 of 160 rows (93.8%), limit is 50%")
+        );
+
+        // Inserts never count, and small tables are exempt from the percentage
+        let rows = HashMap::from([("email_schedules".to_string(), 99)]);
+        let options = GuardOptions { forbid: vec!["DELETE".to_string()], ..GuardOptions::default() };
+        let violations = check(&changes, &rows, &options);
+        assert_eq!(violations.len(), 1);
+        assert!(matches!(&violations[0], Violation::Forbidden { kind, .. } if kind == "DELETE"));
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Progress of an apply, persisted next to the diff file after every committed batch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Journal {
+    /// SHA-256 of the diff SQL this journal belongs to
+    pub diff_hash: String,
+    /// Identifies this apply in the remote progress table; a fresh start gets a new one
+    #[serde(default = "new_apply_id")]
+    pub apply_id: String,
+    pub total_statements: usize,
+    /// Leading statements of the execution order that are committed remotely
+    pub statements_applied: usize,
+    pub batches_committed: usize,
+    /// Rows uploaded to the staging table by a staged atomic apply
+    #[serde(default)]
+    pub stage_rows_uploaded: usize,
+    /// Positions in the diff that the conflict check of a push dropped; `None` until it ran
+    #[serde(default)]
+    pub skipped: Option<Vec<usize>>,
+    /// How many of the skipped changes conflicted with remote edits rather than being there already
+    #[serde(default)]
+    pub conflicts: usize,
+    /// Unix timestamp of the last update
+    pub updated_at: u64,
+}
+
+/// A journal bound to its file on disk
+pub struct JournalFile {
+    path: PathBuf,
+    journal: Journal,
+    /// Whether an existing journal was continued
+    resumed: bool,
+}
+
+/// Journal path for a diff file: `diff.sql` -> `diff.sql.journal`
+pub fn journal_path(diff_file: &str) -> PathBuf {
+    PathBuf::from(format!("{}.journal", diff_file))
+}
+
+/// Hex SHA-256 of a diff
+pub fn hash_diff(diff_sql: &str) -> String {
+    Sha256::digest(diff_sql.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl JournalFile {
+    /// Open the journal for a diff. With `resume`, an existing journal for the same
+    /// diff is continued; otherwise any previous progress is discarded.
+    pub fn open(diff_file: &str, diff_sql: &str, total_statements: usize, resume: bool) -> Result<Self> {
+        let path = journal_path(diff_file);
+        let diff_hash = hash_diff(diff_sql);
+
+        let existing = if path.exists() {
+            let content =
+                fs::read_to_string(&path).with_context(|| format!("Failed to read journal {}", path.display()))?;
+            Some(
+                serde_json::from_str::<Journal>(&content)
+                    .with_context(|| format!("Failed to parse journal {}", path.display()))?,
+            )
+        } else {
+            None
+        };
+
+        let resumed = resume && existing.is_some();
+        let journal = match existing {
+            Some(journal) if resume => {
+                if journal.diff_hash != diff_hash {
+                    return Err(anyhow::anyhow!(
+                        "Journal {} belongs to a different diff (hash {}, current {}); remove it or run without --resume",
+                        path.display(),
+                        journal.diff_hash,
+                        diff_hash
+                    ));
+                }
+                info!(
+                    "Resuming from journal: {}/{} statements already applied in {} batches",
+                    journal.statements_applied, journal.total_statements, journal.batches_committed
+                );
+                journal
+            }
+            existing => {
+                if resume {
+                    warn!("No journal found at {}, starting from the beginning", path.display());
+                } else if existing.is_some_and(|j| j.statements_applied > 0 || j.stage_rows_uploaded > 0) {
+                    warn!(
+                        "Discarding progress of an interrupted apply in {} (use --resume to continue it)",
+                        path.display()
+                    );
+                }
+                Journal {
+                    diff_hash,
+                    apply_id: new_apply_id(),
+                    total_statements,
+                    statements_applied: 0,
+                    batches_committed: 0,
+                    stage_rows_uploaded: 0,
+                    skipped: None,
+                    conflicts: 0,
+                    updated_at: now(),
+                }
+            }
+        };
+
+        let file = JournalFile { path, journal, resumed };
+        file.save()?;
+        Ok(file)
+    }
+
+    pub fn diff_hash(&self) -> &str {
+        &self.journal.diff_hash
+    }
+
+    pub fn apply_id(&self) -> &str {
+        &self.journal.apply_id
+    }
+
+    /// Whether this continues an interrupted apply, which may have committed more than it journaled
+    pub fn resumed(&self) -> bool {
+        self.resumed
+    }
+
+    pub fn statements_applied(&self) -> usize {
+        self.journal.statements_applied
+    }
+
+    pub fn stage_rows_uploaded(&self) -> usize {
+        self.journal.stage_rows_uploaded
+    }
+
+    /// Positions the conflict check dropped from the diff, if it ran for this journal
+    pub fn skipped(&self) -> Option<&[usize]> {
+        self.journal.skipped.as_deref()
+    }
+
+    /// How many of the [`JournalFile::skipped`] changes conflicted with remote edits
+    pub fn conflicts(&self) -> usize {
+        self.journal.conflicts
+    }
+
+    /// Record the positions the conflict check dropped from the diff, `conflicts` of
+    /// them for conflicting with remote edits
+    pub fn record_skipped(&mut self, skipped: Vec<usize>, conflicts: usize) -> Result<()> {
+        self.journal.skipped = Some(skipped);
+        self.journal.conflicts = conflicts;
+        self.save()
+    }
+
+    /// Statements the apply executes, once known; persisted with the next update
+    pub fn set_total_statements(&mut self, total: usize) {
+        self.journal.total_statements = total;
+    }
+
+    /// Record a committed batch of `statements` statements
+    pub fn record_batch(&mut self, statements: usize) -> Result<()> {
+        self.journal.statements_applied += statements;
+        self.journal.batches_committed += 1;
+        self.save()
+    }
+
+    /// Record a committed upload of `rows` staging rows
+    pub fn record_stage_rows(&mut self, rows: usize) -> Result<()> {
+        self.journal.stage_rows_uploaded += rows;
+        self.journal.batches_committed += 1;
+        self.save()
+    }
+
+    /// The apply completed; the journal is no longer needed
+    pub fn finish(self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path).with_context(|| format!("Failed to remove journal {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Write via a temporary file so a crash never leaves a torn journal
+    fn save(&self) -> Result<()> {
+        let mut journal = self.journal.clone();
+        journal.updated_at = now();
+        let tmp = self.path.with_extension("journal.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(&journal)?)
+            .with_context(|| format!("Failed to write journal {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path).with_context(|| format!("Failed to update journal {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Random hex id for a new apply
+fn new_apply_id() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default());
+    format!("{:016x}", hasher.finish())
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_dir;
+
+    #[test]
+    fn progress_survives_reopening() {
+        let dir = temp_dir();
+        let diff_file = dir.path("diff.sql");
+        let mut journal = JournalFile::open(&diff_file, "DELETE FROM t WHERE id=1;", 10, false).unwrap();
+        assert_eq!(journal.statements_applied(), 0);
+        journal.record_batch(3).unwrap();
+        journal.record_batch(2).unwrap();
+        journal.record_stage_rows(40).unwrap();
+
+        let resumed = JournalFile::open(&diff_file, "DELETE FROM t WHERE id=1;", 10, true).unwrap();
+        assert_eq!(resumed.statements_applied(), 5);
+        assert_eq!(resumed.stage_rows_uploaded(), 40);
+        assert_eq!(resumed.journal.batches_committed, 3);
+        assert_eq!(resumed.diff_hash(), hash_diff("DELETE FROM t WHERE id=1;"));
+        assert_eq!(resumed.apply_id(), journal.apply_id());
+        assert!(resumed.resumed() && !journal.resumed());
+
+        resumed.finish().unwrap();
+        assert!(!journal_path(&diff_file).exists());
+    }
+
+    #[test]
+    fn journal_of_another_diff_is_rejected_on_resume() {
+        let dir = temp_dir();
+        let diff_file = dir.path("diff.sql");
+        let mut journal = JournalFile::open(&diff_file, "DELETE FROM t WHERE id=1;", 1, false).unwrap();
+        journal.record_batch(1).unwrap();
+        assert_ne!(hash_diff("DELETE FROM t WHERE id=1;"), hash_diff("DELETE FROM t WHERE id=2;"));
+
+        let error = JournalFile::open(&diff_file, "DELETE FROM t WHERE id=2;", 1, true).err().unwrap();
+        assert!(error.to_string().contains("belongs to a different diff"));
+        // Without --resume the stale progress is discarded instead
+        let fresh = JournalFile::open(&diff_file, "DELETE FROM t WHERE id=2;", 1, false).unwrap();
+        assert_eq!(fresh.statements_applied(), 0);
+        assert_ne!(fresh.apply_id(), journal.apply_id());
+    }
+}
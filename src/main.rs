@@ -1,12 +1,56 @@
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use libsql::Builder;
-use log::{info, warn, error, debug};
+use clap::{Args, Parser, Subcommand};
+use libsql::{Builder, OpenFlags};
+use log::{debug, error, info, warn};
+use std::collections::{BTreeSet, HashSet};
 use std::env;
 use std::fs;
 use std::path::Path;
-use std::process::Command;
-use std::time::Duration;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+
+use apply::{ApplyMode, ApplyOptions};
+use change::Change;
+use changeset::DiffFormat;
+use conflict::{ConflictCheck, ConflictPolicy, RowSnapshot};
+use drift::{DriftOptions, DriftSource};
+use guard::{GuardOptions, Violation};
+use merge::MergePolicy;
+use offline::{SyncDirection, SyncPosition};
+use protect::{ProtectAction, ProtectOptions};
+use retry::{with_retry, RetryOptions};
+use shutdown::ShutdownOptions;
+use snapshot::{SnapshotState, UnpushedAction};
+use sync_state::SyncState;
+use watch::Watcher;
+
+mod apply;
+mod batching;
+mod change;
+mod changeset;
+mod child;
+mod coalesce;
+mod conflict;
+mod diff;
+mod drift;
+mod guard;
+mod journal;
+mod merge;
+mod offline;
+mod prepared;
+mod protect;
+mod retry;
+mod rollback;
+mod shutdown;
+mod snapshot;
+mod sql;
+mod sync_state;
+#[cfg(test)]
+mod testutil;
+mod track;
+mod verify;
+mod watch;
 
 #[derive(Parser)]
 #[command(name = "turso-sync")]
@@ -14,6 +58,12 @@ use std::time::Duration;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    #[command(flatten)]
+    retry: RetryOptions,
+
+    #[command(flatten)]
+    shutdown: ShutdownOptions,
 }
 
 #[derive(Subcommand)]
@@ -23,113 +73,328 @@ enum Commands {
         /// Path to local replica database
         #[arg(short, long, default_value = "local_replica.db")]
         replica_path: String,
-        
+
         /// Turso database URL
         #[arg(short, long)]
         url: Option<String>,
-        
+
         /// Turso auth token
         #[arg(short, long)]
         token: Option<String>,
     },
-    
+
     /// Copy replica to working copy
     Copy {
         /// Path to source database
         #[arg(short, long, default_value = "local_replica.db")]
         source: String,
-        
+
         /// Path to destination database
         #[arg(short, long, default_value = "working_copy.db")]
         dest: String,
+
+        /// What to do with changes in the destination that were never pushed
+        #[arg(long, value_enum, default_value = "abort")]
+        on_unpushed: UnpushedAction,
+
+        /// Overwrite the destination even if it has changes that were never pushed; same as `--on-unpushed discard`
+        #[arg(long, conflicts_with = "on_unpushed")]
+        force: bool,
+
+        /// Turso database URL, for `--on-unpushed push`
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Turso auth token, for `--on-unpushed push`
+        #[arg(long)]
+        token: Option<String>,
     },
-    
+
+    /// Generate diff between replica and working copy without applying it
+    Diff {
+        /// Path to local replica database
+        #[arg(short, long, default_value = "local_replica.db")]
+        replica_path: String,
+
+        /// Path to working copy database
+        #[arg(short, long, default_value = "working_copy.db")]
+        working_path: String,
+
+        /// Path to store the diff SQL file
+        #[arg(long, default_value = "diff.sql")]
+        diff_file: String,
+
+        /// Format of the diff file
+        #[arg(long, value_enum, default_value = "sql")]
+        format: DiffFormat,
+    },
+
     /// Generate diff and apply to Turso
     Push {
         /// Path to local replica database
         #[arg(short, long, default_value = "local_replica.db")]
         replica_path: String,
-        
+
         /// Path to working copy database
         #[arg(short, long, default_value = "working_copy.db")]
         working_path: String,
-        
+
         /// Turso database URL
         #[arg(long)]
         url: Option<String>,
-        
+
         /// Turso auth token
         #[arg(long)]
         token: Option<String>,
-        
+
         /// Path to store the diff SQL file
         #[arg(long, default_value = "diff.sql")]
         diff_file: String,
+
+        #[command(flatten)]
+        push: PushOptions,
+
+        #[command(flatten)]
+        apply: ApplyOptions,
+    },
+
+    /// Undo the last push by applying the inverse changeset saved next to its diff
+    Rollback {
+        /// Path to local replica database
+        #[arg(short, long, default_value = "local_replica.db")]
+        replica_path: String,
+
+        /// Turso database URL
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Turso auth token
+        #[arg(long)]
+        token: Option<String>,
+
+        /// Diff file of the push to undo; its inverse is read from <diff-file>.inverse
+        #[arg(long, default_value = "diff.sql")]
+        diff_file: String,
+
+        /// Largest rollback applied in a single remote transaction; larger ones are staged first
+        #[arg(long, default_value = "5000", env = "TURSO_SYNC_MAX_TRANSACTION_STATEMENTS")]
+        max_transaction_statements: usize,
+
+        /// Continue an interrupted rollback from its journal instead of starting over
+        #[arg(long)]
+        resume: bool,
+    },
+
+    /// Compare per-table row counts and content hashes of the working copy and Turso
+    Verify {
+        /// Path to working copy database
+        #[arg(short, long, default_value = "working_copy.db")]
+        working_path: String,
+
+        /// Turso database URL
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Turso auth token
+        #[arg(long)]
+        token: Option<String>,
+    },
+
+    /// Find rows that differ between a local database and Turso by hashing key ranges
+    Drift {
+        /// Local database compared with Turso
+        #[arg(short, long, default_value = "working_copy.db")]
+        working_path: String,
+
+        /// Turso database URL
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Turso auth token
+        #[arg(long)]
+        token: Option<String>,
+
+        /// Path to store the repair SQL; an interrupted repair is journaled next to it
+        #[arg(long, default_value = "drift.sql")]
+        diff_file: String,
+
+        #[command(flatten)]
+        drift: DriftOptions,
+
+        #[command(flatten)]
+        apply: ApplyOptions,
+    },
+
+    /// Install triggers in the working copy that log row changes for incremental pushes
+    Track {
+        /// Path to working copy database
+        #[arg(short, long, default_value = "working_copy.db")]
+        working_path: String,
+
+        /// Tables to track, comma separated (default: every table)
+        #[arg(long, value_delimiter = ',')]
+        tables: Vec<String>,
+
+        /// Remove the triggers and the change log instead
+        #[arg(long)]
+        remove: bool,
+    },
+
+    /// Three-way merge of the working copy and a freshly synced remote, based on the replica
+    Merge {
+        /// Path to the local replica both sides started from
+        #[arg(short, long, default_value = "local_replica.db")]
+        replica_path: String,
+
+        /// Path to working copy database ("ours")
+        #[arg(short, long, default_value = "working_copy.db")]
+        working_path: String,
+
+        /// Path to a replica synced fresh from Turso ("theirs")
+        #[arg(long, default_value = "remote_snapshot.db")]
+        remote_path: String,
+
+        /// Turso database URL
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Turso auth token
+        #[arg(long)]
+        token: Option<String>,
+
+        /// Use the remote snapshot as it is instead of syncing it first
+        #[arg(long)]
+        no_sync: bool,
+
+        /// How to resolve rows changed incompatibly on both sides
+        #[arg(long, value_enum, default_value = "fail")]
+        policy: MergePolicy,
+
+        /// Column compared by the 'newest' policy
+        #[arg(long, default_value = "updated_at")]
+        timestamp_column: String,
+
+        /// Path to store the merged diff, to be applied to the remote snapshot
+        #[arg(long, default_value = "merge.sql")]
+        diff_file: String,
     },
-    
+
     /// Apply diff file to synced database and sync to remote (uses offline sync)
     ApplyDiff {
         /// Path to local synced database
         #[arg(short, long, default_value = "local_replica.db")]
         db_path: String,
-        
+
         /// Path to diff SQL file to apply
-        #[arg(short, long, default_value = "diff.sql")]
+        #[arg(long, default_value = "diff.sql")]
         diff_file: String,
-        
+
         /// Turso database URL for sync
         #[arg(short, long)]
         sync_url: Option<String>,
-        
+
         /// Turso auth token
         #[arg(short, long)]
         token: Option<String>,
-        
+
         /// Skip sync after applying diff
         #[arg(long)]
         no_sync: bool,
+
+        /// What to do with rows of a changeset whose current values differ from the old values it recorded
+        #[arg(long, value_enum, default_value = "abort")]
+        on_conflict: ConflictPolicy,
+
+        #[command(flatten)]
+        apply: ApplyOptions,
     },
-    
+
     /// Initialize and sync a database using offline sync capabilities
     OfflineSync {
         /// Path to local database
         #[arg(short, long, default_value = "working_copy.db")]
         db_path: String,
-        
+
         /// Turso database URL for sync
         #[arg(short, long)]
         sync_url: Option<String>,
-        
+
         /// Turso auth token
         #[arg(short, long)]
         token: Option<String>,
-        
+
         /// Direction: 'pull' from remote, 'push' to remote, or 'both' (default)
-        #[arg(long, default_value = "both")]
-        direction: String,
+        #[arg(long, value_enum, default_value = "both")]
+        direction: SyncDirection,
+
+        /// Print what would be pushed and pulled without syncing
+        #[arg(long)]
+        dry_run: bool,
     },
-    
+
     /// Full workflow: sync -> copy -> wait for changes -> push
     Workflow {
         /// Path to local replica database
         #[arg(short, long, default_value = "local_replica.db")]
         replica_path: String,
-        
+
+        /// Path to working copy database
+        #[arg(short, long, default_value = "working_copy.db")]
+        working_path: String,
+
+        /// Turso database URL
+        #[arg(long)]
+        url: Option<String>,
+
+        /// Turso auth token
+        #[arg(long)]
+        token: Option<String>,
+
+        #[command(flatten)]
+        workflow: WorkflowOptions,
+
+        #[command(flatten)]
+        push: PushOptions,
+
+        #[command(flatten)]
+        apply: ApplyOptions,
+    },
+
+    /// Sync, copy the replica to the working copy, run a command on it and push what it changed
+    Run {
+        /// Path to local replica database
+        #[arg(short, long, default_value = "local_replica.db")]
+        replica_path: String,
+
         /// Path to working copy database
         #[arg(short, long, default_value = "working_copy.db")]
         working_path: String,
-        
+
         /// Turso database URL
         #[arg(long)]
         url: Option<String>,
-        
+
         /// Turso auth token
         #[arg(long)]
         token: Option<String>,
-        
-        /// Sync interval in seconds
-        #[arg(long, default_value = "300")]
-        sync_interval: u64,
+
+        /// Path to store the diff SQL file
+        #[arg(long, default_value = "diff.sql")]
+        diff_file: String,
+
+        /// What to do before the copy with changes in the working copy that were never pushed
+        #[arg(long, value_enum, default_value = "abort")]
+        on_unpushed: UnpushedAction,
+
+        #[command(flatten)]
+        push: PushOptions,
+
+        #[command(flatten)]
+        apply: ApplyOptions,
+
+        /// Command to run, with its arguments, after `--`
+        #[arg(last = true, required = true, value_name = "COMMAND")]
+        command: Vec<String>,
     },
 
     /// Bidirectional sync with Turso using libSQL sync (pulls and pushes changes)
@@ -148,42 +413,179 @@ enum Commands {
     },
 }
 
+/// Options controlling which changes push sends
+#[derive(Args, Debug, Clone)]
+struct PushOptions {
+    /// What to do when rows in the diff were also changed remotely since the last sync
+    #[arg(long, value_enum, default_value = "abort")]
+    on_conflict: ConflictPolicy,
+
+    /// Diff the whole working copy even when `track` is capturing its changes
+    #[arg(long)]
+    full_diff: bool,
+
+    /// Format of the diff file; changesets and patchsets always diff the whole working copy
+    #[arg(long, value_enum, default_value = "sql")]
+    format: DiffFormat,
+
+    /// Compare per-table checksums of the working copy and Turso after pushing
+    #[arg(long)]
+    verify: bool,
+}
+
+impl Default for PushOptions {
+    fn default() -> Self {
+        PushOptions { on_conflict: ConflictPolicy::Abort, full_diff: false, format: DiffFormat::Sql, verify: false }
+    }
+}
+
+/// Options of the long-running `workflow`
+#[derive(Args, Debug, Clone)]
+struct WorkflowOptions {
+    /// Sync interval in seconds
+    #[arg(long, default_value = "300")]
+    sync_interval: u64,
+
+    /// What to do at startup with changes in the working copy that were never pushed
+    #[arg(long, value_enum, default_value = "abort")]
+    on_unpushed: UnpushedAction,
+
+    /// Path to store the diff SQL file of pushes
+    #[arg(long, default_value = "diff.sql")]
+    diff_file: String,
+
+    /// Push the working copy automatically once it changed and writes to it have stopped
+    #[arg(long)]
+    watch: bool,
+
+    /// How often the working copy is checked for changes, in milliseconds
+    #[arg(long, default_value = "1000")]
+    watch_interval_ms: u64,
+
+    /// How long writes to the working copy must have stopped before it is pushed, in milliseconds
+    #[arg(long, default_value = "5000")]
+    debounce_ms: u64,
+}
+
+/// The Turso database a push goes to, and how
+struct PushTarget<'a> {
+    url: &'a str,
+    token: &'a str,
+    diff_file: &'a str,
+    push: &'a PushOptions,
+    apply: &'a ApplyOptions,
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> Result<ExitCode> {
     // Load .env file if it exists (ignore errors if file doesn't exist)
     let _ = dotenv::dotenv();
-    
+
     env_logger::init();
     let cli = Cli::parse();
+    retry::configure(&cli.retry);
+    shutdown::install(&cli.shutdown);
+
+    let result = run(cli.command).await;
+    retry::log_summary();
+
+    // Work stopped by a signal exits with 128 + the signal, whatever state it stopped in
+    if let Some(signal) = shutdown::signal() {
+        if let Err(e) = result {
+            warn!("{:#}", e);
+        }
+        info!("Shut down after {}", shutdown::signal_name(signal));
+        return Ok(ExitCode::from(shutdown::exit_code(signal)));
+    }
+    result
+}
 
-    match cli.command {
+async fn run(command: Commands) -> Result<ExitCode> {
+    match command {
         Commands::Sync { replica_path, url, token } => {
             let url = get_env_or_arg(url, "TURSO_DATABASE_URL")?;
             let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
             sync_from_turso(&replica_path, &url, &token).await?;
         }
-        Commands::Copy { source, dest } => {
-            copy_database(&source, &dest)?;
+        Commands::Copy { source, dest, on_unpushed, force, url, token } => {
+            let action = if force { UnpushedAction::Discard } else { on_unpushed };
+            if action == UnpushedAction::Push {
+                let url = get_env_or_arg(url, "TURSO_DATABASE_URL")?;
+                let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
+                let (push, apply) = (PushOptions::default(), ApplyOptions::default());
+                let target = PushTarget { url: &url, token: &token, diff_file: "diff.sql", push: &push, apply: &apply };
+                copy_database(&source, &dest, action, Some(&target)).await?;
+            } else {
+                copy_database(&source, &dest, action, None).await?;
+            }
+        }
+        Commands::Diff { replica_path, working_path, diff_file, format } => {
+            write_diff(&replica_path, &working_path, &diff_file, format).await?;
+        }
+        Commands::Push { replica_path, working_path, url, token, diff_file, push, apply } => {
+            let url = get_env_or_arg(url, "TURSO_DATABASE_URL")?;
+            let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
+            push_to_turso(&replica_path, &working_path, &url, &token, &diff_file, &push, &apply).await?;
+        }
+        Commands::Rollback { replica_path, url, token, diff_file, max_transaction_statements, resume } => {
+            let url = get_env_or_arg(url, "TURSO_DATABASE_URL")?;
+            let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
+            rollback_push(&replica_path, &url, &token, &diff_file, max_transaction_statements, resume).await?;
+        }
+        Commands::Verify { working_path, url, token } => {
+            let url = get_env_or_arg(url, "TURSO_DATABASE_URL")?;
+            let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
+            verify_working_copy(&working_path, &url, &token).await?;
         }
-        Commands::Push { replica_path, working_path, url, token, diff_file } => {
+        Commands::Drift { working_path, url, token, diff_file, drift, apply } => {
             let url = get_env_or_arg(url, "TURSO_DATABASE_URL")?;
             let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
-            push_to_turso(&replica_path, &working_path, &url, &token, &diff_file).await?;
+            check_drift(&working_path, &url, &token, &diff_file, &drift, &apply).await?;
+        }
+        Commands::Track { working_path, tables, remove } => {
+            track_changes(&working_path, &tables, remove).await?;
+        }
+        Commands::Merge {
+            replica_path,
+            working_path,
+            remote_path,
+            url,
+            token,
+            no_sync,
+            policy,
+            timestamp_column,
+            diff_file,
+        } => {
+            if !no_sync {
+                let url = get_env_or_arg(url, "TURSO_DATABASE_URL")?;
+                let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
+                sync_from_turso(&remote_path, &url, &token).await?;
+            }
+            merge_with_remote(&replica_path, &working_path, &remote_path, policy, &timestamp_column, &diff_file)
+                .await?;
         }
-        Commands::ApplyDiff { db_path, diff_file, sync_url, token, no_sync } => {
+        Commands::ApplyDiff { db_path, diff_file, sync_url, token, no_sync, on_conflict, apply } => {
             let url = get_env_or_arg(sync_url, "TURSO_DATABASE_URL")?;
             let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
-            apply_diff_to_turso(&db_path, &diff_file, &url, &token, no_sync).await?;
+            apply_diff_to_turso(&db_path, &diff_file, &url, &token, no_sync, on_conflict, &apply).await?;
         }
-        Commands::OfflineSync { db_path, sync_url, token, direction } => {
+        Commands::OfflineSync { db_path, sync_url, token, direction, dry_run } => {
             let url = get_env_or_arg(sync_url, "TURSO_DATABASE_URL")?;
             let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
-            offline_sync(&db_path, &url, &token, &direction).await?;
+            offline_sync(&db_path, &url, &token, direction, dry_run).await?;
         }
-        Commands::Workflow { replica_path, working_path, url, token, sync_interval } => {
+        Commands::Workflow { replica_path, working_path, url, token, workflow, push, apply } => {
             let url = get_env_or_arg(url, "TURSO_DATABASE_URL")?;
             let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
-            run_workflow(&replica_path, &working_path, &url, &token, sync_interval).await?;
+            let target =
+                PushTarget { url: &url, token: &token, diff_file: &workflow.diff_file, push: &push, apply: &apply };
+            run_workflow(&replica_path, &working_path, &workflow, &target).await?;
+        }
+        Commands::Run { replica_path, working_path, url, token, diff_file, on_unpushed, push, apply, command } => {
+            let url = get_env_or_arg(url, "TURSO_DATABASE_URL")?;
+            let token = get_env_or_arg(token, "TURSO_AUTH_TOKEN")?;
+            let target = PushTarget { url: &url, token: &token, diff_file: &diff_file, push: &push, apply: &apply };
+            return run_and_push(&replica_path, &working_path, on_unpushed, &command, &target).await;
         }
         Commands::LibsqlSync { db_path, sync_url, token } => {
             let url = get_env_or_arg(sync_url, "TURSO_DATABASE_URL")?;
@@ -192,7 +594,7 @@ async fn main() -> Result<()> {
         }
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
 /// Helper function to get value from argument or environment variable
@@ -211,184 +613,633 @@ fn get_env_or_arg(arg: Option<String>, env_var: &str) -> Result<String> {
     }
 }
 
-/// Helper function to make CREATE statements idempotent
-fn make_create_statement_idempotent(statement: &str) -> String {
-    let trimmed = statement.trim();
-    if trimmed.starts_with("CREATE INDEX") && !trimmed.contains("IF NOT EXISTS") {
-        trimmed.replace("CREATE INDEX", "CREATE INDEX IF NOT EXISTS")
-    } else if trimmed.starts_with("CREATE TABLE") && !trimmed.contains("IF NOT EXISTS") {
-        trimmed.replace("CREATE TABLE", "CREATE TABLE IF NOT EXISTS")
-    } else if trimmed.starts_with("CREATE UNIQUE INDEX") && !trimmed.contains("IF NOT EXISTS") {
-        trimmed.replace("CREATE UNIQUE INDEX", "CREATE UNIQUE INDEX IF NOT EXISTS")
-    } else if trimmed.starts_with("CREATE VIEW") && !trimmed.contains("IF NOT EXISTS") {
-        trimmed.replace("CREATE VIEW", "CREATE VIEW IF NOT EXISTS")
-    } else if trimmed.starts_with("CREATE TRIGGER") && !trimmed.contains("IF NOT EXISTS") {
-        trimmed.replace("CREATE TRIGGER", "CREATE TRIGGER IF NOT EXISTS")
-    } else {
-        statement.to_string()
-    }
-}
-
 /// Sync from Turso to local replica using embedded replica
 async fn sync_from_turso(replica_path: &str, url: &str, token: &str) -> Result<()> {
     info!("Syncing from Turso to local replica: {}", replica_path);
-    
-    let db = Builder::new_remote_replica(replica_path, url.to_string(), token.to_string())
-        .build()
-        .await
-        .context("Failed to create remote replica")?;
-    
+
+    let db = with_retry("Opening remote replica", || async {
+        Builder::new_remote_replica(replica_path, url.to_string(), token.to_string())
+            .build()
+            .await
+            .context("Failed to create remote replica")
+    })
+    .await?;
+
     // Perform initial sync
-    db.sync().await.context("Failed to sync database")?;
-    
-    info!("Successfully synced from Turso to {}", replica_path);
+    let replicated = with_retry("Sync", || async { db.sync().await.context("Failed to sync database") }).await?;
+
+    // Remember how far the replica got so a later push can tell what it is based on
+    let state = SyncState::from_replicated(&replicated);
+    state.save(replica_path)?;
+
+    info!(
+        "Successfully synced from Turso to {} ({} frames pulled, {})",
+        replica_path,
+        state.frames_synced,
+        state.describe()
+    );
     Ok(())
 }
 
-/// Copy database file
-fn copy_database(source: &str, dest: &str) -> Result<()> {
-    info!("Copying database from {} to {}", source, dest);
-    
+/// Connect to the Turso database, retrying transient failures. Remote connections
+/// are opened lazily, so a first round trip makes sure Turso is reachable.
+async fn connect_turso(url: &str, token: &str) -> Result<libsql::Connection> {
+    with_retry("Connecting to Turso", || async {
+        let db = Builder::new_remote(url.to_string(), token.to_string())
+            .build()
+            .await
+            .context("Failed to connect to Turso")?;
+        let conn = db.connect().context("Failed to get connection")?;
+        conn.query("SELECT 1", ()).await.context("Failed to connect to Turso")?;
+        Ok(conn)
+    })
+    .await
+}
+
+/// Replace `dest` with a consistent snapshot of `source`, first dealing with
+/// changes in `dest` that were never pushed as `action` says
+async fn copy_database(
+    source: &str,
+    dest: &str,
+    action: UnpushedAction,
+    target: Option<&PushTarget<'_>>,
+) -> Result<()> {
     if !Path::new(source).exists() {
         return Err(anyhow::anyhow!("Source database {} does not exist", source));
     }
-    
-    fs::copy(source, dest)
-        .context("Failed to copy database file")?;
-    
+
+    resolve_unpushed(source, dest, action, target).await?;
+    replace_working_copy(source, dest).await
+}
+
+/// Copy `source` over `dest` without checking `dest` for unpushed changes. Tables
+/// whose changes were captured in `dest` are tracked again in the copy.
+async fn replace_working_copy(source: &str, dest: &str) -> Result<()> {
+    info!("Copying database from {} to {}", source, dest);
+
+    let tracked = if Path::new(dest).exists() {
+        let db = Builder::new_local(dest).build().await.with_context(|| format!("Failed to open {}", dest))?;
+        track::tracked_tables(&db.connect()?).await?
+    } else {
+        Vec::new()
+    };
+
+    snapshot::copy(source, dest).await?;
+
+    if !tracked.is_empty() {
+        let db = Builder::new_local(dest).build().await.with_context(|| format!("Failed to open {}", dest))?;
+        track::install(&db.connect()?, &tracked)
+            .await
+            .with_context(|| format!("Failed to track {} again in the copy", tracked.join(", ")))?;
+        info!("Tracking {} again in {}", tracked.join(", "), dest);
+    }
+
     info!("Successfully copied database to {}", dest);
     Ok(())
 }
 
-/// Generate diff using sqldiff and apply to Turso
-async fn push_to_turso(
+/// Make it safe to replace `working_path` with a copy of `replica_path`: changes
+/// in the working copy that were never pushed are stashed, pushed or discarded,
+/// or the copy is refused, depending on `action`. Pushing needs `target`.
+async fn resolve_unpushed(
     replica_path: &str,
     working_path: &str,
-    url: &str,
-    token: &str,
-    diff_file: &str,
+    action: UnpushedAction,
+    target: Option<&PushTarget<'_>>,
 ) -> Result<()> {
-    info!("Generating diff and pushing to Turso");
-    
-    // Check if both databases exist
-    if !Path::new(replica_path).exists() {
-        return Err(anyhow::anyhow!("Local replica {} does not exist", replica_path));
-    }
-    
-    if !Path::new(working_path).exists() {
-        return Err(anyhow::anyhow!("Working copy {} does not exist", working_path));
-    }
-    
-    // Generate diff using sqldiff
-    info!("Generating diff using sqldiff");
-    let output = Command::new("sqldiff")
-        .arg("--transaction")
-        .arg(replica_path)
-        .arg(working_path)
-        .output()
-        .context("Failed to run sqldiff - make sure it's installed and in PATH")?;
-    
-    if !output.status.success() {
-        error!("sqldiff failed: {}", String::from_utf8_lossy(&output.stderr));
-        return Err(anyhow::anyhow!("sqldiff command failed"));
-    }
-    
-    let diff_sql = String::from_utf8(output.stdout)
-        .context("Failed to parse sqldiff output as UTF-8")?;
-    
-    if diff_sql.trim().is_empty() {
-        info!("No changes detected - databases are identical");
+    if !snapshot::has_unpushed_changes(replica_path, working_path).await? {
         return Ok(());
     }
-    
-    // Save diff to file for debugging
-    fs::write(diff_file, &diff_sql)
-        .context("Failed to write diff file")?;
-    
-    info!("Generated diff SQL ({} bytes), saved to {}", diff_sql.len(), diff_file);
-    debug!("Diff SQL:\n{}", diff_sql);
-    
-    // Apply diff to Turso with batching for large diffs
-    info!("Applying changes to Turso");
-    let db = Builder::new_remote(url.to_string(), token.to_string())
-        .build()
-        .await
-        .context("Failed to connect to Turso")?;
-    
-    let conn = db.connect().context("Failed to get connection")?;
-    
-    // Check if we need to batch the operations
-    let statements: Vec<&str> = diff_sql.split(';').collect();
-    let non_empty_statements: Vec<&str> = statements
-        .iter()
-        .map(|s| s.trim())
-        .filter(|s| !s.is_empty() && *s != "BEGIN TRANSACTION" && *s != "COMMIT")
-        .collect();
-    
-    if non_empty_statements.len() > 1000 {
-        info!("Large diff detected ({} statements), processing in batches", non_empty_statements.len());
-        
-        // Process CREATE statements first (indexes, tables, etc.)
-        let create_statements: Vec<&str> = non_empty_statements
-            .iter()
-            .filter(|s| s.starts_with("CREATE"))
-            .copied()
-            .collect();
-        
-        if !create_statements.is_empty() {
-            info!("Applying {} CREATE statements", create_statements.len());
-            
-            // Modify CREATE statements to be idempotent
-            let safe_create_statements: Vec<String> = create_statements
-                .iter()
-                .map(|s| make_create_statement_idempotent(s))
-                .collect();
-            
-            let create_batch = safe_create_statements.join(";\n") + ";";
-            conn.execute_batch(&create_batch)
-                .await
-                .context("Failed to execute CREATE statements")?;
-        }
-        
-        // Process INSERT/UPDATE/DELETE statements in batches
-        let data_statements: Vec<&str> = non_empty_statements
-            .iter()
-            .filter(|s| !s.starts_with("CREATE"))
-            .copied()
-            .collect();
-        
-        if !data_statements.is_empty() {
-            let batch_size = 500; // Adjust batch size as needed
-            let total_batches = (data_statements.len() + batch_size - 1) / batch_size;
-            
-            info!("Processing {} data statements in {} batches of {}", 
-                  data_statements.len(), total_batches, batch_size);
-            
-            for (batch_num, batch) in data_statements.chunks(batch_size).enumerate() {
-                info!("Processing batch {}/{} ({} statements)", 
-                      batch_num + 1, total_batches, batch.len());
-                
-                let batch_sql = batch.join(";\n") + ";";
-                conn.execute_batch(&batch_sql)
-                    .await
-                    .with_context(|| format!("Failed to execute batch {}/{}", batch_num + 1, total_batches))?;
-                
-                // Small delay between batches to avoid overwhelming the server
-                tokio::time::sleep(Duration::from_millis(100)).await;
-            }
+    match action {
+        UnpushedAction::Abort => Err(anyhow::anyhow!(
+            "{} has changes that were never pushed: it was written to since it was last copied or pushed, \
+             and differs from {}. Push them first, or use --on-unpushed to stash, push or discard them",
+            working_path,
+            replica_path
+        )),
+        UnpushedAction::Stash => {
+            let (path, count) = snapshot::stash(replica_path, working_path).await?;
+            warn!("Stashed {} unpushed changes from {} in {}", count, working_path, path);
+            Ok(())
         }
-    } else {
-        // Small diff, execute as single batch
-        conn.execute_batch(&diff_sql)
+        UnpushedAction::Push => {
+            let target = target.context("Pushing unpushed changes needs the Turso URL and token")?;
+            info!("Pushing the unpushed changes in {} first", working_path);
+            push_to_turso(
+                replica_path,
+                working_path,
+                target.url,
+                target.token,
+                target.diff_file,
+                target.push,
+                target.apply,
+            )
             .await
-            .context("Failed to execute diff SQL on Turso")?;
+        }
+        UnpushedAction::Discard => {
+            warn!("Discarding changes in {} that were never pushed", working_path);
+            Ok(())
+        }
     }
-    
-    info!("Successfully applied changes to Turso");
-    
+}
+
+/// Generate diff between replica and working copy and write it to a file
+async fn write_diff(replica_path: &str, working_path: &str, diff_file: &str, format: DiffFormat) -> Result<()> {
+    info!("Generating diff between {} and {}", replica_path, working_path);
+
+    if format != DiffFormat::Sql {
+        let data = changeset::create(replica_path, working_path, format)?;
+        fs::write(diff_file, &data).context("Failed to write diff file")?;
+        info!(
+            "Generated {:?} with {} changes ({} bytes), saved to {}",
+            format,
+            changeset::decode(&data)?.len(),
+            data.len(),
+            diff_file
+        );
+        return Ok(());
+    }
+
+    let diff_sql = diff::render_sql(&diff::diff_databases(replica_path, working_path).await?);
+
+    fs::write(diff_file, &diff_sql).context("Failed to write diff file")?;
+
+    if diff_sql.is_empty() {
+        info!("No changes detected - databases are identical");
+    } else {
+        info!("Generated diff SQL ({} bytes), saved to {}", diff_sql.len(), diff_file);
+    }
+    Ok(())
+}
+
+/// Generate diff and apply to Turso
+async fn push_to_turso(
+    replica_path: &str,
+    working_path: &str,
+    url: &str,
+    token: &str,
+    diff_file: &str,
+    push: &PushOptions,
+    options: &ApplyOptions,
+) -> Result<()> {
+    info!("Generating diff and pushing to Turso");
+
+    // Check if both databases exist
+    if !Path::new(replica_path).exists() {
+        return Err(anyhow::anyhow!("Local replica {} does not exist", replica_path));
+    }
+
+    if !Path::new(working_path).exists() {
+        return Err(anyhow::anyhow!("Working copy {} does not exist", working_path));
+    }
+
+    let working_db = Builder::new_local(working_path).build().await.context("Failed to open working copy")?;
+    let working = working_db.connect().context("Failed to get connection")?;
+    // Hashed before diffing, so a write that lands in between shows up as a change below
+    let diffed = snapshot::content_hash(&working).await?;
+
+    // With change capture installed only the logged rows are read; otherwise (or with
+    // --full-diff, or after a schema change, which the log does not capture) both
+    // databases are diffed. Either way the log is trimmed afterwards.
+    let tracked = track::is_installed(&working).await?;
+    let full_diff = push.full_diff || (tracked && schema_changed(replica_path, &working).await?);
+    if full_diff && !push.full_diff {
+        info!(
+            "The schema of {} differs from {}; diffing both instead of reading the change log",
+            working_path, replica_path
+        );
+    }
+    // Changesets record the old values of every row, which replace the replica as the conflict base
+    let mut base = None;
+    let (changes, last_seq) = if push.format != DiffFormat::Sql {
+        let last_seq = if tracked { Some(track::last_seq(&working).await?) } else { None };
+        info!("Generating {:?} between {} and {}", push.format, replica_path, working_path);
+        let data = changeset::create(replica_path, working_path, push.format)?;
+        if !options.dry_run {
+            fs::write(diff_file, &data).context("Failed to write diff file")?;
+            info!("Generated {:?} ({} bytes), saved to {}", push.format, data.len(), diff_file);
+        }
+        let (changes, old) = changeset::to_changes(&changeset::decode(&data)?, &working).await?;
+        if push.format == DiffFormat::Changeset {
+            base = Some(old);
+        }
+        (changes, last_seq)
+    } else if tracked && !full_diff {
+        info!("Reading changes captured in {}", working_path);
+        let replica = Builder::new_local(replica_path)
+            .flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .build()
+            .await
+            .context("Failed to open local replica")?;
+        let capture = track::capture(&replica.connect()?, &working).await?;
+        info!("Captured {} changes from {} logged writes", capture.changes.len(), capture.logged);
+        (capture.changes, Some(capture.last_seq))
+    } else {
+        let last_seq = if tracked { Some(track::last_seq(&working).await?) } else { None };
+        info!("Generating diff between {} and {}", replica_path, working_path);
+        (diff::diff_databases(replica_path, working_path).await?, last_seq)
+    };
+
+    if changes.is_empty() {
+        info!("No changes detected - databases are identical");
+        if let Some(seq) = last_seq.filter(|_| !options.dry_run) {
+            track::acknowledge(&working, seq).await?;
+        }
+        return Ok(());
+    }
+
+    // Protected rows and table sizes come from the replica, so a rejected push never contacts Turso
+    let replica = Builder::new_local(replica_path)
+        .flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .build()
+        .await
+        .context("Failed to open local replica")?;
+    let replica = replica.connect()?;
+    let dropped = protected_changes(&replica, &changes, &options.protect, options.dry_run).await?;
+    let changes = without(changes, &dropped);
+    let base = base.map(|base| without(base, &dropped));
+    if changes.is_empty() {
+        info!("Every change touches protected rows - nothing to push");
+        // The dropped changes are never pushed; leaving them logged would capture them again every time
+        if let Some(seq) = last_seq.filter(|_| !options.dry_run) {
+            track::acknowledge(&working, seq).await?;
+        }
+        return Ok(());
+    }
+    let violations = guard_violations(&replica, &changes, &options.guard).await?;
+
+    if options.dry_run {
+        let changes = if options.atomic { idempotent_schema(changes) } else { changes };
+        let mode = ApplyMode::for_push(options, changes.len());
+        println!("Push plan for {}:\n\n{}", url, apply::plan(&changes, mode));
+        print_violations(&violations, &options.guard);
+        info!("Dry run: Turso was not contacted, so rows changed remotely since the last sync are not checked");
+        return Ok(());
+    }
+    enforce_guards(&violations, &options.guard)?;
+
+    let conn = connect_turso(url, token).await?;
+    let conflicts = apply_push(replica_path, &conn, changes, base, diff_file, push, options).await?;
+
+    if let Some(seq) = last_seq {
+        track::acknowledge(&working, seq).await?;
+    }
+
     // Update local replica to match
     sync_from_turso(replica_path, url, token).await?;
-    
+    // Turso now has everything that was diffed, so a later copy may replace that state;
+    // one with changes left behind or written during the push keeps counting as unpushed
+    if !dropped.is_empty() || conflicts > 0 {
+        info!("Not recording {} as pushed: {} changes were left out", working_path, dropped.len() + conflicts);
+    } else if snapshot::content_hash(&working).await? != diffed {
+        info!("Not recording {} as pushed: it changed during the push", working_path);
+    } else {
+        SnapshotState { hash: diffed, recorded_at: journal::now() }.save(working_path)?;
+    }
+
+    if push.verify {
+        verify_databases(&working, working_path, &conn).await?;
+    }
+
+    Ok(())
+}
+
+/// Check `changes` against the remote and apply the ones left, journaling progress
+/// next to `diff_file`.
+///
+/// The journal belongs to the diff as generated, before the conflict check, and
+/// records which changes the check dropped. A resumed push reuses that instead of
+/// checking again: rows it applied before being interrupted now look like changes the
+/// remote already has, and dropping them would shift the journaled progress.
+///
+/// Returns how many changes were left out because they conflict with remote edits.
+async fn apply_push(
+    replica_path: &str,
+    conn: &libsql::Connection,
+    changes: Vec<Change>,
+    base: Option<RowSnapshot>,
+    diff_file: &str,
+    push: &PushOptions,
+    options: &ApplyOptions,
+) -> Result<usize> {
+    let mut journal =
+        journal::JournalFile::open(diff_file, &diff::render_sql(&changes), changes.len(), options.resume)?;
+    let resumed = journal.skipped().is_some();
+    let (skipped, conflicts): (HashSet<usize>, usize) = match journal.skipped() {
+        Some(skipped) => {
+            info!("Resuming a push that was checked against the remote; {} changes were skipped then", skipped.len());
+            (skipped.iter().copied().collect(), journal.conflicts())
+        }
+        None => {
+            // The replica is the base the working copy was edited from; make sure the
+            // remote has not moved on underneath the rows we are about to change
+            let (skipped, conflicts) =
+                check_remote_conflicts(replica_path, conn, &changes, base, push.on_conflict).await?;
+            let mut positions: Vec<usize> = skipped.iter().copied().collect();
+            positions.sort_unstable();
+            journal.record_skipped(positions, conflicts)?;
+            (skipped, conflicts)
+        }
+    };
+    let changes = without(changes, &skipped);
+    if changes.is_empty() {
+        info!("Remote already contains every change - nothing to push");
+        journal.finish()?;
+        return Ok(conflicts);
+    }
+
+    let diff_sql = diff::render_sql(&changes);
+
+    // Save diff to file for debugging (binary formats were saved when generated)
+    if push.format == DiffFormat::Sql {
+        fs::write(diff_file, &diff_sql).context("Failed to write diff file")?;
+        info!("Generated diff SQL ({} bytes), saved to {}", diff_sql.len(), diff_file);
+    }
+    debug!("Diff SQL:\n{}", diff_sql);
+
+    // The inverse of a resumed push was saved before any row changed and must not be
+    // replaced by one computed from half-applied rows
+    if !resumed || !Path::new(&rollback::inverse_path(diff_file)).exists() {
+        let before = conflict::snapshot_rows(conn, &changes).await?;
+        save_inverse(conn, diff_file, &changes, &before).await?;
+    }
+
+    // Apply diff to Turso with batching for large diffs
+    info!("Applying changes to Turso");
+    apply_changes(conn, changes, options, &mut journal).await?;
+    journal.finish()?;
+    info!("Successfully applied changes to Turso");
+    Ok(conflicts)
+}
+
+/// Apply `changes` the way `push` does: atomically, in grouped batches for large
+/// diffs, or as a single batch
+async fn apply_changes(
+    conn: &libsql::Connection,
+    changes: Vec<Change>,
+    options: &ApplyOptions,
+    journal: &mut journal::JournalFile,
+) -> Result<()> {
+    // Row values are bound as parameters rather than sent as SQL literals
+    match ApplyMode::for_push(options, changes.len()) {
+        ApplyMode::Transaction | ApplyMode::Staged => {
+            let safe_changes = idempotent_schema(changes);
+            apply::apply_atomic(conn, &safe_changes, options.max_transaction_statements, journal).await?;
+        }
+        ApplyMode::Grouped => {
+            info!("Large diff detected ({} statements), processing in batches", changes.len());
+            apply::apply_grouped(conn, &changes, &options.batching, journal).await?;
+        }
+        _ => {
+            // Small diff, execute as single batch
+            let statements = coalesce::coalesce_changes(&changes);
+            let phases = [apply::Phase::new("diff", statements, changes.len())];
+            apply::execute_phases(conn, &phases, &options.batching, journal)
+                .await
+                .context("Failed to execute diff SQL")?;
+        }
+    }
+    Ok(())
+}
+
+/// Verify that Turso holds exactly the data of the working copy
+async fn verify_working_copy(working_path: &str, url: &str, token: &str) -> Result<()> {
+    if !Path::new(working_path).exists() {
+        return Err(anyhow::anyhow!("Working copy {} does not exist", working_path));
+    }
+    let working = Builder::new_local(working_path)
+        .flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .build()
+        .await
+        .context("Failed to open working copy")?;
+    let remote = connect_turso(url, token).await?;
+    verify_databases(&working.connect()?, working_path, &remote).await
+}
+
+/// Compare per-table row counts and content hashes of `local` and Turso, failing
+/// with the tables that diverge
+async fn verify_databases(local: &libsql::Connection, local_name: &str, remote: &libsql::Connection) -> Result<()> {
+    info!("Verifying {} against Turso with per-table checksums", local_name);
+    let local_sums = verify::checksums(local).await?;
+    let remote_sums = verify::checksums(remote).await?;
+
+    let tables = local_sums.keys().chain(remote_sums.keys()).collect::<BTreeSet<_>>().len();
+    let diverged = verify::compare(&local_sums, &remote_sums);
+    if diverged.is_empty() {
+        let rows: u64 = local_sums.values().map(|c| c.rows).sum();
+        println!("Verified {} tables ({} rows): Turso matches {}", tables, rows, local_name);
+        return Ok(());
+    }
+    let report: Vec<String> = diverged.iter().map(|(table, d)| format!("  - {}: {}", table, d)).collect();
+    Err(anyhow::anyhow!(
+        "Turso diverges from {} in {} of {} tables:\n{}",
+        local_name,
+        diverged.len(),
+        tables,
+        report.join("\n")
+    ))
+}
+
+/// Report the rows that differ between a local database and Turso and, with
+/// `--repair`, bring the side that is not the source of truth in line
+async fn check_drift(
+    working_path: &str,
+    url: &str,
+    token: &str,
+    diff_file: &str,
+    drift: &DriftOptions,
+    options: &ApplyOptions,
+) -> Result<()> {
+    if !Path::new(working_path).exists() {
+        return Err(anyhow::anyhow!("Local database {} does not exist", working_path));
+    }
+    let repair_local = drift.repair && drift.source == DriftSource::Remote && !options.dry_run;
+    let local = Builder::new_local(working_path)
+        .flags(if repair_local { OpenFlags::default() } else { OpenFlags::SQLITE_OPEN_READ_ONLY })
+        .build()
+        .await
+        .context("Failed to open local database")?;
+    let local = local.connect()?;
+    let remote = connect_turso(url, token).await?;
+
+    let (source, target, target_name) = match drift.source {
+        DriftSource::Local => (&local, &remote, "Turso"),
+        DriftSource::Remote => (&remote, &local, working_path),
+    };
+    info!("Comparing {} with Turso by hashing key ranges", working_path);
+    let report = drift::detect(source, target, &drift.tables).await?;
+
+    for (table, reason) in &report.skipped {
+        warn!("Skipped {}: {}", table, reason);
+    }
+    let changes = report.changes();
+    if changes.is_empty() {
+        println!("No drift: {} tables match between {} and Turso", report.tables.len(), working_path);
+        return Ok(());
+    }
+    for table in report.tables.iter().filter(|t| !t.changes.is_empty()) {
+        println!("{}", table);
+    }
+    println!("{}", drift::report(&changes, 20));
+    if !drift.repair {
+        info!("Run with --repair to bring {} in line with its source of truth", target_name);
+        return Ok(());
+    }
+
+    let dropped = protected_changes(target, &changes, &options.protect, options.dry_run).await?;
+    let changes = without(changes, &dropped);
+    if changes.is_empty() {
+        info!("Every differing row is protected - nothing to repair");
+        return Ok(());
+    }
+    let violations = guard_violations(target, &changes, &options.guard).await?;
+    if options.dry_run {
+        let mode = ApplyMode::for_push(options, changes.len());
+        println!("\nRepair plan for {}:\n\n{}", target_name, apply::plan(&changes, mode));
+        print_violations(&violations, &options.guard);
+        return Ok(());
+    }
+    enforce_guards(&violations, &options.guard)?;
+
+    let diff_sql = diff::render_sql(&changes);
+    fs::write(diff_file, &diff_sql).context("Failed to write diff file")?;
+    info!("Repairing {} changes on {}, saved to {}", changes.len(), target_name, diff_file);
+    let mut journal = journal::JournalFile::open(diff_file, &diff_sql, changes.len(), options.resume)?;
+    apply_changes(target, changes, options, &mut journal).await?;
+    journal.finish()?;
+    info!("Successfully repaired {}", target_name);
+    if drift.source == DriftSource::Local {
+        info!("Run 'turso-sync sync' to bring the local replica up to date");
+    }
+    Ok(())
+}
+
+/// Install (or remove) change capture triggers in the working copy
+async fn track_changes(working_path: &str, tables: &[String], remove: bool) -> Result<()> {
+    if !Path::new(working_path).exists() {
+        return Err(anyhow::anyhow!("Working copy {} does not exist", working_path));
+    }
+
+    let db = Builder::new_local(working_path).build().await.context("Failed to open working copy")?;
+    let conn = db.connect().context("Failed to get connection")?;
+
+    if remove {
+        let dropped = track::uninstall(&conn).await?;
+        info!("Removed {} capture triggers and the change log from {}", dropped, working_path);
+        return Ok(());
+    }
+
+    let tracked = track::install(&conn, tables).await?;
+    info!("Tracking {} tables in {}: {}", tracked.len(), working_path, tracked.join(", "));
+    info!("Rows changed from now on are pushed from the change log; after a schema change, push diffs both databases instead");
+    Ok(())
+}
+
+/// Whether the schema of `working` differs from the replica's
+async fn schema_changed(replica_path: &str, working: &libsql::Connection) -> Result<bool> {
+    let replica = Builder::new_local(replica_path)
+        .flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .build()
+        .await
+        .context("Failed to open local replica")?;
+    Ok(diff::schema_hash(&replica.connect()?).await? != diff::schema_hash(working).await?)
+}
+
+/// Compare the rows touched by `changes` in the replica (or in `base`, when the diff
+/// recorded them) with the remote and handle rows that changed remotely since the
+/// replica was synced according to `policy`. Returns the positions of the changes
+/// that are not to be applied and how many of those conflict with remote edits.
+async fn check_remote_conflicts(
+    replica_path: &str,
+    remote: &libsql::Connection,
+    changes: &[Change],
+    base: Option<RowSnapshot>,
+    policy: ConflictPolicy,
+) -> Result<(HashSet<usize>, usize)> {
+    let synced = match SyncState::load(replica_path)? {
+        Some(state) => format!("replica synced at {}", state.describe()),
+        None => "replica sync position unknown".to_string(),
+    };
+    info!("Checking touched rows against the remote ({})", synced);
+
+    let base = match base {
+        Some(base) => base,
+        None => {
+            let replica = Builder::new_local(replica_path)
+                .flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .build()
+                .await
+                .context("Failed to open local replica")?;
+            conflict::snapshot_rows(&replica.connect()?, changes).await?
+        }
+    };
+    let current = conflict::snapshot_rows(remote, changes).await?;
+
+    let check = ConflictCheck::detect(changes, &base, &current);
+    if !check.redundant.is_empty() {
+        info!("{} changes are already on the remote and will be skipped", check.redundant.len());
+    }
+    if check.conflicts.is_empty() {
+        return Ok((check.dropped(), 0));
+    }
+
+    match policy {
+        ConflictPolicy::Abort => Err(anyhow::anyhow!(
+            "Push aborted: {} changes conflict with remote edits made after the last sync ({}):\n{}\n\
+             Nothing was applied. Sync and re-apply your changes, or rerun with --on-conflict rebase \
+             to push only the non-conflicting changes",
+            check.conflicts.len(),
+            synced,
+            check.report(20)
+        )),
+        ConflictPolicy::Rebase => {
+            warn!(
+                "Skipping {} changes that conflict with remote edits; the remote keeps its values:\n{}",
+                check.conflicts.len(),
+                check.report(20)
+            );
+            Ok((check.dropped(), check.conflicts.len()))
+        }
+    }
+}
+
+/// Merge the working copy with the remote snapshot and write the merged diff
+async fn merge_with_remote(
+    replica_path: &str,
+    working_path: &str,
+    remote_path: &str,
+    policy: MergePolicy,
+    timestamp_column: &str,
+    diff_file: &str,
+) -> Result<()> {
+    info!("Merging {} and {} (base {})", working_path, remote_path, replica_path);
+
+    let merge = merge::merge_databases(replica_path, working_path, remote_path, policy, timestamp_column).await?;
+
+    if !merge.conflicts.is_empty() {
+        warn!("{} conflicting rows:\n{}", merge.conflicts.len(), merge.report(50));
+    }
+    if merge.unresolved() > 0 {
+        return Err(anyhow::anyhow!(
+            "Merge failed: {} conflicts left unresolved by policy {:?}; nothing was written",
+            merge.unresolved(),
+            policy
+        ));
+    }
+
+    let diff_sql = diff::render_sql(&merge.changes);
+    fs::write(diff_file, &diff_sql).context("Failed to write merge diff file")?;
+
+    info!(
+        "Merged {} changes ({} already on the remote, {} conflicts resolved), saved to {}",
+        merge.changes.len(),
+        merge.redundant,
+        merge.conflicts.len(),
+        diff_file
+    );
+    if !merge.changes.is_empty() {
+        info!("Apply with: turso-sync apply-diff --db-path {} --diff-file {}", remote_path, diff_file);
+    }
     Ok(())
 }
 
@@ -400,214 +1251,472 @@ async fn apply_diff_to_turso(
     url: &str,
     token: &str,
     no_sync: bool,
+    on_conflict: ConflictPolicy,
+    options: &ApplyOptions,
 ) -> Result<()> {
     info!("Applying diff file to local replica database and syncing to Turso");
-    
+
     // Check if the database exists
     if !Path::new(db_path).exists() {
         return Err(anyhow::anyhow!("Local database {} does not exist", db_path));
     }
-    
+
     // Check if diff file exists
     if !Path::new(diff_file).exists() {
         return Err(anyhow::anyhow!("Diff file {} does not exist", diff_file));
     }
-    
+
     // Read diff file
-    let diff_sql = fs::read_to_string(diff_file)
-        .context("Failed to read diff file")?;
-    
-    if diff_sql.trim().is_empty() {
+    let data = fs::read(diff_file).context("Failed to read diff file")?;
+
+    if data.trim_ascii().is_empty() {
         info!("No changes detected - diff file is empty");
         return Ok(());
     }
-    
-    info!("Read diff file: {} bytes", diff_sql.len());
-    debug!("Diff SQL:\n{}", diff_sql);
-    
+
+    let format = DiffFormat::detect(&data);
+    info!("Read diff file: {} bytes ({:?})", data.len(), format);
+
+    if options.dry_run {
+        return plan_apply_diff(db_path, data, format, no_sync, options).await;
+    }
+
+    // Changesets applied locally go through the session extension, which reports
+    // every row that does not match the change instead of failing on the first
+    if format != DiffFormat::Sql && no_sync {
+        let db = Builder::new_local(db_path).build().await.context("Failed to open local database")?;
+        let conn = db.connect().context("Failed to get connection")?;
+        let rows = changeset::decode(&data)?;
+        let (changes, _) = changeset::to_changes(&rows, &conn).await?;
+        let dropped = protected_changes(&conn, &changes, &options.protect, false).await?;
+        let changes = without(changes, &dropped);
+        let data = if dropped.is_empty() { data } else { changeset::encode(&without(rows, &dropped), format) };
+        enforce_guards(&guard_violations(&conn, &changes, &options.guard).await?, &options.guard)?;
+        let before = conflict::snapshot_rows(&conn, &changes).await?;
+
+        let conflicts = changeset::apply_local(db_path, &data, on_conflict)?;
+        let skipped: HashSet<usize> = conflicts.iter().map(|c| c.index).collect();
+        report_changeset_conflicts(conflicts, on_conflict)?;
+        info!("Successfully applied {:?} to {}", format, db_path);
+
+        // Skipped rows were left alone, so there is nothing to undo for them
+        return save_inverse(&conn, diff_file, &without(changes, &skipped), &without(before, &skipped)).await;
+    }
+
     // For diff application, we'll use a simple local connection and only sync if requested
     let db = if no_sync {
         // For local-only mode, use a simple local database connection
         info!("Using local-only database connection");
-        Builder::new_local(db_path)
-            .build()
-            .await
-            .context("Failed to create local database")?
+        Builder::new_local(db_path).build().await.context("Failed to create local database")?
     } else {
         // For sync mode, use the remote replica
         info!("Using synced database connection");
-        Builder::new_remote_replica(db_path, url.to_string(), token.to_string())
-            .build()
-            .await
-            .context("Failed to create synced database")?
+        with_retry("Opening remote replica", || async {
+            Builder::new_remote_replica(db_path, url.to_string(), token.to_string())
+                .build()
+                .await
+                .context("Failed to create synced database")
+        })
+        .await?
     };
-    
+
     let conn = db.connect().context("Failed to get connection")?;
-    
+
     // Apply diff to local replica database
     info!("Applying diff to local replica database");
-    
-    // Check if we need to batch the operations
-    let statements: Vec<&str> = diff_sql.split(';').collect();
-    let non_empty_statements: Vec<&str> = statements
-        .iter()
-        .map(|s| s.trim())
-        .filter(|s| !s.is_empty() && *s != "BEGIN TRANSACTION" && *s != "COMMIT")
-        .collect();
-    
-    let statement_count = non_empty_statements.len();
-    
-    // Analyze and group statements by type for batch execution
-    info!("Analyzing {} statements for batch optimization...", statement_count);
-    
-    let mut create_statements = Vec::new();
-    let mut delete_statements = Vec::new();
-    let mut insert_statements = Vec::new();
-    let mut other_statements = Vec::new();
-    
-    for statement in &non_empty_statements {
-        let trimmed = statement.trim();
-        if trimmed.starts_with("CREATE") {
-            create_statements.push(make_create_statement_idempotent(statement));
-        } else if trimmed.starts_with("DELETE FROM email_schedules WHERE id=") {
-            delete_statements.push(statement.to_string());
-        } else if trimmed.starts_with("INSERT INTO email_schedules") {
-            insert_statements.push(statement.to_string());
+
+    let (changes, diff_sql) = if format == DiffFormat::Sql {
+        let diff_sql = String::from_utf8(data).context("Diff file is neither SQL text nor a changeset")?;
+        debug!("Diff SQL:\n{}", diff_sql);
+        // Parse the diff back into row changes so their values can be bound as parameters
+        (diff::parse_sql(&diff_sql), diff_sql)
+    } else {
+        let (changes, old) = changeset::to_changes(&changeset::decode(&data)?, &conn).await?;
+        let changes = if format == DiffFormat::Changeset {
+            let current = conflict::snapshot_rows(&conn, &changes).await?;
+            let check = ConflictCheck::detect(&changes, &old, &current);
+            let conflicts = check.conflicts.clone();
+            report_changeset_conflicts(conflicts, on_conflict)?;
+            check.rebase(changes)
         } else {
-            other_statements.push(statement.to_string());
-        }
+            warn!("Patchsets carry no old values, so conflicting rows are overwritten");
+            changes
+        };
+        let diff_sql = diff::render_sql(&changes);
+        (changes, diff_sql)
+    };
+    let dropped = protected_changes(&conn, &changes, &options.protect, false).await?;
+    let changes = without(changes, &dropped);
+    let statement_count = changes.len();
+    enforce_guards(&guard_violations(&conn, &changes, &options.guard).await?, &options.guard)?;
+
+    if !resuming_with_inverse(diff_file, options) {
+        let before = conflict::snapshot_rows(&conn, &changes).await?;
+        save_inverse(&conn, diff_file, &changes, &before).await?;
     }
-    
-    info!("Statement grouping complete:");
-    info!("  - CREATE statements: {}", create_statements.len());
-    info!("  - DELETE statements: {}", delete_statements.len());
-    info!("  - INSERT statements: {}", insert_statements.len());
-    info!("  - Other statements: {}", other_statements.len());
-    
-    info!("Starting optimized execution...");
+
     let execution_start = std::time::Instant::now();
-    
-    // Execute CREATE statements first (usually just a few)
-    if !create_statements.is_empty() {
-        info!("Executing {} CREATE statements...", create_statements.len());
-        for (i, statement) in create_statements.iter().enumerate() {
-            info!("CREATE {}/{}: {}", i + 1, create_statements.len(),
-                  if statement.len() > 100 { format!("{}...", &statement[..100]) } else { statement.to_string() });
-            conn.execute(statement, ())
-                .await
-                .with_context(|| format!("Failed to execute CREATE statement: {}", statement))?;
-        }
-        info!("✅ Completed {} CREATE statements", create_statements.len());
-    }
-    
-    // Batch execute DELETE statements using execute_batch
-    if !delete_statements.is_empty() {
-        info!("Batch executing {} DELETE statements...", delete_statements.len());
-        let batch_size = 1000; // Much larger batch for simple DELETEs
-        let total_batches = (delete_statements.len() + batch_size - 1) / batch_size;
-        
-        for (batch_num, batch) in delete_statements.chunks(batch_size).enumerate() {
-            info!("DELETE batch {}/{} ({} statements)", batch_num + 1, total_batches, batch.len());
-            
-            // Join statements with semicolons for batch execution
-            let batch_sql = batch.join(";\n") + ";";
-            
-            conn.execute_batch(&batch_sql)
-                .await
-                .with_context(|| format!("Failed to execute DELETE batch {}", batch_num + 1))?;
-                
-            info!("✅ Completed DELETE batch {}/{}", batch_num + 1, total_batches);
-        }
-        info!("✅ Completed {} DELETE statements", delete_statements.len());
-    }
-    
-    // Batch execute INSERT statements using execute_batch
-    if !insert_statements.is_empty() {
-        info!("Batch executing {} INSERT statements...", insert_statements.len());
-        let batch_size = 500; // Moderate batch size for INSERTs (they're larger)
-        let total_batches = (insert_statements.len() + batch_size - 1) / batch_size;
-        
-        for (batch_num, batch) in insert_statements.chunks(batch_size).enumerate() {
-            info!("INSERT batch {}/{} ({} statements)", batch_num + 1, total_batches, batch.len());
-            
-            // Join statements with semicolons for batch execution
-            let batch_sql = batch.join(";\n") + ";";
-            
-            conn.execute_batch(&batch_sql)
-                .await
-                .with_context(|| format!("Failed to execute INSERT batch {}", batch_num + 1))?;
-                
-            info!("✅ Completed INSERT batch {}/{}", batch_num + 1, total_batches);
-        }
-        info!("✅ Completed {} INSERT statements", insert_statements.len());
-    }
-    
-    // Execute other statements individually (usually just a few)
-    if !other_statements.is_empty() {
-        info!("Executing {} other statements individually...", other_statements.len());
-        for (i, statement) in other_statements.iter().enumerate() {
-            info!("OTHER {}/{}: {}", i + 1, other_statements.len(),
-                  if statement.len() > 100 { format!("{}...", &statement[..100]) } else { statement.to_string() });
-            conn.execute(statement, ())
-                .await
-                .with_context(|| format!("Failed to execute statement: {}", statement))?;
+    let mut journal = journal::JournalFile::open(diff_file, &diff_sql, statement_count, options.resume)?;
+
+    match ApplyMode::for_apply_diff(options, statement_count, no_sync) {
+        ApplyMode::Grouped => apply::apply_grouped(&conn, &changes, &options.batching, &mut journal).await?,
+        ApplyMode::Transaction if no_sync => apply::apply_in_transaction(&conn, &idempotent_schema(changes)).await?,
+        _ => {
+            let safe_changes = idempotent_schema(changes);
+            apply::apply_atomic(&conn, &safe_changes, options.max_transaction_statements, &mut journal).await?;
         }
-        info!("✅ Completed {} other statements", other_statements.len());
     }
-    
+    journal.finish()?;
+
     let execution_duration = execution_start.elapsed();
-    info!("Successfully applied {} statements to local replica database in {:.2}s", 
-          statement_count, execution_duration.as_secs_f64());
-    
+    info!(
+        "Successfully applied {} statements to local replica database in {:.2}s",
+        statement_count,
+        execution_duration.as_secs_f64()
+    );
+
     // Sync to Turso if not skipped
     if !no_sync {
         info!("Syncing changes to Turso...");
-        db.sync().await.context("Failed to sync to Turso")?;
+        with_retry("Sync", || async { db.sync().await.context("Failed to sync to Turso") }).await?;
         info!("Successfully synced to Turso");
     } else {
         info!("Skipping sync to Turso (--no-sync flag set)");
     }
-    
+
     Ok(())
 }
 
-/// Initialize and sync a database using offline sync capabilities
-async fn offline_sync(
+/// Print what `apply-diff` would do with a diff, reading the database without changing it
+async fn plan_apply_diff(
     db_path: &str,
+    data: Vec<u8>,
+    format: DiffFormat,
+    no_sync: bool,
+    options: &ApplyOptions,
+) -> Result<()> {
+    let db = Builder::new_local(db_path)
+        .flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .build()
+        .await
+        .context("Failed to open local database")?;
+    let conn = db.connect().context("Failed to get connection")?;
+
+    let (changes, mode) = if format == DiffFormat::Sql {
+        let diff_sql = String::from_utf8(data).context("Diff file is neither SQL text nor a changeset")?;
+        let changes = diff::parse_sql(&diff_sql);
+        let mode = ApplyMode::for_apply_diff(options, changes.len(), no_sync);
+        (changes, mode)
+    } else {
+        let (changes, _) = changeset::to_changes(&changeset::decode(&data)?, &conn).await?;
+        let mode =
+            if no_sync { ApplyMode::Session } else { ApplyMode::for_apply_diff(options, changes.len(), no_sync) };
+        (changes, mode)
+    };
+    let dropped = protected_changes(&conn, &changes, &options.protect, true).await?;
+    let changes = without(changes, &dropped);
+    let violations = guard_violations(&conn, &changes, &options.guard).await?;
+    let changes = if options.atomic { idempotent_schema(changes) } else { changes };
+
+    let target = if no_sync { db_path.to_string() } else { format!("{} (then synced to Turso)", db_path) };
+    println!("Apply plan for {}:\n\n{}", target, apply::plan(&changes, mode));
+    print_violations(&violations, &options.guard);
+    info!("Dry run: nothing was applied");
+    Ok(())
+}
+
+/// Find the changes to rows protected by `--protect` rules, reading the rows from
+/// `conn` as they are before the apply. Fails under `--on-protected reject` (unless
+/// only planning); otherwise returns the positions of the changes to leave out.
+async fn protected_changes(
+    conn: &libsql::Connection,
+    changes: &[Change],
+    protect: &ProtectOptions,
+    dry_run: bool,
+) -> Result<HashSet<usize>> {
+    if protect.no_protect || protect.rules.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let protected = protect::find_protected(conn, changes, &protect.rules).await?;
+    if protected.is_empty() {
+        return Ok(HashSet::new());
+    }
+    let report = protect::report(&protected, 20);
+    match protect.on_protected {
+        ProtectAction::Reject if dry_run => {
+            println!(
+                "Row protection would reject this diff, {} changes modify protected rows:\n{}\n",
+                protected.len(),
+                report
+            );
+            Ok(HashSet::new())
+        }
+        ProtectAction::Reject => Err(anyhow::anyhow!(
+            "Rejected: {} changes would modify protected rows:\n{}\n\
+             Nothing was written. Fix the diff, or rerun with --on-protected drop to apply the other changes",
+            protected.len(),
+            report
+        )),
+        ProtectAction::Drop => {
+            if dry_run {
+                println!("Row protection drops {} changes to protected rows:\n{}\n", protected.len(), report);
+            } else {
+                warn!("Dropping {} changes to protected rows:\n{}", protected.len(), report);
+            }
+            Ok(protected.iter().map(|p| p.index).collect())
+        }
+    }
+}
+
+/// `items` without the ones at the `dropped` positions
+fn without<T>(items: Vec<T>, dropped: &HashSet<usize>) -> Vec<T> {
+    if dropped.is_empty() {
+        return items;
+    }
+    items.into_iter().enumerate().filter(|(i, _)| !dropped.contains(i)).map(|(_, item)| item).collect()
+}
+
+/// Check `changes` against the safety guards, counting table rows in `conn` as they
+/// are before the apply
+async fn guard_violations(
+    conn: &libsql::Connection,
+    changes: &[Change],
+    guard: &GuardOptions,
+) -> Result<Vec<Violation>> {
+    let rows = guard::table_rows(conn, &guard::touched_tables(changes)).await?;
+    Ok(guard::check(changes, &rows, guard))
+}
+
+/// Fail on safety guard violations unless `--force` was given, before anything is written
+fn enforce_guards(violations: &[Violation], guard: &GuardOptions) -> Result<()> {
+    if violations.is_empty() {
+        return Ok(());
+    }
+    if guard.force {
+        warn!(
+            "Applying despite {} safety guard violations (--force):\n{}",
+            violations.len(),
+            guard::report(violations)
+        );
+        return Ok(());
+    }
+    Err(anyhow::anyhow!(
+        "Blocked by {} safety guard violations:\n{}\n\
+         Nothing was written. Check the diff, raise the limits, or rerun with --force to apply anyway",
+        violations.len(),
+        guard::report(violations)
+    ))
+}
+
+/// Show the safety guard violations under a dry-run plan
+fn print_violations(violations: &[Violation], guard: &GuardOptions) {
+    if violations.is_empty() {
+        return;
+    }
+    let outcome = if guard.force { "are overridden by --force" } else { "would block this apply" };
+    println!("\nSafety guards {}:\n{}", outcome, guard::report(violations));
+}
+
+/// Fail on changeset conflicts under `ConflictPolicy::Abort`, otherwise log them as skipped
+fn report_changeset_conflicts(conflicts: Vec<conflict::Conflict>, policy: ConflictPolicy) -> Result<()> {
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    let check = ConflictCheck { conflicts, redundant: Vec::new() };
+    match policy {
+        ConflictPolicy::Abort => Err(anyhow::anyhow!(
+            "Apply aborted: {} rows no longer match the old values recorded in the changeset:\n{}\n\
+             Nothing was applied. Rerun with --on-conflict rebase to apply the other changes",
+            check.conflicts.len(),
+            check.report(20)
+        )),
+        ConflictPolicy::Rebase => {
+            warn!("Skipped {} conflicting rows:\n{}", check.conflicts.len(), check.report(20));
+            Ok(())
+        }
+    }
+}
+
+/// Save the changeset undoing `changes` next to the diff file. `before` holds the rows
+/// they touch as they are before the diff is applied.
+async fn save_inverse(
+    conn: &libsql::Connection,
+    diff_file: &str,
+    changes: &[Change],
+    before: &RowSnapshot,
+) -> Result<()> {
+    let inverse = rollback::invert(conn, changes, before).await?;
+    let path = rollback::inverse_path(diff_file);
+    fs::write(&path, &inverse.data).with_context(|| format!("Failed to write inverse changeset {}", path))?;
+    info!("Saved inverse changeset with {} changes to {}", inverse.rows, path);
+    if inverse.skipped > 0 {
+        warn!(
+            "{} changes cannot be rolled back: schema changes and rows of tables without a PRIMARY KEY",
+            inverse.skipped
+        );
+    }
+    Ok(())
+}
+
+/// Whether an interrupted apply is being resumed; its inverse was saved before any
+/// row changed and must not be replaced by one computed from half-applied rows
+fn resuming_with_inverse(diff_file: &str, options: &ApplyOptions) -> bool {
+    options.resume
+        && journal::journal_path(diff_file).exists()
+        && Path::new(&rollback::inverse_path(diff_file)).exists()
+}
+
+/// Undo a push by applying its inverse changeset to Turso, provided every row it
+/// touches still holds what the push wrote
+async fn rollback_push(
+    replica_path: &str,
     url: &str,
     token: &str,
-    direction: &str,
+    diff_file: &str,
+    max_transaction_statements: usize,
+    resume: bool,
 ) -> Result<()> {
+    let inverse_file = rollback::inverse_path(diff_file);
+    if !Path::new(&inverse_file).exists() {
+        return Err(anyhow::anyhow!(
+            "No inverse changeset {}; it is saved by push and apply-diff with --diff-file {}",
+            inverse_file,
+            diff_file
+        ));
+    }
+    let data = fs::read(&inverse_file).with_context(|| format!("Failed to read inverse changeset {}", inverse_file))?;
+    let rows = changeset::decode(&data)?;
+    info!("Rolling back {} with {} changes from {}", diff_file, rows.len(), inverse_file);
+
+    let conn = connect_turso(url, token).await?;
+
+    // The old values of the inverse are the rows as the push left them
+    let (changes, pushed) = changeset::to_changes(&rows, &conn).await?;
+    let current = conflict::snapshot_rows(&conn, &changes).await?;
+    let check = ConflictCheck::detect(&changes, &pushed, &current);
+    if !check.conflicts.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Rollback aborted: {} rows changed since the push:\n{}\n\
+             Nothing was rolled back",
+            check.conflicts.len(),
+            check.report(20)
+        ));
+    }
+    if !check.redundant.is_empty() {
+        info!("{} rows are already back to their state before the push", check.redundant.len());
+    }
+    let changes = check.rebase(changes);
+
+    if !changes.is_empty() {
+        let diff_sql = diff::render_sql(&changes);
+        debug!("Rollback SQL:\n{}", diff_sql);
+        let mut journal = journal::JournalFile::open(&inverse_file, &diff_sql, changes.len(), resume)?;
+        apply::apply_atomic(&conn, &changes, max_transaction_statements, &mut journal).await?;
+        journal.finish()?;
+    }
+
+    // Rolled back once; applying the inverse again would only report conflicts
+    fs::remove_file(&inverse_file).with_context(|| format!("Failed to remove inverse changeset {}", inverse_file))?;
+    info!("Successfully rolled back {} changes on Turso", changes.len());
+
+    sync_from_turso(replica_path, url, token).await?;
+    info!("The working copy still holds the rolled back changes; run 'turso-sync copy' to start over from the replica");
+    Ok(())
+}
+
+/// Make the CREATE statements among `changes` idempotent
+fn idempotent_schema(changes: Vec<Change>) -> Vec<Change> {
+    changes
+        .into_iter()
+        .map(|change| match change {
+            Change::Schema(sql) => Change::Schema(sql::make_create_statement_idempotent(&sql)),
+            row => row,
+        })
+        .collect()
+}
+
+/// Initialize and sync a database using offline sync capabilities
+async fn offline_sync(db_path: &str, url: &str, token: &str, direction: SyncDirection, dry_run: bool) -> Result<()> {
     info!("Performing offline sync for database: {}", db_path);
-    info!("Direction: {}", direction);
-    
+    info!("Direction: {:?}", direction);
+
+    // libsql has to initialize SQLite before the frame count opens it through rusqlite
+    Builder::new_local(":memory:").build().await.context("Failed to initialize libsql")?;
+    // A synced database pushes on sync() when it has unpushed frames and pulls
+    // otherwise, so what each direction may do depends on how far ahead we are
+    let ahead = offline::frames_ahead(db_path)?;
+    if direction == SyncDirection::Pull && ahead > 0 {
+        return Err(anyhow::anyhow!(
+            "{} has {} local frames that were not pushed yet and a pull would push them first; \
+             use --direction push or both",
+            db_path,
+            ahead
+        ));
+    }
+
+    if dry_run {
+        println!("Offline sync plan for {} ({:?}):\n", db_path, direction);
+        if direction != SyncDirection::Pull {
+            println!("  - push: {} local frames", ahead);
+        }
+        if direction != SyncDirection::Push {
+            // Only the remote knows how many frames it has beyond our position
+            let position = match SyncPosition::load(db_path)? {
+                Some(p) => format!("generation {}, frame {}", p.generation, p.durable_frame_num),
+                None => "the start (never synced)".to_string(),
+            };
+            println!("  + pull: remote frames after {}", position);
+        }
+        info!("Dry run: Turso was not contacted and {} was not changed", db_path);
+        return Ok(());
+    }
+
     // Create synced database (will create if it doesn't exist)
-    let db = Builder::new_remote_replica(db_path, url.to_string(), token.to_string())
-        .build()
-        .await
-        .context("Failed to create synced database")?;
-    
-    match direction {
-        "pull" => {
-            info!("Pulling changes from remote to local database");
-            db.sync().await.context("Failed to sync from remote")?;
-            info!("Successfully pulled changes from remote");
-        }
-        "push" => {
-            info!("Pushing changes from local to remote database");
-            db.sync().await.context("Failed to sync to remote")?;
+    let db = with_retry("Opening synced database", || async {
+        Builder::new_synced_database(db_path, url.to_string(), token.to_string())
+            .build()
+            .await
+            .context("Failed to create synced database")
+    })
+    .await?;
+
+    let mut frames_pushed = 0;
+    let mut frames_pulled = 0;
+
+    if direction != SyncDirection::Pull {
+        if ahead > 0 {
+            info!("Pushing {} local frames to remote database", ahead);
+            let replicated =
+                with_retry("Push sync", || async { db.sync().await.context("Failed to sync to remote") }).await?;
+            frames_pushed = replicated.frames_synced();
+            let left = offline::frames_ahead(db_path)?;
+            if left > 0 {
+                return Err(anyhow::anyhow!(
+                    "Sync did not push the local frames of {}: {} are still ahead of the remote",
+                    db_path,
+                    left
+                ));
+            }
             info!("Successfully pushed changes to remote");
+        } else {
+            info!("No local changes to push");
         }
-        "both" | _ => {
-            info!("Syncing bidirectionally (pull and push)");
-            db.sync().await.context("Failed to sync bidirectionally")?;
-            info!("Successfully synced bidirectionally");
-        }
     }
-    
+
+    if direction != SyncDirection::Push {
+        info!("Pulling changes from remote to local database");
+        // Pulls report no frame count, so measure it from the durable position
+        let before = SyncPosition::load(db_path)?;
+        with_retry("Pull sync", || async { db.sync().await.context("Failed to sync from remote") }).await?;
+        frames_pulled = SyncPosition::frames_since(SyncPosition::load(db_path)?, before);
+        info!("Successfully pulled changes from remote");
+    }
+
+    info!("Sync report: {} frames pushed, {} frames pulled", frames_pushed, frames_pulled);
+
     // Show database stats
     let conn = db.connect().context("Failed to get connection")?;
-    
+
     // Try to get table count as a basic health check
     match conn.query("SELECT name FROM sqlite_master WHERE type='table'", ()).await {
         Ok(mut results) => {
@@ -621,67 +1730,178 @@ async fn offline_sync(
             warn!("Could not query database schema: {}", e);
         }
     }
-    
+
     Ok(())
 }
 
+/// Sync the replica and replace the working copy with a copy of it, dealing with
+/// unpushed changes in the working copy as `on_unpushed` says
+async fn prepare_working_copy(
+    replica_path: &str,
+    working_path: &str,
+    on_unpushed: UnpushedAction,
+    target: &PushTarget<'_>,
+) -> Result<()> {
+    // Left-over changes are handled while the replica is still the state they were
+    // made on; after the sync, a diff against it would also undo remote changes
+    let had_replica = Path::new(replica_path).exists();
+    if had_replica {
+        resolve_unpushed(replica_path, working_path, on_unpushed, Some(target)).await?;
+    }
+
+    sync_from_turso(replica_path, target.url, target.token).await?;
+    if !had_replica {
+        resolve_unpushed(replica_path, working_path, on_unpushed, Some(target)).await?;
+    }
+    replace_working_copy(replica_path, working_path).await
+}
+
+/// Run `command` on a fresh working copy and push what it changed. The exit code
+/// is the command's own when it fails, in which case nothing is pushed.
+async fn run_and_push(
+    replica_path: &str,
+    working_path: &str,
+    on_unpushed: UnpushedAction,
+    command: &[String],
+    target: &PushTarget<'_>,
+) -> Result<ExitCode> {
+    prepare_working_copy(replica_path, working_path, on_unpushed, target).await?;
+
+    shutdown::check()?;
+    info!("Running: {}", command.join(" "));
+    let started = Instant::now();
+    let status = child::run_logged(command).await?;
+    if shutdown::requested() {
+        warn!("{} was stopped ({}); its changes to {} were not pushed", command[0], status, working_path);
+        return Ok(ExitCode::from(child::exit_code(status)));
+    }
+    if !status.success() {
+        error!(
+            "{} failed after {:.1}s ({}); its changes to {} were not pushed",
+            command[0],
+            started.elapsed().as_secs_f64(),
+            status,
+            working_path
+        );
+        return Ok(ExitCode::from(child::exit_code(status)));
+    }
+    info!("{} finished after {:.1}s", command[0], started.elapsed().as_secs_f64());
+
+    snapshot::check_integrity(working_path)
+        .await
+        .with_context(|| format!("{} left {} damaged; nothing was pushed", command[0], working_path))?;
+    push_to_turso(replica_path, working_path, target.url, target.token, target.diff_file, target.push, target.apply)
+        .await?;
+    Ok(ExitCode::SUCCESS)
+}
+
 /// Run the full workflow with periodic syncing
 async fn run_workflow(
     replica_path: &str,
     working_path: &str,
-    url: &str,
-    token: &str,
-    sync_interval: u64,
+    workflow: &WorkflowOptions,
+    target: &PushTarget<'_>,
 ) -> Result<()> {
+    let (url, token, sync_interval) = (target.url, target.token, workflow.sync_interval);
     info!("Starting Turso sync workflow");
-    info!("Replica: {}, Working: {}, Sync interval: {}s", 
-          replica_path, working_path, sync_interval);
-    
+    info!("Replica: {}, Working: {}, Sync interval: {}s", replica_path, working_path, sync_interval);
+
     // Initial sync and copy
-    sync_from_turso(replica_path, url, token).await?;
-    copy_database(replica_path, working_path)?;
-    
+    prepare_working_copy(replica_path, working_path, workflow.on_unpushed, target).await?;
+
     info!("Initial setup complete. OCaml can now use: {}", working_path);
-    info!("Run 'turso-sync push' when ready to sync changes back to Turso");
-    
+    if workflow.watch {
+        info!("Watching {}; changes are pushed once writes stop for {} ms", working_path, workflow.debounce_ms);
+    } else {
+        info!("Run 'turso-sync push' when ready to sync changes back to Turso");
+    }
+
     // Periodic sync from Turso (in case of external changes)
     let mut interval = tokio::time::interval(Duration::from_secs(sync_interval));
-    
+    let mut poll = tokio::time::interval(Duration::from_millis(workflow.watch_interval_ms.max(1)));
+    poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut watcher = Watcher::new(working_path, Duration::from_millis(workflow.debounce_ms));
+
+    // Pulls and pushes run one at a time in this loop, never concurrently, and a
+    // shutdown request ends it between them
     loop {
-        interval.tick().await;
-        
-        info!("Performing periodic sync from Turso...");
-        if let Err(e) = sync_from_turso(replica_path, url, token).await {
-            warn!("Periodic sync failed: {}", e);
-        } else {
-            info!("Periodic sync completed");
+        tokio::select! {
+            biased;
+            _ = shutdown::wait() => {
+                if watcher.pending() {
+                    warn!("Stopping with changes in {} that are not pushed yet", working_path);
+                }
+                return Ok(());
+            }
+            _ = interval.tick() => {
+                // Pulling would move the replica, which the push diffs against, past the
+                // state the local changes were made on and make the diff undo remote changes
+                if watcher.pending() {
+                    info!("Skipping periodic sync: {} has changes that are not pushed yet", working_path);
+                    watcher.release();
+                    continue;
+                }
+                info!("Performing periodic sync from Turso...");
+                if let Err(e) = sync_from_turso(replica_path, url, token).await {
+                    warn!("Periodic sync failed: {}", e);
+                } else {
+                    info!("Periodic sync completed");
+                }
+            }
+            _ = poll.tick(), if workflow.watch => {
+                if !watcher.poll(Instant::now()) {
+                    continue;
+                }
+                info!("{} changed, pushing to Turso...", working_path);
+                let pushed = push_to_turso(
+                    replica_path,
+                    working_path,
+                    url,
+                    token,
+                    target.diff_file,
+                    target.push,
+                    target.apply,
+                )
+                .await;
+                match pushed {
+                    Ok(()) => {
+                        // Writes made during the push, the push's own included, stay pending and are
+                        // pushed next; a push with nothing left to send writes nothing
+                        watcher.reset();
+                        info!("Automatic push completed");
+                    }
+                    Err(e) => {
+                        warn!("Automatic push failed, trying again after the next write or sync interval: {:#}", e);
+                        watcher.hold();
+                    }
+                }
+            }
         }
     }
 }
 
 /// Bidirectional sync with Turso using libSQL sync (pulls and pushes changes)
-async fn libsql_sync(
-    db_path: &str,
-    url: &str,
-    token: &str,
-) -> Result<()> {
+async fn libsql_sync(db_path: &str, url: &str, token: &str) -> Result<()> {
     info!("Starting bidirectional sync with Turso");
     info!("Local database: {}", db_path);
     info!("Remote URL: {}", url);
-    
+
     // Create synced database connection
-    let db = Builder::new_synced_database(db_path, url.to_string(), token.to_string())
-        .build()
-        .await
-        .context("Failed to create synced database connection")?;
-    
+    let db = with_retry("Opening synced database", || async {
+        Builder::new_synced_database(db_path, url.to_string(), token.to_string())
+            .build()
+            .await
+            .context("Failed to create synced database connection")
+    })
+    .await?;
+
     let conn = db.connect().context("Failed to get database connection")?;
-    
+
     // First sync: Pull any remote changes to local
     info!("📥 Syncing from remote to local...");
-    db.sync().await.context("Failed to sync from remote")?;
+    with_retry("Pull sync", || async { db.sync().await.context("Failed to sync from remote") }).await?;
     info!("✅ Successfully pulled changes from remote");
-    
+
     // Show current database state
     match conn.query("SELECT COUNT(*) as count FROM sqlite_master WHERE type='table'", ()).await {
         Ok(mut results) => {
@@ -694,13 +1914,86 @@ async fn libsql_sync(
             warn!("Could not query database schema: {}", e);
         }
     }
-    
+
     // Second sync: Push any local changes to remote
     info!("📤 Syncing from local to remote...");
-    db.sync().await.context("Failed to sync to remote")?;
+    with_retry("Push sync", || async { db.sync().await.context("Failed to sync to remote") }).await?;
     info!("✅ Successfully pushed changes to remote");
-    
+
     info!("🎉 Bidirectional sync completed successfully!");
-    
+
     Ok(())
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{exec, open, temp_dir};
+
+    #[tokio::test]
+    async fn resumes_a_push_that_failed_partway() {
+        let dir = temp_dir();
+        let (replica, working, remote) = (dir.path("replica.db"), dir.path("working.db"), dir.path("remote.db"));
+        exec(
+            &replica,
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, v TEXT);
+             WITH RECURSIVE s(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM s WHERE i < 1200)
+             INSERT INTO t SELECT i, 'old' FROM s;",
+        )
+        .await;
+        fs::copy(&replica, &working).unwrap();
+        fs::copy(&replica, &remote).unwrap();
+        exec(&working, "UPDATE t SET v = 'new'").await;
+        let changes = diff::diff_databases(&replica, &working).await.unwrap();
+
+        // The remote rejects a row in the middle, after several batches committed
+        let (_db, conn) = open(&remote).await;
+        conn.execute_batch(
+            "CREATE TRIGGER reject BEFORE UPDATE ON t WHEN NEW.id = 700 BEGIN SELECT RAISE(ABORT, 'rejected'); END;",
+        )
+        .await
+        .unwrap();
+        let diff_file = dir.path("diff.sql");
+        let push = PushOptions::default();
+        let mut options = ApplyOptions::default();
+        options.batching.batch_size = Some(100);
+        assert!(apply_push(&replica, &conn, changes.clone(), None, &diff_file, &push, &options).await.is_err());
+        let count = |conn: libsql::Connection| async move {
+            let mut rows = conn.query("SELECT count(*) FROM t WHERE v = 'new'", ()).await.unwrap();
+            rows.next().await.unwrap().unwrap().get::<i64>(0).unwrap()
+        };
+        let applied = count(conn.clone()).await;
+        assert!(applied > 0 && applied < 1200, "{} rows applied", applied);
+
+        // Rows pushed before the failure now match the remote; the resumed push must not
+        // drop them as already applied, or its diff no longer matches the journal
+        conn.execute("DROP TRIGGER reject", ()).await.unwrap();
+        options.resume = true;
+        apply_push(&replica, &conn, changes, None, &diff_file, &push, &options).await.unwrap();
+        assert_eq!(count(conn.clone()).await, 1200);
+        assert!(!journal::journal_path(&diff_file).exists());
+    }
+
+    #[tokio::test]
+    async fn a_replaced_working_copy_keeps_capturing_changes() {
+        let dir = temp_dir();
+        let (replica, working) = (dir.path("replica.db"), dir.path("working.db"));
+        exec(&replica, "CREATE TABLE t(id INTEGER PRIMARY KEY, v TEXT); CREATE TABLE u(id INTEGER PRIMARY KEY);").await;
+        fs::copy(&replica, &working).unwrap();
+        {
+            let (_db, conn) = open(&working).await;
+            track::install(&conn, &["t".to_string()]).await.unwrap();
+        }
+
+        replace_working_copy(&replica, &working).await.unwrap();
+        let (_db, conn) = open(&working).await;
+        assert_eq!(track::tracked_tables(&conn).await.unwrap(), vec!["t".to_string()]);
+        conn.execute("INSERT INTO t VALUES (1, 'a')", ()).await.unwrap();
+        assert_eq!(track::last_seq(&conn).await.unwrap(), 1);
+
+        // The log misses schema changes, so push notices them and diffs instead
+        assert!(!schema_changed(&replica, &conn).await.unwrap());
+        conn.execute("ALTER TABLE u ADD COLUMN v TEXT", ()).await.unwrap();
+        assert!(schema_changed(&replica, &conn).await.unwrap());
+    }
+}
@@ -0,0 +1,318 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use libsql::{Builder, OpenFlags, Value};
+use log::{debug, info};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use crate::change::{sql_literal, Change, RowChange, RowOp};
+use crate::conflict::{self, Conflict, ConflictKind, RowValues};
+use crate::diff::{compare_values, diff_databases};
+
+/// How a merge resolves a row both sides changed incompatibly
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergePolicy {
+    /// Keep the working copy's version
+    Ours,
+    /// Keep the remote's version
+    Theirs,
+    /// Keep the version with the later timestamp column; ties and deleted rows stay unresolved
+    Newest,
+    /// Resolve nothing; any conflict fails the merge
+    Fail,
+}
+
+/// Which side a conflict was resolved to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Ours,
+    Theirs,
+}
+
+/// Result of a three-way merge
+#[derive(Debug, Default)]
+pub struct Merge {
+    /// Changes that turn `theirs` into the merged state
+    pub changes: Vec<Change>,
+    /// Conflicts and the side each was resolved to; `None` when left unresolved
+    pub conflicts: Vec<(Conflict, Option<Side>)>,
+    /// Our changes that `theirs` already contains
+    pub redundant: usize,
+}
+
+impl Merge {
+    pub fn unresolved(&self) -> usize {
+        self.conflicts.iter().filter(|(_, side)| side.is_none()).count()
+    }
+
+    /// Conflicts one per line with their resolution, listing at most `limit` of them
+    pub fn report(&self, limit: usize) -> String {
+        let mut lines: Vec<String> = self
+            .conflicts
+            .iter()
+            .take(limit)
+            .map(|(conflict, side)| {
+                let resolution = match side {
+                    Some(Side::Ours) => "kept ours",
+                    Some(Side::Theirs) => "kept theirs",
+                    None => "unresolved",
+                };
+                format!("  - {} [{}]", conflict, resolution)
+            })
+            .collect();
+        if self.conflicts.len() > limit {
+            lines.push(format!("  ... and {} more", self.conflicts.len() - limit));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Three-way merge of `ours` and `theirs`, both descended from `base`.
+///
+/// Our row changes are replayed onto `theirs` at column granularity: columns only we
+/// changed are kept, columns both sides set to the same value are dropped, and
+/// everything else is a conflict resolved by `policy`. Schema changes are not merged;
+/// the merge fails if both sides changed the schema differently.
+pub async fn merge_databases(
+    base: &str,
+    ours: &str,
+    theirs: &str,
+    policy: MergePolicy,
+    timestamp_column: &str,
+) -> Result<Merge> {
+    let our_changes = diff_databases(base, ours).await?;
+    let their_changes = diff_databases(base, theirs).await?;
+    info!("Merging {} local changes onto {} remote changes", our_changes.len(), their_changes.len());
+
+    let their_schema: HashSet<&str> = their_changes
+        .iter()
+        .filter_map(|c| match c {
+            Change::Schema(sql) => Some(sql.as_str()),
+            Change::Row(_) => None,
+        })
+        .collect();
+    let their_rows: HashMap<(&str, String), &RowChange> = their_changes
+        .iter()
+        .filter_map(|c| match c {
+            Change::Row(row) => Some(((row.table.as_str(), key_string(row)), row)),
+            Change::Schema(_) => None,
+        })
+        .collect();
+
+    let our_rows = snapshot(ours, &our_changes).await?;
+    let their_now = snapshot(theirs, &our_changes).await?;
+
+    let mut merge = Merge::default();
+    for (index, change) in our_changes.iter().enumerate() {
+        let ours = match change {
+            Change::Schema(sql) if their_schema.contains(sql.as_str()) => {
+                merge.redundant += 1;
+                continue;
+            }
+            Change::Schema(sql) => {
+                if !their_schema.is_empty() {
+                    return Err(anyhow::anyhow!(
+                        "Schema changed both locally and remotely (local: {}); merge only reconciles row changes",
+                        sql
+                    ));
+                }
+                merge.changes.push(change.clone());
+                continue;
+            }
+            Change::Row(row) => row,
+        };
+        let Some(theirs) = their_rows.get(&(ours.table.as_str(), key_string(ours))) else {
+            merge.changes.push(change.clone());
+            continue;
+        };
+
+        let (merged, kind) = merge_row(ours, theirs);
+        let Some(kind) = kind else {
+            match merged {
+                Some(row) => merge.changes.push(Change::Row(row)),
+                None => merge.redundant += 1,
+            }
+            continue;
+        };
+
+        let side = match policy {
+            MergePolicy::Ours => Some(Side::Ours),
+            MergePolicy::Theirs => Some(Side::Theirs),
+            MergePolicy::Newest => newest(&our_rows[index], &their_now[index], timestamp_column),
+            MergePolicy::Fail => None,
+        };
+        debug!("Conflict on {} {}: {:?} resolved to {:?}", ours.table, key_string(ours), kind, side);
+        match side {
+            Some(Side::Ours) => merge.changes.extend(keep_ours(ours, theirs, &our_rows[index]).map(Change::Row)),
+            Some(Side::Theirs) => merge.changes.extend(merged.map(Change::Row)),
+            None => {}
+        }
+        merge
+            .conflicts
+            .push((Conflict { index, op: ours.op, table: ours.table.clone(), key: ours.key.clone(), kind }, side));
+    }
+    Ok(merge)
+}
+
+/// Merge our change to a row with theirs. Returns the part of our change that does
+/// not overlap theirs (with their values where the two disagree) and the conflict, if any.
+fn merge_row(ours: &RowChange, theirs: &RowChange) -> (Option<RowChange>, Option<ConflictKind>) {
+    match (ours.op, theirs.op) {
+        (RowOp::Delete, RowOp::Delete) => (None, None),
+        (RowOp::Delete, RowOp::Insert) => (None, Some(ConflictKind::Inserted)),
+        (RowOp::Delete, RowOp::Update) => {
+            let columns = theirs.values.iter().map(|(c, _)| c.clone()).collect();
+            (None, Some(ConflictKind::Updated(columns)))
+        }
+        (_, RowOp::Delete) => (None, Some(ConflictKind::Deleted)),
+        _ => {
+            let mut kept = ours.clone();
+            let mut conflicting = Vec::new();
+            kept.values.retain_mut(|(column, value)| match find(&theirs.values, column) {
+                None => true,
+                Some(their_value) if compare_values(value, their_value) == Ordering::Equal => ours.op == RowOp::Insert,
+                Some(their_value) => {
+                    conflicting.push(column.clone());
+                    if ours.op == RowOp::Insert {
+                        // An INSERT must stay complete; take their value instead
+                        *value = their_value.clone();
+                        true
+                    } else {
+                        false
+                    }
+                }
+            });
+            // Their INSERT already created the row, so nothing of ours is left to add
+            let redundant = match ours.op {
+                RowOp::Insert => theirs.op == RowOp::Insert,
+                _ => kept.values.is_empty(),
+            };
+            let kind = (!conflicting.is_empty()).then_some(ConflictKind::Updated(conflicting));
+            ((!redundant).then_some(kept), kind)
+        }
+    }
+}
+
+/// Our side of a conflicting row: our change as-is, our values over their inserted
+/// row, or our whole row again when theirs deleted it
+fn keep_ours(ours: &RowChange, theirs: &RowChange, our_row: &Option<RowValues>) -> Option<RowChange> {
+    match (ours.op, theirs.op) {
+        (RowOp::Insert, RowOp::Insert) => Some(RowChange { op: RowOp::Update, ..ours.clone() }),
+        (RowOp::Update, RowOp::Delete) => {
+            let row = our_row.as_ref()?;
+            Some(RowChange {
+                table: ours.table.clone(),
+                op: RowOp::Insert,
+                key: ours.key.clone(),
+                values: row.iter().filter(|(c, _)| find(&ours.key, c).is_none()).cloned().collect(),
+            })
+        }
+        _ => Some(ours.clone()),
+    }
+}
+
+/// Side whose row has the later non-NULL timestamp
+fn newest(ours: &Option<RowValues>, theirs: &Option<RowValues>, column: &str) -> Option<Side> {
+    let ours = ours.as_ref().and_then(|row| find(row, column)).filter(|v| **v != Value::Null)?;
+    let theirs = theirs.as_ref().and_then(|row| find(row, column)).filter(|v| **v != Value::Null)?;
+    match compare_values(ours, theirs) {
+        Ordering::Greater => Some(Side::Ours),
+        Ordering::Less => Some(Side::Theirs),
+        Ordering::Equal => None,
+    }
+}
+
+async fn snapshot(path: &str, changes: &[Change]) -> Result<conflict::RowSnapshot> {
+    let db = Builder::new_local(path)
+        .flags(OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .build()
+        .await
+        .with_context(|| format!("Failed to open {}", path))?;
+    conflict::snapshot_rows(&db.connect()?, changes).await
+}
+
+fn find<'a>(values: &'a RowValues, column: &str) -> Option<&'a Value> {
+    values.iter().find(|(c, _)| c.eq_ignore_ascii_case(column)).map(|(_, v)| v)
+}
+
+fn key_string(row: &RowChange) -> String {
+    row.key.iter().map(|(_, v)| sql_literal(v)).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{apply, exec, open, temp_dir};
+
+    async fn contacts(path: &str) -> String {
+        let (_db, conn) = open(path).await;
+        let mut rows =
+            conn.query("SELECT group_concat(id || ':' || email || ':' || updated_at, ' ') FROM c", ()).await.unwrap();
+        rows.next().await.unwrap().unwrap().get(0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn merges_columns_and_resolves_conflicts_by_policy() {
+        let dir = temp_dir();
+        let (base, ours, theirs) = (dir.path("base.db"), dir.path("ours.db"), dir.path("theirs.db"));
+        let schema = "CREATE TABLE c(id INTEGER PRIMARY KEY, email TEXT, phone TEXT, updated_at TEXT);
+                      INSERT INTO c VALUES (1, 'a', '1', 't0'), (2, 'b', '2', 't0'), (3, 'c', '3', 't0'), (4, 'd', '4', 't0');";
+        exec(&base, schema).await;
+        exec(&ours, schema).await;
+        exec(&theirs, schema).await;
+        exec(
+            &ours,
+            "UPDATE c SET email = 'A', updated_at = 't1' WHERE id = 1;
+             UPDATE c SET email = 'B', updated_at = 't2' WHERE id = 2;
+             UPDATE c SET email = 'C', updated_at = 't1' WHERE id = 3;
+             DELETE FROM c WHERE id = 4;",
+        )
+        .await;
+        exec(
+            &theirs,
+            "UPDATE c SET phone = '9' WHERE id = 1;
+             UPDATE c SET email = 'x', updated_at = 't1' WHERE id = 2;
+             UPDATE c SET email = 'y', updated_at = 't3' WHERE id = 3;",
+        )
+        .await;
+
+        let failed = merge_databases(&base, &ours, &theirs, MergePolicy::Fail, "updated_at").await.unwrap();
+        assert_eq!(failed.unresolved(), 2);
+        let report: Vec<String> = failed.conflicts.iter().map(|(c, _)| c.to_string()).collect();
+        assert_eq!(
+            report,
+            vec![
+                "UPDATE c id=2: email, updated_at changed remotely",
+                "UPDATE c id=3: email, updated_at changed remotely"
+            ]
+        );
+
+        let newest = merge_databases(&base, &ours, &theirs, MergePolicy::Newest, "updated_at").await.unwrap();
+        assert_eq!(newest.unresolved(), 0);
+        apply(&theirs, &newest.changes).await;
+        // Row 1 merges both sides, row 2 keeps ours (t2 > t1), row 3 keeps theirs (t3 > t1)
+        assert_eq!(contacts(&theirs).await, "1:A:t1 2:B:t2 3:y:t3");
+        let (_db, conn) = open(&theirs).await;
+        let mut rows = conn.query("SELECT phone FROM c WHERE id = 1", ()).await.unwrap();
+        assert_eq!(rows.next().await.unwrap().unwrap().get::<String>(0).unwrap(), "9");
+    }
+
+    #[tokio::test]
+    async fn keeping_ours_restores_a_row_deleted_remotely() {
+        let dir = temp_dir();
+        let (base, ours, theirs) = (dir.path("base.db"), dir.path("ours.db"), dir.path("theirs.db"));
+        let schema = "CREATE TABLE c(id INTEGER PRIMARY KEY, email TEXT, updated_at TEXT);
+                      INSERT INTO c VALUES (1, 'a', 't0'), (2, 'b', 't0');";
+        exec(&base, schema).await;
+        exec(&ours, &format!("{} UPDATE c SET email = 'A' WHERE id = 1;", schema)).await;
+        exec(&theirs, &format!("{} DELETE FROM c WHERE id = 1;", schema)).await;
+
+        let merge = merge_databases(&base, &ours, &theirs, MergePolicy::Theirs, "updated_at").await.unwrap();
+        assert_eq!(merge.conflicts[0].0.kind, ConflictKind::Deleted);
+        assert!(merge.changes.is_empty());
+
+        let merge = merge_databases(&base, &ours, &theirs, MergePolicy::Ours, "updated_at").await.unwrap();
+        apply(&theirs, &merge.changes).await;
+        assert_eq!(contacts(&theirs).await, "1:A:t0 2:b:t0");
+    }
+}
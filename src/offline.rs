@@ -0,0 +1,124 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use rusqlite::OpenFlags;
+use serde::Deserialize;
+use std::fs;
+use std::os::raw::c_uint;
+use std::path::Path;
+
+/// Which way `offline-sync` moves frames
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncDirection {
+    /// Pull remote frames only; refuses to run while local frames are unpushed
+    Pull,
+    /// Push local frames only; never pulls
+    Push,
+    /// Push local frames, then pull remote ones
+    Both,
+}
+
+/// Sync position libsql keeps in `<db>-info` for a synced database
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct SyncPosition {
+    pub generation: u32,
+    /// Last frame known to be durable on the remote
+    pub durable_frame_num: u32,
+}
+
+impl SyncPosition {
+    /// Position recorded for `db_path`, or `None` before its first sync
+    pub fn load(db_path: &str) -> Result<Option<SyncPosition>> {
+        let path = format!("{}-info", db_path);
+        if !Path::new(&path).exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(&path).with_context(|| format!("Failed to read sync metadata {}", path))?;
+        let position = serde_json::from_str(&content).with_context(|| {
+            format!("Failed to parse sync metadata {} (was the database created as an embedded replica?)", path)
+        })?;
+        Ok(Some(position))
+    }
+
+    /// Frames pulled to get from `before` to `after`; a new generation starts counting from zero
+    pub fn frames_since(after: Option<SyncPosition>, before: Option<SyncPosition>) -> u32 {
+        match (before, after) {
+            (_, None) => 0,
+            (Some(before), Some(after)) if before.generation == after.generation => {
+                after.durable_frame_num.saturating_sub(before.durable_frame_num)
+            }
+            (_, Some(after)) => after.durable_frame_num,
+        }
+    }
+}
+
+/// Frames in the WAL of `db_path`, counted by libsql itself: the last frame of its
+/// latest commit, which is what a sync compares with the durable position to decide
+/// between pushing and pulling.
+///
+/// SQLite is opened through rusqlite here, so libsql must have initialized it first.
+pub fn wal_frame_count(db_path: &str) -> Result<u32> {
+    if !Path::new(db_path).exists() {
+        return Ok(0);
+    }
+    let conn = rusqlite::Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .with_context(|| format!("Failed to open {}", db_path))?;
+    // A read opens the WAL and loads its header; until then there is nothing to count
+    conn.query_row("PRAGMA schema_version", [], |_| Ok(())).with_context(|| format!("Failed to read {}", db_path))?;
+    let mut frames: c_uint = 0;
+    // SAFETY: the handle belongs to `conn`, which outlives the call
+    let rc = unsafe { libsql_ffi::libsql_wal_frame_count(conn.handle(), &mut frames) };
+    if rc != libsql_ffi::SQLITE_OK {
+        anyhow::bail!("Failed to count the WAL frames of {} (error {})", db_path, rc);
+    }
+    Ok(frames)
+}
+
+/// Local frames of a synced database that the remote does not have yet; a sync pushes
+/// exactly when this is not zero
+pub fn frames_ahead(db_path: &str) -> Result<u32> {
+    let durable = SyncPosition::load(db_path)?.map_or(0, |p| p.durable_frame_num);
+    Ok(wal_frame_count(db_path)?.saturating_sub(durable))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{open, temp_dir};
+
+    #[tokio::test]
+    async fn counts_frames_ahead_of_the_durable_position() {
+        let dir = temp_dir();
+        let db = dir.path("synced.db");
+        assert_eq!(frames_ahead(&db).unwrap(), 0);
+
+        let (_db, conn) = open(&db).await;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL; PRAGMA wal_autocheckpoint = 0;
+             CREATE TABLE t(x);
+             INSERT INTO t VALUES (zeroblob(100000));",
+        )
+        .await
+        .unwrap();
+        let frames = wal_frame_count(&db).unwrap();
+        assert!(frames > 20, "{} frames", frames);
+
+        // A restarted WAL keeps its size, but only the frames written since count
+        let mut rows = conn.query("PRAGMA wal_checkpoint(RESTART)", ()).await.unwrap();
+        while rows.next().await.unwrap().is_some() {}
+        drop(rows);
+        conn.execute("INSERT INTO t VALUES (1)", ()).await.unwrap();
+        let wal_bytes = fs::metadata(format!("{}-wal", db)).unwrap().len();
+        assert!(wal_bytes > 20 * 4096, "{} bytes", wal_bytes);
+        assert_eq!(frames_ahead(&db).unwrap(), 1);
+
+        fs::write(format!("{}-info", db), r#"{"hash":1,"version":0,"durable_frame_num":1,"generation":1}"#).unwrap();
+        let position = SyncPosition::load(&db).unwrap();
+        assert_eq!(position, Some(SyncPosition { generation: 1, durable_frame_num: 1 }));
+        assert_eq!(frames_ahead(&db).unwrap(), 0);
+
+        let later = Some(SyncPosition { generation: 1, durable_frame_num: 7 });
+        assert_eq!(SyncPosition::frames_since(later, position), 6);
+        let next_generation = Some(SyncPosition { generation: 2, durable_frame_num: 2 });
+        assert_eq!(SyncPosition::frames_since(next_generation, position), 2);
+    }
+}
@@ -0,0 +1,159 @@
+use anyhow::{Context, Result};
+use libsql::params::Params;
+use libsql::{Connection, Statement, Value};
+use std::collections::HashMap;
+
+/// An SQL statement with `?` placeholders and the values bound to them
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoundStatement {
+    pub sql: String,
+    pub params: Vec<Value>,
+}
+
+impl BoundStatement {
+    /// A statement without parameters, executed as-is
+    pub fn raw(sql: impl Into<String>) -> Self {
+        BoundStatement { sql: sql.into(), params: Vec::new() }
+    }
+
+    /// Approximate request payload: the SQL text plus the bound values
+    pub fn size(&self) -> usize {
+        self.sql.len() + self.params.iter().map(value_size).sum::<usize>()
+    }
+}
+
+/// Approximate encoded size of a bound value
+pub fn value_size(value: &Value) -> usize {
+    match value {
+        Value::Null => 1,
+        Value::Integer(_) | Value::Real(_) => 8,
+        Value::Text(s) => s.len(),
+        Value::Blob(bytes) => bytes.len(),
+    }
+}
+
+/// Prepared statements of one connection, reused by every execution with the same SQL.
+///
+/// Remote transactions run on their own stream, so a cache must be created from the
+/// transaction it executes in and dropped before that transaction ends.
+pub struct StatementCache<'c> {
+    conn: &'c Connection,
+    statements: HashMap<String, Statement>,
+    /// Requests sent to the database: preparations and executions
+    requests: usize,
+}
+
+impl<'c> StatementCache<'c> {
+    pub fn new(conn: &'c Connection) -> Self {
+        StatementCache { conn, statements: HashMap::new(), requests: 0 }
+    }
+
+    /// Execute a statement, preparing its SQL on first use. Statements without
+    /// parameters (schema changes) are executed directly and not cached.
+    pub async fn execute(&mut self, statement: &BoundStatement) -> Result<usize> {
+        if statement.params.is_empty() {
+            self.requests += 1;
+            let changed = self.conn.execute(&statement.sql, ()).await?;
+            return Ok(changed as usize);
+        }
+
+        let prepared = match self.statements.get(&statement.sql) {
+            Some(prepared) => prepared,
+            None => {
+                self.requests += 1;
+                let prepared = self
+                    .conn
+                    .prepare(&statement.sql)
+                    .await
+                    .with_context(|| format!("Failed to prepare {}", statement.sql))?;
+                self.statements.entry(statement.sql.clone()).or_insert(prepared)
+            }
+        };
+        self.requests += 1;
+        let result = prepared.execute(Params::Positional(statement.params.clone())).await;
+        prepared.reset();
+        Ok(result?)
+    }
+
+    /// Execute statements in order, stopping at the first failure
+    pub async fn execute_all(&mut self, statements: &[BoundStatement]) -> Result<()> {
+        for statement in statements {
+            self.execute(statement).await?;
+        }
+        Ok(())
+    }
+
+    /// Number of distinct statements prepared so far
+    pub fn prepared(&self) -> usize {
+        self.statements.len()
+    }
+
+    /// Number of requests sent so far, each a round trip on a remote connection
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{exec, open, temp_dir};
+
+    #[tokio::test]
+    async fn blob_and_real_values_round_trip_through_one_statement() {
+        let dir = temp_dir();
+        let path = dir.path("t.db");
+        exec(&path, "CREATE TABLE t(id INTEGER PRIMARY KEY, data BLOB, score REAL);").await;
+
+        let values = [
+            (Value::Blob(vec![0, 0x27, 0xff]), Value::Real(0.1 + 0.2)),
+            (Value::Blob(Vec::new()), Value::Real(-1e-300)),
+            (Value::Null, Value::Real(f64::MAX)),
+        ];
+        let (_db, conn) = open(&path).await;
+        let mut cache = StatementCache::new(&conn);
+        for (id, (data, score)) in values.iter().enumerate() {
+            let statement = BoundStatement {
+                sql: "INSERT INTO t(id,data,score) VALUES(?,?,?)".to_string(),
+                params: vec![Value::Integer(id as i64), data.clone(), score.clone()],
+            };
+            assert_eq!(cache.execute(&statement).await.unwrap(), 1);
+        }
+        // One request prepares the SQL, then one per execution with its values bound
+        assert_eq!((cache.prepared(), cache.requests()), (1, 4));
+
+        let mut rows = conn.query("SELECT data, score FROM t ORDER BY id", ()).await.unwrap();
+        for (data, score) in &values {
+            let row = rows.next().await.unwrap().unwrap();
+            assert_eq!((&row.get_value(0).unwrap(), &row.get_value(1).unwrap()), (data, score));
+        }
+    }
+
+    #[tokio::test]
+    async fn a_batch_prepares_each_distinct_statement_once() {
+        let dir = temp_dir();
+        let path = dir.path("t.db");
+        exec(&path, "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT);").await;
+        let mut batch = vec![BoundStatement::raw("CREATE INDEX t_name ON t(name)")];
+        batch.extend((1..=100).map(|id| BoundStatement {
+            sql: "INSERT INTO t(id,name) VALUES(?,?)".to_string(),
+            params: vec![Value::Integer(id), Value::Text("it's".to_string())],
+        }));
+        batch.extend((1..=100).map(|id| BoundStatement {
+            sql: "UPDATE t SET name=? WHERE id=?".to_string(),
+            params: vec![Value::Blob(vec![0x27, id as u8]), Value::Integer(id)],
+        }));
+
+        let (_db, conn) = open(&path).await;
+        let tx = conn.transaction().await.unwrap();
+        let mut cache = StatementCache::new(&tx);
+        cache.execute_all(&batch).await.unwrap();
+        // The schema statement runs unprepared; two prepares, then 200 bound executions
+        assert_eq!((cache.prepared(), cache.requests()), (2, 203));
+        drop(cache);
+        tx.commit().await.unwrap();
+
+        let mut rows = conn.query("SELECT count(*) FROM t WHERE name = X'2764'", ()).await.unwrap();
+        assert_eq!(rows.next().await.unwrap().unwrap().get::<i64>(0).unwrap(), 1);
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use libsql::{Connection, Value};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::change::{quote_ident, sql_literal, Change, RowOp};
+
+/// Largest number of key values looked up by one query
+const MAX_KEY_VALUES_PER_QUERY: usize = 999;
+
+/// Rows a diff must not update or delete: those of `table` for which `condition`,
+/// an SQL expression over the row's columns, is true
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtectRule {
+    pub table: String,
+    pub condition: String,
+}
+
+impl FromStr for ProtectRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        match rule.split_once(':') {
+            Some((table, condition)) if !table.trim().is_empty() && !condition.trim().is_empty() => {
+                Ok(ProtectRule { table: table.trim().to_string(), condition: condition.trim().to_string() })
+            }
+            _ => Err(format!("expected TABLE:CONDITION, got '{}'", rule)),
+        }
+    }
+}
+
+impl fmt::Display for ProtectRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.table, self.condition)
+    }
+}
+
+/// What happens to changes that would modify protected rows
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtectAction {
+    /// Apply nothing and report them
+    Reject,
+    /// Leave them out, apply the rest and report them
+    Drop,
+}
+
+/// Row protection rules checked against the replica before UPDATEs and DELETEs
+#[derive(Args, Debug, Clone)]
+pub struct ProtectOptions {
+    /// Rows diffs may not update or delete, as TABLE:CONDITION on the row before the apply; repeatable
+    #[arg(
+        long = "protect",
+        value_name = "TABLE:CONDITION",
+        default_value = "email_schedules:status IN ('sent','delivered')",
+        env = "TURSO_SYNC_PROTECT",
+        value_delimiter = ';'
+    )]
+    pub rules: Vec<ProtectRule>,
+
+    /// What to do with changes to protected rows: reject the whole diff or drop just them
+    #[arg(long, value_enum, default_value = "reject")]
+    pub on_protected: ProtectAction,
+
+    /// Do not check the row protection rules
+    #[arg(long)]
+    pub no_protect: bool,
+}
+
+impl Default for ProtectOptions {
+    fn default() -> Self {
+        ProtectOptions {
+            rules: vec!["email_schedules:status IN ('sent','delivered')".parse().expect("default rule")],
+            on_protected: ProtectAction::Reject,
+            no_protect: false,
+        }
+    }
+}
+
+/// A change that would update or delete a protected row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Protected {
+    /// Position of the change in the diff
+    pub index: usize,
+    pub op: RowOp,
+    pub table: String,
+    pub key: Vec<(String, Value)>,
+    pub rule: ProtectRule,
+}
+
+impl fmt::Display for Protected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = if self.op == RowOp::Delete { "DELETE" } else { "UPDATE" };
+        let key: Vec<String> = self.key.iter().map(|(c, v)| format!("{}={}", c, sql_literal(v))).collect();
+        write!(f, "{} {} {}: row matches {}", op, self.table, key.join(" AND "), self.rule.condition)
+    }
+}
+
+/// Find the UPDATEs and DELETEs in `changes` whose row, as it is in `conn`, matches
+/// one of `rules`. Rules for tables `conn` does not have are ignored.
+pub async fn find_protected(conn: &Connection, changes: &[Change], rules: &[ProtectRule]) -> Result<Vec<Protected>> {
+    let mut protected: Vec<Protected> = Vec::new();
+    let mut seen = HashSet::new();
+
+    for rule in rules {
+        if !table_exists(conn, &rule.table).await? {
+            continue;
+        }
+        // Changes to the rule's table, grouped by key columns
+        let mut groups: BTreeMap<Vec<&str>, Vec<usize>> = BTreeMap::new();
+        for (index, change) in changes.iter().enumerate() {
+            if let Change::Row(row) = change {
+                if row.op != RowOp::Insert && !row.key.is_empty() && row.table.eq_ignore_ascii_case(&rule.table) {
+                    groups.entry(row.key.iter().map(|(c, _)| c.as_str()).collect()).or_default().push(index);
+                }
+            }
+        }
+
+        for (key, indices) in groups {
+            let key_columns: Vec<String> = key.iter().map(|c| quote_ident(c)).collect();
+            for chunk in indices.chunks((MAX_KEY_VALUES_PER_QUERY / key.len()).max(1)) {
+                let tuple = format!("({})", vec!["?"; key.len()].join(","));
+                let sql = format!(
+                    "SELECT {} FROM {} WHERE ({}) IN (VALUES{}) AND ({})",
+                    key_columns.join(", "),
+                    quote_ident(&rule.table),
+                    key_columns.join(", "),
+                    vec![tuple.as_str(); chunk.len()].join(","),
+                    rule.condition
+                );
+                let params: Vec<Value> = chunk.iter().flat_map(|&i| row_key(&changes[i])).collect();
+                let mut rows = conn
+                    .query(&sql, params)
+                    .await
+                    .with_context(|| format!("Failed to check protection rule {}", rule))?;
+                let mut matched = HashSet::new();
+                while let Some(row) = rows.next().await? {
+                    let values = (0..key.len()).map(|i| row.get_value(i as i32)).collect::<libsql::Result<Vec<_>>>()?;
+                    matched.insert(key_string(&values));
+                }
+
+                for &index in chunk {
+                    let Change::Row(row) = &changes[index] else { continue };
+                    if matched.contains(&key_string(&row_key(&changes[index]))) && seen.insert(index) {
+                        protected.push(Protected {
+                            index,
+                            op: row.op,
+                            table: row.table.clone(),
+                            key: row.key.clone(),
+                            rule: rule.clone(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+    protected.sort_by_key(|p| p.index);
+    Ok(protected)
+}
+
+/// Protected changes one per line, listing at most `limit` of them
+pub fn report(protected: &[Protected], limit: usize) -> String {
+    let mut lines: Vec<String> = protected.iter().take(limit).map(|p| format!("  - {}", p)).collect();
+    if protected.len() > limit {
+        lines.push(format!("  ... and {} more", protected.len() - limit));
+    }
+    lines.join("\n")
+}
+
+async fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    let mut rows = conn
+        .query("SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = ? COLLATE NOCASE", [table])
+        .await
+        .context("Failed to list tables")?;
+    Ok(rows.next().await?.is_some())
+}
+
+fn row_key(change: &Change) -> Vec<Value> {
+    match change {
+        Change::Row(row) => row.key.iter().map(|(_, v)| v.clone()).collect(),
+        Change::Schema(_) => Vec::new(),
+    }
+}
+
+fn key_string(values: &[Value]) -> String {
+    values.iter().map(sql_literal).collect::<Vec<_>>().join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{exec, open, temp_dir};
+
+    #[tokio::test]
+    async fn finds_updates_and_deletes_of_protected_rows() {
+        let dir = temp_dir();
+        let path = dir.path("replica.db");
+        exec(
+            &path,
+            "CREATE TABLE email_schedules(id INTEGER PRIMARY KEY, status TEXT);
+             INSERT INTO email_schedules VALUES (1, 'sent'), (2, 'scheduled'), (3, 'delivered'), (4, 'skipped');",
+        )
+        .await;
+        let changes: Vec<Change> = [
+            "UPDATE email_schedules SET status='scheduled' WHERE id=1",
+            "UPDATE email_schedules SET status='skipped' WHERE id=2",
+            "DELETE FROM email_schedules WHERE id=3",
+            "DELETE FROM email_schedules WHERE id=4",
+            "INSERT INTO email_schedules(id,status) VALUES(5,'sent')",
+        ]
+        .iter()
+        .map(|s| Change::parse(s))
+        .collect();
+
+        let (_db, conn) = open(&path).await;
+        let rules = vec![ProtectOptions::default().rules.remove(0), "contacts: locked = 1".parse().unwrap()];
+        let protected = find_protected(&conn, &changes, &rules).await.unwrap();
+        let found: Vec<(usize, RowOp)> = protected.iter().map(|p| (p.index, p.op)).collect();
+        assert_eq!(found, vec![(0, RowOp::Update), (2, RowOp::Delete)]);
+        assert_eq!(protected[1].to_string(), "DELETE email_schedules id=3: row matches status IN ('sent','delivered')");
+
+        assert!("no condition".parse::<ProtectRule>().is_err());
+        let bad: ProtectRule = "email_schedules: no_such_column = 1".parse().unwrap();
+        assert!(find_protected(&conn, &changes, &[bad]).await.is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use anyhow::Result;
+use clap::Args;
+use log::{info, warn};
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::shutdown;
+
+/// Policy every remote operation retries with, set once from the command line
+static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+
+/// Operations that needed at least one retry, and retries made in total
+static RETRIED_OPERATIONS: AtomicUsize = AtomicUsize::new(0);
+static RETRIES: AtomicUsize = AtomicUsize::new(0);
+
+/// How transient remote failures are retried
+#[derive(Args, Debug, Clone)]
+pub struct RetryOptions {
+    /// Attempts per remote operation (connecting, each batch, each sync); 1 disables retries
+    #[arg(long, global = true, default_value = "5", env = "TURSO_SYNC_RETRY_ATTEMPTS")]
+    pub retry_attempts: u32,
+
+    /// Delay before the first retry in milliseconds, doubled for each further one
+    #[arg(long, global = true, default_value = "200", env = "TURSO_SYNC_RETRY_BASE_MS")]
+    pub retry_base_ms: u64,
+
+    /// Longest delay between two attempts in milliseconds
+    #[arg(long, global = true, default_value = "10000", env = "TURSO_SYNC_RETRY_MAX_MS")]
+    pub retry_max_ms: u64,
+
+    /// Share of each delay that is random, from 0 (fixed delays) to 1
+    #[arg(long, global = true, default_value = "0.5", env = "TURSO_SYNC_RETRY_JITTER")]
+    pub retry_jitter: f64,
+}
+
+/// Exponential backoff with jitter for errors [`is_retryable`] accepts
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_millis(200),
+            max_delay: Duration::from_secs(10),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl From<&RetryOptions> for RetryPolicy {
+    fn from(options: &RetryOptions) -> Self {
+        RetryPolicy {
+            max_attempts: options.retry_attempts.max(1),
+            base_delay: Duration::from_millis(options.retry_base_ms),
+            max_delay: Duration::from_millis(options.retry_max_ms),
+            jitter: options.retry_jitter.clamp(0.0, 1.0),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `retry` (1 for the second attempt): the base delay
+    /// doubled per retry and capped, with its jittered share drawn at random
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(2u32.saturating_pow(retry.saturating_sub(1))).min(self.max_delay);
+        let random = RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64;
+        delay.mul_f64(1.0 - self.jitter * random)
+    }
+
+    /// How long to wait before attempt `attempt + 1` after `error`, or `None` when
+    /// the error is permanent, the attempts are used up or a shutdown was requested.
+    /// Retries are logged and counted.
+    pub fn backoff(&self, what: &str, attempt: u32, error: &anyhow::Error) -> Option<Duration> {
+        if attempt >= self.max_attempts || shutdown::requested() || !is_retryable(error) {
+            return None;
+        }
+        if attempt == 1 {
+            RETRIED_OPERATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        RETRIES.fetch_add(1, Ordering::Relaxed);
+        let delay = self.delay(attempt);
+        warn!(
+            "{} failed (attempt {}/{}): {:#}; retrying in {} ms",
+            what,
+            attempt,
+            self.max_attempts,
+            error,
+            delay.as_millis()
+        );
+        Some(delay)
+    }
+
+    /// Run `op` until it succeeds, fails permanently or runs out of attempts
+    pub async fn run<T, F, Fut>(&self, what: &str, mut op: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(error) => match self.backoff(what, attempt, &error) {
+                    Some(delay) => tokio::time::sleep(delay).await,
+                    None => return Err(error),
+                },
+            }
+            attempt += 1;
+        }
+    }
+}
+
+/// Use `options` for every later remote operation
+pub fn configure(options: &RetryOptions) {
+    let _ = POLICY.set(RetryPolicy::from(options));
+}
+
+/// The configured policy, or the defaults when none was set
+pub fn policy() -> &'static RetryPolicy {
+    POLICY.get_or_init(RetryPolicy::default)
+}
+
+/// Run `op` with the configured policy
+pub async fn with_retry<T, F, Fut>(what: &str, op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    policy().run(what, op).await
+}
+
+/// Log how many remote operations needed retries, if any did
+pub fn log_summary() {
+    let retries = RETRIES.load(Ordering::Relaxed);
+    if retries > 0 {
+        info!(
+            "Retry summary: {} remote operations were retried, {} retries in total",
+            RETRIED_OPERATIONS.load(Ordering::Relaxed),
+            retries
+        );
+    }
+}
+
+/// Whether an error is likely to go away when the operation is repeated: network
+/// failures, timeouts, overloaded servers and locked databases. SQL errors such as
+/// constraint violations, and authentication failures, are permanent.
+pub fn is_retryable(error: &anyhow::Error) -> bool {
+    for cause in error.chain() {
+        if cause.downcast_ref::<std::io::Error>().is_some() {
+            return true;
+        }
+        if let Some(error) = cause.downcast_ref::<libsql::Error>() {
+            return match error {
+                libsql::Error::ConnectionFailed(_) => true,
+                // SQLITE_BUSY and SQLITE_LOCKED, including their extended codes
+                libsql::Error::SqliteFailure(code, _) => matches!(code & 0xff, 5 | 6),
+                libsql::Error::RemoteSqliteFailure(code, _, _) => matches!(code & 0xff, 5 | 6),
+                libsql::Error::Hrana(_)
+                | libsql::Error::Replication(_)
+                | libsql::Error::Sync(_)
+                | libsql::Error::WriteDelegation(_) => transient_message(&error.to_string()),
+                _ => false,
+            };
+        }
+    }
+    transient_message(&format!("{:#}", error))
+}
+
+fn transient_message(message: &str) -> bool {
+    const PERMANENT: [&str; 5] = ["401", "403", "unauthorized", "forbidden", "constraint"];
+    const TRANSIENT: [&str; 16] = [
+        "timed out",
+        "timeout",
+        "connection reset",
+        "connection refused",
+        "connection closed",
+        "broken pipe",
+        "stream closed",
+        "error sending request",
+        "temporarily unavailable",
+        "too many requests",
+        "database is locked",
+        "429",
+        "500",
+        "502",
+        "503",
+        "504",
+    ];
+    let message = message.to_lowercase();
+    !PERMANENT.iter().any(|p| message.contains(p)) && TRANSIENT.iter().any(|t| message.contains(t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[tokio::test]
+    async fn retries_transient_errors_with_growing_delays() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(3),
+            jitter: 0.0,
+        };
+        assert_eq!((1..=4).map(|retry| policy.delay(retry).as_millis()).collect::<Vec<_>>(), vec![1, 2, 3, 3]);
+        let jittered = RetryPolicy { jitter: 1.0, base_delay: Duration::from_secs(1), ..policy.clone() };
+        assert!(jittered.delay(1) <= Duration::from_secs(1));
+
+        // Fails twice with a timeout, then succeeds on the last attempt
+        let attempts = Cell::new(0);
+        let result = policy
+            .run("batch", || async {
+                attempts.set(attempts.get() + 1);
+                match attempts.get() {
+                    1 => Err(anyhow::Error::new(libsql::Error::Hrana("http error: `operation timed out`".into()))),
+                    2 => Err(anyhow::Error::new(libsql::Error::SqliteFailure(517, "database is locked".into()))),
+                    n => Ok(n),
+                }
+            })
+            .await;
+        assert_eq!(result.unwrap(), 3);
+
+        // Permanent errors fail at once, transient ones once the attempts are used up
+        attempts.set(0);
+        let result: Result<()> = policy
+            .run("batch", || async {
+                attempts.set(attempts.get() + 1);
+                Err(anyhow::Error::new(libsql::Error::SqliteFailure(19, "UNIQUE constraint failed".into())))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+        attempts.set(0);
+        let result: Result<()> = policy
+            .run("sync", || async {
+                attempts.set(attempts.get() + 1);
+                Err(anyhow::anyhow!("server returned 503 Service Unavailable"))
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 3);
+        assert!(!is_retryable(&anyhow::anyhow!("Hrana: `api error: 401 Unauthorized`")));
+    }
+}
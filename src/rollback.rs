@@ -0,0 +1,190 @@
+use anyhow::{Context, Result};
+use libsql::{Connection, Value};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::change::{quote_ident, Change, RowOp};
+use crate::changeset::{self, ChangesetRow, DiffFormat};
+use crate::conflict::{RowSnapshot, RowValues};
+use crate::diff::compare_values;
+
+/// Changeset undoing an applied diff
+#[derive(Debug)]
+pub struct Inverse {
+    /// The changeset; its old values are the rows as the diff left them, which
+    /// `rollback` checks the target against before applying it
+    pub data: Vec<u8>,
+    pub rows: usize,
+    /// Changes that cannot be undone: schema statements and rows of tables without
+    /// a PRIMARY KEY, which changesets cannot address
+    pub skipped: usize,
+}
+
+/// Inverse changeset path for a diff file: `diff.sql` -> `diff.sql.inverse`
+pub fn inverse_path(diff_file: &str) -> String {
+    format!("{}.inverse", diff_file)
+}
+
+/// Build the changeset that undoes `changes`, given the rows they touch as they are
+/// before the apply (`before`, aligned with the changes). Deleted rows are inserted
+/// again, updated columns get their old values back and inserted rows are deleted,
+/// in reverse order of the diff.
+pub async fn invert(conn: &Connection, changes: &[Change], before: &RowSnapshot) -> Result<Inverse> {
+    let mut layouts: HashMap<String, Layout> = HashMap::new();
+    let mut rows = Vec::new();
+    let mut skipped = 0;
+
+    for (change, before) in changes.iter().zip(before).rev() {
+        let Change::Row(row) = change else {
+            skipped += 1;
+            continue;
+        };
+        let table = row.table.to_lowercase();
+        if !layouts.contains_key(&table) {
+            layouts.insert(table.clone(), Layout::read(conn, &row.table).await?);
+        }
+        let layout = &layouts[&table];
+        if !layout.pk.iter().any(|&pk| pk != 0) {
+            skipped += 1;
+            continue;
+        }
+
+        // The row as the change leaves it: every column for INSERT, the changed ones for UPDATE
+        let after: Option<RowValues> =
+            (row.op != RowOp::Delete).then(|| row.key.iter().chain(&row.values).cloned().collect());
+        let columns = layout.columns.len();
+        let mut inverse = ChangesetRow {
+            table: row.table.clone(),
+            op: RowOp::Update,
+            pk: layout.pk.clone(),
+            old: vec![None; columns],
+            new: vec![None; columns],
+        };
+        match (before, &after) {
+            (None, None) => continue,
+            (None, Some(after)) => {
+                inverse.op = RowOp::Delete;
+                inverse.old = layout.record(after);
+            }
+            (Some(before), None) => {
+                inverse.op = RowOp::Insert;
+                inverse.new = layout.record(before);
+            }
+            (Some(before), Some(after)) => {
+                let mut changed = false;
+                for (i, column) in layout.columns.iter().enumerate() {
+                    if layout.pk[i] != 0 {
+                        inverse.old[i] = value(after, column).or_else(|| value(before, column)).cloned();
+                        continue;
+                    }
+                    let Some(new) = value(after, column) else { continue };
+                    let old = value(before, column).cloned().unwrap_or(Value::Null);
+                    if compare_values(new, &old) != Ordering::Equal {
+                        inverse.old[i] = Some(new.clone());
+                        inverse.new[i] = Some(old);
+                        changed = true;
+                    }
+                }
+                if !changed {
+                    continue;
+                }
+            }
+        }
+        rows.push(inverse);
+    }
+
+    Ok(Inverse { data: changeset::encode(&rows, DiffFormat::Changeset), rows: rows.len(), skipped })
+}
+
+/// Columns of a table with their position in its PRIMARY KEY, as changesets record them
+struct Layout {
+    columns: Vec<String>,
+    pk: Vec<u8>,
+}
+
+impl Layout {
+    async fn read(conn: &Connection, table: &str) -> Result<Layout> {
+        let mut rows = conn
+            .query(&format!("PRAGMA table_info({})", quote_ident(table)), ())
+            .await
+            .with_context(|| format!("Failed to read columns of {}", table))?;
+        let (mut columns, mut pk) = (Vec::new(), Vec::new());
+        while let Some(row) = rows.next().await? {
+            columns.push(row.get::<String>(1)?);
+            pk.push(row.get::<i64>(5)? as u8);
+        }
+        Ok(Layout { columns, pk })
+    }
+
+    /// Values of `row` in column order, `None` for columns it does not have
+    fn record(&self, row: &RowValues) -> Vec<Option<Value>> {
+        self.columns.iter().map(|column| value(row, column).cloned()).collect()
+    }
+}
+
+fn value<'a>(row: &'a RowValues, column: &str) -> Option<&'a Value> {
+    row.iter().find(|(c, _)| c.eq_ignore_ascii_case(column)).map(|(_, v)| v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conflict::{self, ConflictCheck, ConflictPolicy};
+    use crate::diff::diff_databases;
+    use crate::testutil::{apply, exec, open, temp_dir};
+
+    #[tokio::test]
+    async fn inverse_restores_the_rows_a_diff_changed() {
+        let dir = temp_dir();
+        let (replica, working, target) = (dir.path("replica.db"), dir.path("working.db"), dir.path("target.db"));
+        exec(
+            &replica,
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, score REAL, data BLOB);
+             INSERT INTO t VALUES (1, 'a', 0.1, X'00'), (2, 'b', 2.5, NULL), (3, 'c', NULL, X'FF27');
+             CREATE TABLE m(a TEXT, b INTEGER, v, PRIMARY KEY (b, a)) WITHOUT ROWID;
+             INSERT INTO m VALUES ('x', 1, 'one'), ('y', 2, 'two');
+             CREATE TABLE k(v TEXT);",
+        )
+        .await;
+        std::fs::copy(&replica, &working).unwrap();
+        exec(
+            &working,
+            "UPDATE t SET score = 0.1 + 0.2, data = X'0102' WHERE id = 2;
+             DELETE FROM t WHERE id = 3;
+             INSERT INTO t VALUES (4, 'it''s', -1e-300, X'');
+             UPDATE m SET v = 'ONE' WHERE a = 'x';
+             DELETE FROM m WHERE a = 'y';
+             INSERT INTO k VALUES ('no key');",
+        )
+        .await;
+        let changes = diff_databases(&replica, &working).await.unwrap();
+
+        std::fs::copy(&replica, &target).unwrap();
+        let (_db, conn) = open(&target).await;
+        let before = conflict::snapshot_rows(&conn, &changes).await.unwrap();
+        let inverse = invert(&conn, &changes, &before).await.unwrap();
+        assert_eq!((inverse.rows, inverse.skipped), (5, 1));
+        apply(&target, &changes).await;
+
+        // The session extension reads it like any changeset
+        let copy = dir.path("copy.db");
+        std::fs::copy(&working, &copy).unwrap();
+        assert!(changeset::apply_local(&copy, &inverse.data, ConflictPolicy::Abort).unwrap().is_empty());
+        assert_eq!(diff_databases(&replica, &copy).await.unwrap().len(), 1, "the keyless row is left");
+
+        // Rolling back checks the rows still hold what the diff wrote
+        exec(&target, "UPDATE t SET name = 'edited' WHERE id = 4;").await;
+        let (undo, applied) = changeset::to_changes(&changeset::decode(&inverse.data).unwrap(), &conn).await.unwrap();
+        let current = conflict::snapshot_rows(&conn, &undo).await.unwrap();
+        let check = ConflictCheck::detect(&undo, &applied, &current);
+        assert_eq!(check.conflicts.len(), 1);
+        assert_eq!(check.conflicts[0].op, RowOp::Delete);
+
+        exec(&target, "UPDATE t SET name = 'it''s' WHERE id = 4;").await;
+        let current = conflict::snapshot_rows(&conn, &undo).await.unwrap();
+        assert!(ConflictCheck::detect(&undo, &applied, &current).conflicts.is_empty());
+        apply(&target, &undo).await;
+        let left = diff_databases(&replica, &target).await.unwrap();
+        assert_eq!(left.len(), 1, "only the row in the keyless table stays: {:?}", left);
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::Result;
+use clap::Args;
+use log::{error, warn};
+use std::fmt;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+pub const SIGINT: i32 = 2;
+pub const SIGTERM: i32 = 15;
+
+/// The process-wide shutdown request
+static STATE: Shutdown = Shutdown::new();
+
+/// A shutdown request and the tasks waiting for it
+struct Shutdown {
+    /// Signal that asked for the shutdown, 0 while none did
+    signal: AtomicI32,
+    requested: Notify,
+}
+
+impl Shutdown {
+    const fn new() -> Self {
+        Shutdown { signal: AtomicI32::new(0), requested: Notify::const_new() }
+    }
+
+    fn request(&self, signal: i32) -> bool {
+        let first = self.signal.compare_exchange(0, signal, Ordering::SeqCst, Ordering::SeqCst).is_ok();
+        if first {
+            self.requested.notify_waiters();
+        }
+        first
+    }
+
+    fn signal(&self) -> Option<i32> {
+        match self.signal.load(Ordering::SeqCst) {
+            0 => None,
+            signal => Some(signal),
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        match self.signal() {
+            Some(signal) => Err(Interrupted { signal }.into()),
+            None => Ok(()),
+        }
+    }
+
+    async fn wait(&self) {
+        let notified = self.requested.notified();
+        if self.signal().is_some() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// How SIGINT and SIGTERM stop long-running commands
+#[derive(Args, Debug, Clone)]
+pub struct ShutdownOptions {
+    /// Seconds to finish the current batch, sync or child command after SIGINT or SIGTERM before exiting anyway
+    #[arg(long, global = true, default_value = "25", env = "TURSO_SYNC_SHUTDOWN_TIMEOUT")]
+    pub shutdown_timeout: u64,
+}
+
+/// Error of work that stopped early because a shutdown was requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted {
+    pub signal: i32,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "stopped by {}", signal_name(self.signal))
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Handle SIGINT and SIGTERM from now on. The first one asks running work to stop
+/// at its next safe point, see [`check`] and [`wait`]; if it has not stopped once
+/// the timeout passed, or a second signal arrives, the process exits right away.
+/// Either way the exit code is 128 plus the signal number.
+pub fn install(options: &ShutdownOptions) {
+    let timeout = Duration::from_secs(options.shutdown_timeout);
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let (Ok(mut interrupt), Ok(mut terminate)) = (signal(SignalKind::interrupt()), signal(SignalKind::terminate()))
+        else {
+            warn!("Could not install signal handlers; SIGINT and SIGTERM stop the process at once");
+            return;
+        };
+        loop {
+            let received = tokio::select! {
+                _ = interrupt.recv() => SIGINT,
+                _ = terminate.recv() => SIGTERM,
+            };
+            if request(received) {
+                warn!(
+                    "Received {}, stopping after the current batch or sync (at most {}s)",
+                    signal_name(received),
+                    timeout.as_secs()
+                );
+                tokio::spawn(async move {
+                    tokio::time::sleep(timeout).await;
+                    exit_now(&format!("Did not stop within {}s of {}", timeout.as_secs(), signal_name(received)));
+                });
+            } else {
+                exit_now(&format!("Received {} again", signal_name(received)));
+            }
+        }
+    });
+    #[cfg(not(unix))]
+    let _ = timeout;
+}
+
+/// Record a shutdown request for `signal`; false if one was already made
+fn request(signal: i32) -> bool {
+    STATE.request(signal)
+}
+
+/// Signal of the shutdown request, if one was made
+pub fn signal() -> Option<i32> {
+    STATE.signal()
+}
+
+pub fn requested() -> bool {
+    STATE.signal().is_some()
+}
+
+/// Fail with [`Interrupted`] once a shutdown was requested; called between units of
+/// work that are safe to stop after
+pub fn check() -> Result<()> {
+    STATE.check()
+}
+
+/// Wait until a shutdown is requested
+pub async fn wait() {
+    STATE.wait().await
+}
+
+/// Exit code for a shutdown caused by `signal`
+pub fn exit_code(signal: i32) -> u8 {
+    128u8.saturating_add(signal as u8)
+}
+
+pub fn signal_name(signal: i32) -> String {
+    match signal {
+        SIGINT => "SIGINT".to_string(),
+        SIGTERM => "SIGTERM".to_string(),
+        other => format!("signal {}", other),
+    }
+}
+
+fn exit_now(reason: &str) -> ! {
+    let signal = signal().unwrap_or(SIGTERM);
+    error!("{}, exiting without finishing the current work", reason);
+    log::logger().flush();
+    std::process::exit(exit_code(signal) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_request_stops_work_at_the_next_check() {
+        // A state of its own, as requesting the process-wide one would stop other tests
+        static STATE: Shutdown = Shutdown::new();
+        assert!(STATE.check().is_ok());
+        let waiter = tokio::spawn(STATE.wait());
+        tokio::task::yield_now().await;
+
+        assert!(STATE.request(SIGTERM));
+        assert!(!STATE.request(SIGINT));
+        waiter.await.unwrap();
+        STATE.wait().await;
+
+        let error = STATE.check().unwrap_err();
+        assert_eq!(error.downcast_ref::<Interrupted>(), Some(&Interrupted { signal: SIGTERM }));
+        assert_eq!(error.to_string(), "stopped by SIGTERM");
+        assert_eq!(exit_code(SIGTERM), 143);
+    }
+}
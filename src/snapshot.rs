@@ -0,0 +1,255 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use libsql::{Builder, Connection};
+use log::{debug, warn};
+use rusqlite::backup::Backup;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::diff;
+use crate::journal::now;
+use crate::verify;
+
+/// Pages the online backup copies before letting writers to the source in again
+const BACKUP_PAGES_PER_STEP: i32 = 1024;
+
+/// Pause between two backup steps
+const BACKUP_STEP_PAUSE: Duration = Duration::from_millis(10);
+
+/// What happens to unpushed changes in a working copy that is about to be replaced
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnpushedAction {
+    /// Leave the working copy alone and fail
+    Abort,
+    /// Save the changes to a timestamped diff file next to the working copy, then replace it
+    Stash,
+    /// Push the changes to Turso first
+    Push,
+    /// Replace the working copy and lose the changes
+    Discard,
+}
+
+/// Content of a working copy when it last held nothing that Turso lacks, recorded
+/// when it is copied from the replica and after a push that sent all of it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SnapshotState {
+    /// [`content_hash`] of the working copy
+    pub hash: String,
+    /// Unix timestamp of the copy or push
+    pub recorded_at: u64,
+}
+
+/// State path for a working copy: `working_copy.db` -> `working_copy.db.snapshot`
+pub fn state_path(working_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.snapshot", working_path))
+}
+
+impl SnapshotState {
+    /// State recorded for a working copy, if any
+    pub fn load(working_path: &str) -> Result<Option<SnapshotState>> {
+        let path = state_path(working_path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read snapshot state {}", path.display()))?;
+        let state = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse snapshot state {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Write via a temporary file so a crash never leaves a torn state file
+    pub fn save(&self, working_path: &str) -> Result<()> {
+        let path = state_path(working_path);
+        let tmp = path.with_extension("snapshot.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write snapshot state {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to update snapshot state {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Hash over the [`verify::checksums`] of every user table
+pub async fn content_hash(conn: &Connection) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for (table, checksum) in verify::checksums(conn).await? {
+        hasher.update(format!("{}\n{}\n{}\n", table, checksum.rows, checksum.hash));
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Whether copying `source` over `dest` would lose changes: `dest` exists, differs
+/// from `source`, and has been written to since it was last copied or pushed. A
+/// working copy without a recorded state counts as changed unless it matches `source`.
+pub async fn has_unpushed_changes(source: &str, dest: &str) -> Result<bool> {
+    if !Path::new(dest).exists() {
+        return Ok(false);
+    }
+    let hash = hash_of(dest).await?;
+    if SnapshotState::load(dest)?.is_some_and(|state| state.hash == hash) {
+        return Ok(false);
+    }
+    Ok(hash_of(source).await? != hash)
+}
+
+/// Write the changes that turn `source` into `dest` to `<dest>.stash-<unix time>.sql`.
+/// Returns the file and the number of changes in it.
+pub async fn stash(source: &str, dest: &str) -> Result<(String, usize)> {
+    let changes = diff::diff_databases(source, dest).await?;
+    let path = format!("{}.stash-{}.sql", dest, now());
+    fs::write(&path, diff::render_sql(&changes)).with_context(|| format!("Failed to write stash {}", path))?;
+    Ok((path, changes.len()))
+}
+
+/// Replace `dest` with a consistent snapshot of `source`.
+///
+/// The source's WAL is checkpointed first, then the snapshot is taken with SQLite's
+/// online backup API, which reads committed WAL frames too and starts over when
+/// another connection writes to the source meanwhile. The snapshot is written next
+/// to `dest`, checked with [`check_integrity`] and only then renamed over it,
+/// together with removing `dest`'s stale `-wal` and `-shm` files.
+pub async fn copy(source: &str, dest: &str) -> Result<()> {
+    // Opened through libsql before rusqlite touches SQLite, which would otherwise
+    // initialize it with a threading mode libsql refuses to work with
+    let db = Builder::new_local(source).build().await.with_context(|| format!("Failed to open {}", source))?;
+    let conn = db.connect()?;
+    // A checkpoint only fails while other connections hold the WAL; the backup reads it anyway
+    match conn.query("PRAGMA wal_checkpoint(PASSIVE)", ()).await {
+        Ok(mut rows) => {
+            if rows.next().await?.is_some_and(|row| row.get::<i64>(0).ok() != Some(0)) {
+                warn!("Could not checkpoint {}: the database is busy", source);
+            }
+        }
+        Err(e) => warn!("Could not checkpoint {}: {}", source, e),
+    }
+    let mut rows = conn.query("PRAGMA journal_mode", ()).await?;
+    let wal = match rows.next().await? {
+        Some(row) => row.get::<String>(0)?.eq_ignore_ascii_case("wal"),
+        None => false,
+    };
+    drop(rows);
+
+    let tmp = format!("{}.copy-tmp", dest);
+    remove_with_sidecars(&tmp)?;
+    if let Err(e) = snapshot_to(source, &tmp, wal) {
+        let _ = remove_with_sidecars(&tmp);
+        return Err(e);
+    }
+    if let Err(e) = check_integrity(&tmp).await {
+        let _ = remove_with_sidecars(&tmp);
+        return Err(e.context(format!("Copy of {} is damaged", source)));
+    }
+
+    let hash = hash_of(&tmp).await?;
+    remove_sidecars(dest)?;
+    fs::rename(&tmp, dest).with_context(|| format!("Failed to move the copy into place as {}", dest))?;
+    SnapshotState { hash, recorded_at: now() }.save(dest)
+}
+
+/// Fail unless `PRAGMA integrity_check` finds the database at `path` intact
+pub async fn check_integrity(path: &str) -> Result<()> {
+    let db = Builder::new_local(path).build().await.with_context(|| format!("Failed to open {}", path))?;
+    let mut rows = db
+        .connect()?
+        .query("PRAGMA integrity_check", ())
+        .await
+        .with_context(|| format!("Failed to check the integrity of {}", path))?;
+    let mut problems = Vec::new();
+    while let Some(row) = rows.next().await? {
+        problems.push(row.get::<String>(0)?);
+    }
+    if problems != ["ok"] {
+        anyhow::bail!("{} failed the integrity check:\n  {}", path, problems.join("\n  "));
+    }
+    Ok(())
+}
+
+/// Back up `source` into a new database at `tmp`, in WAL mode if `wal` is set
+fn snapshot_to(source: &str, tmp: &str, wal: bool) -> Result<()> {
+    let src = rusqlite::Connection::open(source).with_context(|| format!("Failed to open {}", source))?;
+    let mut dst = rusqlite::Connection::open(tmp).with_context(|| format!("Failed to create {}", tmp))?;
+    Backup::new(&src, &mut dst)
+        .and_then(|backup| backup.run_to_completion(BACKUP_PAGES_PER_STEP, BACKUP_STEP_PAUSE, None))
+        .with_context(|| format!("Failed to back up {}", source))?;
+    if wal {
+        dst.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+    }
+    debug!("Backed up {} to {}", source, tmp);
+    Ok(())
+}
+
+async fn hash_of(path: &str) -> Result<String> {
+    let db = Builder::new_local(path).build().await.with_context(|| format!("Failed to open {}", path))?;
+    content_hash(&db.connect()?).await
+}
+
+fn remove_sidecars(path: &str) -> Result<()> {
+    for sidecar in [format!("{}-wal", path), format!("{}-shm", path), format!("{}-journal", path)] {
+        match fs::remove_file(&sidecar) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Failed to remove {}", sidecar));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn remove_with_sidecars(path: &str) -> Result<()> {
+    remove_sidecars(path)?;
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to remove {}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{exec, open, temp_dir};
+
+    #[tokio::test]
+    async fn copies_uncheckpointed_writes_and_notices_unpushed_changes() {
+        let dir = temp_dir();
+        let (replica, working) = (dir.path("replica.db"), dir.path("working.db"));
+        // Rows still in the WAL of an open connection, which a plain file copy misses
+        let (_db, conn) = open(&replica).await;
+        conn.execute_batch("PRAGMA journal_mode = WAL; CREATE TABLE t(id INTEGER PRIMARY KEY, v);").await.unwrap();
+        conn.execute("INSERT INTO t VALUES (1, 'a'), (2, 'b')", ()).await.unwrap();
+        assert!(fs::metadata(format!("{}-wal", replica)).unwrap().len() > 0);
+
+        assert!(!has_unpushed_changes(&replica, &working).await.unwrap());
+        copy(&replica, &working).await.unwrap();
+        let (_w, working_conn) = open(&working).await;
+        let mut rows = working_conn.query("SELECT count(*) FROM t", ()).await.unwrap();
+        assert_eq!(rows.next().await.unwrap().unwrap().get::<i64>(0).unwrap(), 2);
+        drop(rows);
+        assert!(!has_unpushed_changes(&replica, &working).await.unwrap());
+
+        // Written since the copy: unpushed, until the replica has the same rows
+        exec(&working, "UPDATE t SET v = 'x' WHERE id = 1").await;
+        assert!(has_unpushed_changes(&replica, &working).await.unwrap());
+        conn.execute("UPDATE t SET v = 'x' WHERE id = 1", ()).await.unwrap();
+        assert!(!has_unpushed_changes(&replica, &working).await.unwrap());
+
+        // Once recorded (as a push does), later remote changes do not count as local ones
+        let hash = content_hash(&working_conn).await.unwrap();
+        SnapshotState { hash, recorded_at: now() }.save(&working).unwrap();
+        conn.execute("DELETE FROM t WHERE id = 2", ()).await.unwrap();
+        assert!(!has_unpushed_changes(&replica, &working).await.unwrap());
+
+        exec(&working, "INSERT INTO t VALUES (3, 'c')").await;
+        let (stash_path, stashed) = stash(&replica, &working).await.unwrap();
+        assert_eq!(stashed, 2);
+        let sql = fs::read_to_string(&stash_path).unwrap();
+        assert!(sql.contains("VALUES(2,'b')") && sql.contains("VALUES(3,'c')"), "{}", sql);
+        drop(working_conn);
+        copy(&replica, &working).await.unwrap();
+        assert!(!Path::new(&format!("{}.copy-tmp", working)).exists());
+    }
+}
@@ -0,0 +1,408 @@
+/// Lexical class of a token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// Bare word: keyword or unquoted identifier
+    Word,
+    /// "ident", `ident` or [ident]
+    QuotedIdent,
+    /// 'text' literal
+    String,
+    /// X'hex' literal
+    Blob,
+    Number,
+    /// Bind parameter such as ?, ?1, :name, @name or $name
+    Variable,
+    Semicolon,
+    /// Any other operator or punctuation
+    Punct,
+    Whitespace,
+    Comment,
+}
+
+/// A token borrowed from the source text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+}
+
+impl Token<'_> {
+    /// Whitespace and comments carry no meaning for statement structure
+    pub fn is_trivia(&self) -> bool {
+        matches!(self.kind, TokenKind::Whitespace | TokenKind::Comment)
+    }
+
+    /// Case-insensitive keyword match
+    pub fn is_word(&self, word: &str) -> bool {
+        self.kind == TokenKind::Word && self.text.eq_ignore_ascii_case(word)
+    }
+}
+
+/// Split SQL text into tokens. Unterminated strings and comments run to end of input.
+pub fn tokenize(sql: &str) -> Vec<Token<'_>> {
+    let bytes = sql.as_bytes();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        let start = pos;
+        let c = bytes[pos];
+        let kind = match c {
+            b' ' | b'\t' | b'\n' | b'\r' | b'\x0c' => {
+                while pos < bytes.len() && matches!(bytes[pos], b' ' | b'\t' | b'\n' | b'\r' | b'\x0c') {
+                    pos += 1;
+                }
+                TokenKind::Whitespace
+            }
+            b'-' if bytes.get(pos + 1) == Some(&b'-') => {
+                while pos < bytes.len() && bytes[pos] != b'\n' {
+                    pos += 1;
+                }
+                TokenKind::Comment
+            }
+            b'/' if bytes.get(pos + 1) == Some(&b'*') => {
+                pos = match sql[pos + 2..].find("*/") {
+                    Some(end) => pos + 2 + end + 2,
+                    None => bytes.len(),
+                };
+                TokenKind::Comment
+            }
+            b'\'' => {
+                pos = skip_quoted(bytes, pos, b'\'');
+                TokenKind::String
+            }
+            b'"' | b'`' => {
+                pos = skip_quoted(bytes, pos, c);
+                TokenKind::QuotedIdent
+            }
+            b'[' => {
+                pos = match sql[pos..].find(']') {
+                    Some(end) => pos + end + 1,
+                    None => bytes.len(),
+                };
+                TokenKind::QuotedIdent
+            }
+            b'x' | b'X' if bytes.get(pos + 1) == Some(&b'\'') => {
+                pos = skip_quoted(bytes, pos + 1, b'\'');
+                TokenKind::Blob
+            }
+            b';' => {
+                pos += 1;
+                TokenKind::Semicolon
+            }
+            b'0'..=b'9' => {
+                pos = skip_number(bytes, pos);
+                TokenKind::Number
+            }
+            b'.' if bytes.get(pos + 1).is_some_and(u8::is_ascii_digit) => {
+                pos = skip_number(bytes, pos);
+                TokenKind::Number
+            }
+            b'?' => {
+                pos += 1;
+                while pos < bytes.len() && bytes[pos].is_ascii_digit() {
+                    pos += 1;
+                }
+                TokenKind::Variable
+            }
+            b':' | b'@' | b'$' if bytes.get(pos + 1).is_some_and(|b| is_word_byte(*b)) => {
+                pos += 1;
+                while pos < bytes.len() && is_word_byte(bytes[pos]) {
+                    pos += 1;
+                }
+                TokenKind::Variable
+            }
+            _ if is_word_byte(c) => {
+                while pos < bytes.len() && is_word_byte(bytes[pos]) {
+                    pos += 1;
+                }
+                TokenKind::Word
+            }
+            _ => {
+                // Keep multi-byte UTF-8 characters whole
+                pos += sql[pos..].chars().next().map_or(1, char::len_utf8);
+                TokenKind::Punct
+            }
+        };
+        tokens.push(Token { kind, text: &sql[start..pos] });
+    }
+
+    tokens
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+/// Skip a quoted run starting at `pos`, where a doubled quote is an escaped quote
+fn skip_quoted(bytes: &[u8], pos: usize, quote: u8) -> usize {
+    let mut i = pos + 1;
+    while i < bytes.len() {
+        if bytes[i] == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+fn skip_number(bytes: &[u8], pos: usize) -> usize {
+    let mut i = pos;
+    if bytes[i] == b'0' && matches!(bytes.get(i + 1), Some(b'x' | b'X')) {
+        i += 2;
+        while i < bytes.len() && bytes[i].is_ascii_hexdigit() {
+            i += 1;
+        }
+        return i;
+    }
+    while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+        i += 1;
+    }
+    if matches!(bytes.get(i), Some(b'e' | b'E')) {
+        let mut j = i + 1;
+        if matches!(bytes.get(j), Some(b'+' | b'-')) {
+            j += 1;
+        }
+        if bytes.get(j).is_some_and(u8::is_ascii_digit) {
+            i = j;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+        }
+    }
+    i
+}
+
+/// Split SQL text into complete statements.
+///
+/// Semicolons inside string, blob and identifier literals or comments do not end a
+/// statement, and a `CREATE TRIGGER` body runs until the `END` that follows a `;`.
+/// Leading comments are dropped, statements are trimmed, have no trailing `;`, and
+/// empty statements are skipped.
+pub fn split_statements(sql: &str) -> Vec<String> {
+    let tokens = tokenize(sql);
+    let mut statements = Vec::new();
+
+    let mut start: Option<usize> = None;
+    let mut end = 0;
+    // Meaningful tokens seen so far in the current statement, used to detect triggers
+    let mut head: Vec<&Token> = Vec::new();
+    let mut in_trigger = false;
+    // Inside a trigger body: the previous meaningful token was `;`, then `END`
+    let mut after_semicolon = false;
+    let mut after_end = false;
+    let mut offset = 0;
+
+    for token in &tokens {
+        let token_start = offset;
+        offset += token.text.len();
+
+        if token.is_trivia() {
+            continue;
+        }
+
+        if token.kind == TokenKind::Semicolon && (!in_trigger || after_end) {
+            if let Some(s) = start.take() {
+                statements.push(sql[s..end].trim().to_string());
+            }
+            head.clear();
+            in_trigger = false;
+            after_semicolon = false;
+            after_end = false;
+            continue;
+        }
+
+        if start.is_none() {
+            start = Some(token_start);
+        }
+        end = offset;
+
+        if head.len() < 4 {
+            head.push(token);
+            in_trigger = is_create_trigger(&head);
+        }
+
+        if in_trigger {
+            after_end = after_semicolon && token.is_word("END");
+            after_semicolon = token.kind == TokenKind::Semicolon;
+        }
+    }
+
+    if let Some(s) = start {
+        statements.push(sql[s..end].trim().to_string());
+    }
+
+    statements
+}
+
+/// CREATE [TEMP|TEMPORARY] TRIGGER
+fn is_create_trigger(head: &[&Token]) -> bool {
+    match head {
+        [create, trigger, ..] if create.is_word("CREATE") && trigger.is_word("TRIGGER") => true,
+        [create, temp, trigger, ..]
+            if create.is_word("CREATE")
+                && (temp.is_word("TEMP") || temp.is_word("TEMPORARY"))
+                && trigger.is_word("TRIGGER") =>
+        {
+            true
+        }
+        _ => false,
+    }
+}
+
+/// BEGIN/COMMIT/END/ROLLBACK statements that wrap a diff rather than change data
+pub fn is_transaction_control(statement: &str) -> bool {
+    let words: Vec<Token> = tokenize(statement).into_iter().filter(|t| !t.is_trivia()).collect();
+    match words.as_slice() {
+        [first, rest @ ..] if first.is_word("BEGIN") => match rest {
+            [] => true,
+            [t] => t.is_word("TRANSACTION") || is_begin_mode(t),
+            [mode, t] => is_begin_mode(mode) && t.is_word("TRANSACTION"),
+            _ => false,
+        },
+        [first, rest @ ..] if first.is_word("COMMIT") || first.is_word("END") || first.is_word("ROLLBACK") => {
+            matches!(rest, [] | [_]) && rest.iter().all(|t| t.is_word("TRANSACTION"))
+        }
+        _ => false,
+    }
+}
+
+fn is_begin_mode(token: &Token) -> bool {
+    token.is_word("DEFERRED") || token.is_word("IMMEDIATE") || token.is_word("EXCLUSIVE")
+}
+
+/// Helper function to make CREATE statements idempotent
+pub fn make_create_statement_idempotent(statement: &str) -> String {
+    let trimmed = statement.trim();
+    if trimmed.starts_with("CREATE INDEX") && !trimmed.contains("IF NOT EXISTS") {
+        trimmed.replace("CREATE INDEX", "CREATE INDEX IF NOT EXISTS")
+    } else if trimmed.starts_with("CREATE TABLE") && !trimmed.contains("IF NOT EXISTS") {
+        trimmed.replace("CREATE TABLE", "CREATE TABLE IF NOT EXISTS")
+    } else if trimmed.starts_with("CREATE UNIQUE INDEX") && !trimmed.contains("IF NOT EXISTS") {
+        trimmed.replace("CREATE UNIQUE INDEX", "CREATE UNIQUE INDEX IF NOT EXISTS")
+    } else if trimmed.starts_with("CREATE VIEW") && !trimmed.contains("IF NOT EXISTS") {
+        trimmed.replace("CREATE VIEW", "CREATE VIEW IF NOT EXISTS")
+    } else if trimmed.starts_with("CREATE TRIGGER") && !trimmed.contains("IF NOT EXISTS") {
+        trimmed.replace("CREATE TRIGGER", "CREATE TRIGGER IF NOT EXISTS")
+    } else {
+        statement.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (input, expected statements) pairs modelled on real sqldiff output
+    const CORPUS: &[(&str, &[&str])] = &[
+        (
+            "BEGIN TRANSACTION;\nDELETE FROM email_schedules WHERE id=1;\nCOMMIT;\n",
+            &["BEGIN TRANSACTION", "DELETE FROM email_schedules WHERE id=1", "COMMIT"],
+        ),
+        (
+            "INSERT INTO email_schedules(id,skip_reason) VALUES(7,'exclusion window; state=CA');",
+            &["INSERT INTO email_schedules(id,skip_reason) VALUES(7,'exclusion window; state=CA')"],
+        ),
+        (
+            "UPDATE email_templates SET body='It''s here; really' WHERE id=2;UPDATE t SET a=1 WHERE rowid=3;",
+            &[
+                "UPDATE email_templates SET body='It''s here; really' WHERE id=2",
+                "UPDATE t SET a=1 WHERE rowid=3",
+            ],
+        ),
+        (
+            "INSERT INTO t(id,body) VALUES(1,'line one;\nline two;\n');\nDELETE FROM t WHERE id=2;",
+            &["INSERT INTO t(id,body) VALUES(1,'line one;\nline two;\n')", "DELETE FROM t WHERE id=2"],
+        ),
+        (
+            "INSERT INTO files(id,data) VALUES(1,X'3B3B27');INSERT INTO files(id,data) VALUES(2,x'');",
+            &[
+                "INSERT INTO files(id,data) VALUES(1,X'3B3B27')",
+                "INSERT INTO files(id,data) VALUES(2,x'')",
+            ],
+        ),
+        (
+            "DROP TABLE contacts; -- due to schema mismatch\nCREATE TABLE contacts(id INTEGER PRIMARY KEY, \"a;b\" TEXT);",
+            &["DROP TABLE contacts", "CREATE TABLE contacts(id INTEGER PRIMARY KEY, \"a;b\" TEXT)"],
+        ),
+        (
+            "/* header; with 'quote */ DELETE FROM t WHERE id=1; -- trailing; 'comment\n",
+            &["DELETE FROM t WHERE id=1"],
+        ),
+        (
+            "CREATE TRIGGER trg_touch AFTER UPDATE ON contacts BEGIN\n  UPDATE contacts SET updated_at = CURRENT_TIMESTAMP WHERE id = NEW.id;\n  INSERT INTO log(msg) VALUES('touched; ok');\nEND;\nDELETE FROM t WHERE id=1;",
+            &[
+                "CREATE TRIGGER trg_touch AFTER UPDATE ON contacts BEGIN\n  UPDATE contacts SET updated_at = CURRENT_TIMESTAMP WHERE id = NEW.id;\n  INSERT INTO log(msg) VALUES('touched; ok');\nEND",
+                "DELETE FROM t WHERE id=1",
+            ],
+        ),
+        (
+            "CREATE TEMP TRIGGER t1 BEFORE INSERT ON t BEGIN SELECT CASE WHEN NEW.a < 0 THEN RAISE(ABORT, 'neg') END; END;SELECT 1;",
+            &[
+                "CREATE TEMP TRIGGER t1 BEFORE INSERT ON t BEGIN SELECT CASE WHEN NEW.a < 0 THEN RAISE(ABORT, 'neg') END; END",
+                "SELECT 1",
+            ],
+        ),
+        (
+            "CREATE TRIGGER IF NOT EXISTS t2 AFTER DELETE ON t BEGIN DELETE FROM u WHERE id=OLD.id; END ; ",
+            &["CREATE TRIGGER IF NOT EXISTS t2 AFTER DELETE ON t BEGIN DELETE FROM u WHERE id=OLD.id; END"],
+        ),
+        (
+            "UPDATE [odd;table] SET `semi;col`='x' WHERE id=1;;\n;  \nDELETE FROM t WHERE id=2",
+            &["UPDATE [odd;table] SET `semi;col`='x' WHERE id=1", "DELETE FROM t WHERE id=2"],
+        ),
+        (
+            "INSERT INTO t(id,name) VALUES(1,'Zoë; café');",
+            &["INSERT INTO t(id,name) VALUES(1,'Zoë; café')"],
+        ),
+        ("-- only a comment;\n/* and another; */", &[]),
+    ];
+
+    #[test]
+    fn splits_corpus() {
+        for (input, expected) in CORPUS {
+            assert_eq!(split_statements(input), *expected, "input: {}", input);
+        }
+    }
+
+    #[test]
+    fn unterminated_string_runs_to_end() {
+        assert_eq!(split_statements("INSERT INTO t VALUES('a;b"), vec!["INSERT INTO t VALUES('a;b"]);
+    }
+
+    #[test]
+    fn tokenizes_literals() {
+        let kinds: Vec<TokenKind> = tokenize("x'0A' 1.5e3 'it''s' \"q\"\"id\" ?1 :name")
+            .into_iter()
+            .filter(|t| !t.is_trivia())
+            .map(|t| t.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TokenKind::Blob,
+                TokenKind::Number,
+                TokenKind::String,
+                TokenKind::QuotedIdent,
+                TokenKind::Variable,
+                TokenKind::Variable,
+            ]
+        );
+    }
+
+    #[test]
+    fn recognizes_transaction_control() {
+        for stmt in
+            ["BEGIN TRANSACTION", "begin", "BEGIN IMMEDIATE TRANSACTION", "COMMIT", "END TRANSACTION", "ROLLBACK"]
+        {
+            assert!(is_transaction_control(stmt), "{}", stmt);
+        }
+        for stmt in ["BEGIN; SELECT 1", "COMMIT WORK", "DELETE FROM t"] {
+            assert!(!is_transaction_control(stmt), "{}", stmt);
+        }
+    }
+}
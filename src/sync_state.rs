@@ -0,0 +1,58 @@
+use anyhow::{Context, Result};
+use libsql::replication::Replicated;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+use crate::journal::now;
+
+/// Replication position of a replica after its last sync, persisted next to the replica
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SyncState {
+    /// Replication frame the replica reached; the server may reset it to a lower value
+    pub frame_no: Option<u64>,
+    /// Frames pulled by that sync
+    pub frames_synced: usize,
+    /// Unix timestamp of the sync
+    pub synced_at: u64,
+}
+
+/// State path for a replica: `local_replica.db` -> `local_replica.db.sync-state`
+pub fn state_path(replica_path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.sync-state", replica_path))
+}
+
+impl SyncState {
+    pub fn from_replicated(replicated: &Replicated) -> Self {
+        SyncState { frame_no: replicated.frame_no(), frames_synced: replicated.frames_synced(), synced_at: now() }
+    }
+
+    /// State recorded by the last sync of a replica, if any
+    pub fn load(replica_path: &str) -> Result<Option<SyncState>> {
+        let path = state_path(replica_path);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read sync state {}", path.display()))?;
+        let state =
+            serde_json::from_str(&content).with_context(|| format!("Failed to parse sync state {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Write via a temporary file so a crash never leaves a torn state file
+    pub fn save(&self, replica_path: &str) -> Result<()> {
+        let path = state_path(replica_path);
+        let tmp = path.with_extension("sync-state.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Failed to write sync state {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to update sync state {}", path.display()))?;
+        Ok(())
+    }
+
+    /// One-line description for logs and conflict reports
+    pub fn describe(&self) -> String {
+        let frame = self.frame_no.map_or_else(|| "no frame".to_string(), |f| format!("frame {}", f));
+        format!("{}, {}s ago", frame, now().saturating_sub(self.synced_at))
+    }
+}
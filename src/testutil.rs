@@ -0,0 +1,56 @@
+//! Temporary SQLite databases for tests
+
+use libsql::{Builder, Connection, Database};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::change::Change;
+use crate::diff;
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// A scratch directory, removed with everything in it when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Path of `name` inside the directory
+    pub fn path(&self, name: &str) -> String {
+        self.0.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A fresh scratch directory
+pub fn temp_dir() -> TempDir {
+    let path = std::env::temp_dir().join(format!(
+        "turso-sync-test-{}-{}",
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+    ));
+    fs::create_dir_all(&path).expect("create temp dir");
+    TempDir(path)
+}
+
+/// Open (creating if needed) the database at `path`
+pub async fn open(path: &str) -> (Database, Connection) {
+    let db = Builder::new_local(path).build().await.expect("open database");
+    let conn = db.connect().expect("connect");
+    (db, conn)
+}
+
+/// Run `sql` against the database at `path`, creating it if needed
+pub async fn exec(path: &str, sql: &str) {
+    let (_db, conn) = open(path).await;
+    conn.execute_batch(sql).await.expect("execute SQL");
+}
+
+/// Apply `changes` to the database at `path` as one rendered diff
+pub async fn apply(path: &str, changes: &[Change]) {
+    exec(path, &diff::render_sql(changes)).await;
+}
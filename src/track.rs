@@ -0,0 +1,373 @@
+use anyhow::{Context, Result};
+use libsql::{Connection, Value};
+use log::debug;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::change::{quote_ident, sql_literal, Change, RowChange, RowOp};
+use crate::conflict::{self, RowValues};
+use crate::diff::{self, compare_values};
+
+/// Table the capture triggers append to
+pub const CHANGE_LOG: &str = "_turso_sync_changes";
+
+/// Prefix of the capture triggers; `diff` skips every object named like this
+const TRIGGER_PREFIX: &str = "_turso_sync_capture_";
+
+/// Largest number of logged keys evaluated by one query
+const KEYS_PER_QUERY: usize = 500;
+
+/// Row changes rebuilt from the change log
+#[derive(Debug)]
+pub struct Capture {
+    pub changes: Vec<Change>,
+    /// Log entries read, including repeated writes to the same row
+    pub logged: usize,
+    /// Highest log sequence covered; `acknowledge` it once the changes are applied
+    pub last_seq: i64,
+}
+
+/// Whether capture triggers were installed in the database
+pub async fn is_installed(conn: &Connection) -> Result<bool> {
+    let mut rows = conn
+        .query("SELECT 1 FROM sqlite_schema WHERE type = 'table' AND name = ?", [CHANGE_LOG])
+        .await
+        .context("Failed to look for the change log")?;
+    Ok(rows.next().await?.is_some())
+}
+
+/// Install the change log and capture triggers on `tables`, or on every user table
+/// when `tables` is empty. Returns the tracked tables.
+///
+/// Triggers only log which rows were touched; `capture` reads the rows themselves,
+/// so repeated writes to a row cost one log entry each but are pushed once.
+pub async fn install(conn: &Connection, tables: &[String]) -> Result<Vec<String>> {
+    let tables = if tables.is_empty() { user_tables(conn).await? } else { tables.to_vec() };
+
+    let tx = conn.transaction().await.context("Failed to begin transaction")?;
+    tx.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {}(seq INTEGER PRIMARY KEY AUTOINCREMENT, tbl TEXT NOT NULL, op TEXT NOT NULL, key TEXT NOT NULL)",
+            CHANGE_LOG
+        ),
+        (),
+    )
+    .await
+    .context("Failed to create the change log")?;
+
+    for table in &tables {
+        let info = diff::table_info(&tx, table).await?;
+        if info.columns.is_empty() {
+            return Err(anyhow::anyhow!("Table {} does not exist", table));
+        }
+        for sql in trigger_sql(table, &info.key) {
+            tx.execute(&sql, ()).await.with_context(|| format!("Failed to install capture trigger on {}", table))?;
+        }
+        debug!("Tracking {} by ({})", table, info.key.join(", "));
+    }
+    tx.commit().await.context("Failed to commit capture triggers")?;
+    Ok(tables)
+}
+
+/// Tables that have capture triggers installed
+pub async fn tracked_tables(conn: &Connection) -> Result<Vec<String>> {
+    let mut rows = conn
+        .query(
+            "SELECT DISTINCT tbl_name FROM sqlite_schema WHERE type = 'trigger' AND name LIKE ? ESCAPE '\\' ORDER BY tbl_name",
+            [format!("{}%", TRIGGER_PREFIX.replace('_', "\\_"))],
+        )
+        .await
+        .context("Failed to list capture triggers")?;
+    let mut tables = Vec::new();
+    while let Some(row) = rows.next().await? {
+        tables.push(row.get::<String>(0)?);
+    }
+    Ok(tables)
+}
+
+/// Drop the capture triggers and the change log. Returns the number of triggers dropped.
+pub async fn uninstall(conn: &Connection) -> Result<usize> {
+    let mut rows = conn
+        .query(
+            "SELECT name FROM sqlite_schema WHERE type = 'trigger' AND name LIKE ? ESCAPE '\\'",
+            [format!("{}%", TRIGGER_PREFIX.replace('_', "\\_"))],
+        )
+        .await
+        .context("Failed to list capture triggers")?;
+    let mut triggers = Vec::new();
+    while let Some(row) = rows.next().await? {
+        triggers.push(row.get::<String>(0)?);
+    }
+
+    let tx = conn.transaction().await.context("Failed to begin transaction")?;
+    for trigger in &triggers {
+        tx.execute(&format!("DROP TRIGGER {}", quote_ident(trigger)), ()).await?;
+    }
+    tx.execute(&format!("DROP TABLE IF EXISTS {}", CHANGE_LOG), ()).await?;
+    tx.commit().await.context("Failed to drop capture triggers")?;
+    Ok(triggers.len())
+}
+
+/// Rebuild the changes recorded in `working`'s change log, using `base` (the replica
+/// the working copy started from) for the rows as they were before.
+///
+/// Every logged row is compared between both databases, which yields its net change:
+/// a row inserted and deleted again produces nothing, an updated row only its changed
+/// columns. Schema changes are not captured.
+pub async fn capture(base: &Connection, working: &Connection) -> Result<Capture> {
+    let mut rows = working
+        .query(
+            &format!("SELECT tbl, key, min(seq), max(seq), count(*) FROM {} GROUP BY tbl, key ORDER BY 3", CHANGE_LOG),
+            (),
+        )
+        .await
+        .context("Failed to read the change log")?;
+    let mut logged_keys: Vec<(String, String)> = Vec::new();
+    let mut logged = 0;
+    let mut last_seq = 0;
+    while let Some(row) = rows.next().await? {
+        logged_keys.push((row.get(0)?, row.get(1)?));
+        last_seq = last_seq.max(row.get::<i64>(3)?);
+        logged += row.get::<i64>(4)? as usize;
+    }
+    if logged_keys.is_empty() {
+        return Ok(Capture { changes: Vec::new(), logged, last_seq });
+    }
+
+    // Probe changes carrying just the keys, to read both versions of every row
+    let mut infos = HashMap::new();
+    let mut probes = Vec::with_capacity(logged_keys.len());
+    for (table, keys) in group_by_table(&logged_keys) {
+        if !infos.contains_key(table) {
+            infos.insert(table.to_string(), diff::table_info(working, table).await?);
+        }
+        let key_columns = &infos[table].key;
+        for chunk in keys.chunks(KEYS_PER_QUERY) {
+            for key in evaluate_keys(working, chunk).await? {
+                probes.push(Change::Row(RowChange {
+                    table: table.to_string(),
+                    op: RowOp::Delete,
+                    key: key_columns.iter().cloned().zip(key).collect(),
+                    values: Vec::new(),
+                }));
+            }
+        }
+    }
+
+    let before = conflict::snapshot_rows(base, &probes).await?;
+    let after = conflict::snapshot_rows(working, &probes).await?;
+
+    let mut changes = Vec::new();
+    for ((probe, before), after) in probes.into_iter().zip(before).zip(after) {
+        let Change::Row(probe) = probe else { continue };
+        if let Some(change) = net_change(probe, before, after) {
+            changes.push(Change::Row(change));
+        }
+    }
+    Ok(Capture { changes, logged, last_seq })
+}
+
+/// Highest sequence in the change log, or 0 when it is empty
+pub async fn last_seq(conn: &Connection) -> Result<i64> {
+    let mut rows = conn
+        .query(&format!("SELECT coalesce(max(seq), 0) FROM {}", CHANGE_LOG), ())
+        .await
+        .context("Failed to read the change log")?;
+    match rows.next().await? {
+        Some(row) => Ok(row.get(0)?),
+        None => Ok(0),
+    }
+}
+
+/// Remove log entries up to `last_seq` once their changes reached the remote.
+/// Entries logged while the push ran are kept for the next one.
+pub async fn acknowledge(conn: &Connection, last_seq: i64) -> Result<u64> {
+    conn.execute(&format!("DELETE FROM {} WHERE seq <= ?", CHANGE_LOG), [last_seq])
+        .await
+        .context("Failed to trim the change log")
+}
+
+/// User tables that can be tracked (and compared): no internal or virtual tables
+pub async fn user_tables(conn: &Connection) -> Result<Vec<String>> {
+    let mut rows = conn
+        .query(
+            "SELECT name, sql FROM sqlite_schema WHERE type = 'table' \
+             AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\' \
+             AND name NOT LIKE 'libsql\\_%' ESCAPE '\\' \
+             AND name NOT LIKE '\\_turso\\_sync\\_%' ESCAPE '\\' ORDER BY name",
+            (),
+        )
+        .await
+        .context("Failed to list tables")?;
+    let mut tables = Vec::new();
+    while let Some(row) = rows.next().await? {
+        let sql: String = row.get(1)?;
+        if !diff::is_virtual_table(&sql) {
+            tables.push(row.get(0)?);
+        }
+    }
+    Ok(tables)
+}
+
+/// AFTER INSERT/UPDATE/DELETE triggers logging the key of every written row.
+/// An UPDATE that changes the key logs the new key as well.
+fn trigger_sql(table: &str, key: &[String]) -> Vec<String> {
+    let key_of = |row: &str| {
+        key.iter().map(|column| format!("quote({}.{})", row, quote_ident(column))).collect::<Vec<_>>().join("||','||")
+    };
+    let name = |op: &str| quote_ident(&format!("{}{}_{}", TRIGGER_PREFIX, table, op));
+    let log = |op: &str, key: &str, condition: &str| {
+        format!(
+            "INSERT INTO {}(tbl, op, key) SELECT {}, '{}', {}{};",
+            CHANGE_LOG,
+            sql_literal(&Value::Text(table.to_string())),
+            op,
+            key,
+            condition
+        )
+    };
+    let table_ident = quote_ident(table);
+    let (old_key, new_key) = (key_of("OLD"), key_of("NEW"));
+    let key_changed = format!(" WHERE ({}) IS NOT ({})", new_key, old_key);
+    vec![
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS {} AFTER INSERT ON {} BEGIN {} END",
+            name("insert"),
+            table_ident,
+            log("INSERT", &new_key, "")
+        ),
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS {} AFTER UPDATE ON {} BEGIN {} {} END",
+            name("update"),
+            table_ident,
+            log("UPDATE", &old_key, ""),
+            log("INSERT", &new_key, &key_changed)
+        ),
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS {} AFTER DELETE ON {} BEGIN {} END",
+            name("delete"),
+            table_ident,
+            log("DELETE", &old_key, "")
+        ),
+    ]
+}
+
+/// Logged keys grouped by table, each group in log order
+fn group_by_table(logged_keys: &[(String, String)]) -> Vec<(&str, Vec<&str>)> {
+    let mut groups: Vec<(&str, Vec<&str>)> = Vec::new();
+    for (table, key) in logged_keys {
+        match groups.iter_mut().find(|(t, _)| t == table) {
+            Some((_, keys)) => keys.push(key),
+            None => groups.push((table, vec![key])),
+        }
+    }
+    groups
+}
+
+/// Turn logged keys, which are `quote()`d SQL literals, back into values
+async fn evaluate_keys(conn: &Connection, keys: &[&str]) -> Result<Vec<Vec<Value>>> {
+    let rows: Vec<String> = keys.iter().map(|key| format!("({})", key)).collect();
+    let mut result =
+        conn.query(&format!("VALUES{}", rows.join(",")), ()).await.context("Failed to read logged keys")?;
+    let mut values = Vec::with_capacity(keys.len());
+    while let Some(row) = result.next().await? {
+        values.push((0..row.column_count()).map(|i| row.get_value(i)).collect::<libsql::Result<Vec<_>>>()?);
+    }
+    Ok(values)
+}
+
+/// The change turning `before` into `after` for the row identified by `probe`
+fn net_change(probe: RowChange, before: Option<RowValues>, after: Option<RowValues>) -> Option<RowChange> {
+    let is_key = |column: &str| probe.key.iter().any(|(k, _)| k.eq_ignore_ascii_case(column));
+    let (op, values) = match (before, after) {
+        (None, None) => return None,
+        (Some(_), None) => (RowOp::Delete, Vec::new()),
+        (None, Some(after)) => (RowOp::Insert, after.into_iter().filter(|(c, _)| !is_key(c)).collect()),
+        (Some(before), Some(after)) => {
+            let changed: RowValues = after
+                .into_iter()
+                .filter(|(column, new)| {
+                    let old =
+                        before.iter().find(|(c, _)| c.eq_ignore_ascii_case(column)).map_or(&Value::Null, |(_, v)| v);
+                    !is_key(column) && compare_values(old, new) != Ordering::Equal
+                })
+                .collect();
+            if changed.is_empty() {
+                return None;
+            }
+            (RowOp::Update, changed)
+        }
+    };
+    Some(RowChange { op, values, ..probe })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{apply, exec, open, temp_dir};
+
+    #[tokio::test]
+    async fn captured_changes_reproduce_the_working_copy() {
+        let dir = temp_dir();
+        let (replica, working, copy) = (dir.path("replica.db"), dir.path("working.db"), dir.path("copy.db"));
+        exec(
+            &replica,
+            "CREATE TABLE t(id INTEGER PRIMARY KEY, name TEXT, score REAL);
+             INSERT INTO t VALUES (1, 'a', 1.0), (2, 'b', 2.0), (3, 'c', 3.0);
+             CREATE TABLE m(a TEXT, b INTEGER, v, PRIMARY KEY (a, b)) WITHOUT ROWID;
+             INSERT INTO m VALUES ('x', 1, 'one'), ('it''s', 2, X'00FF');",
+        )
+        .await;
+        std::fs::copy(&replica, &working).unwrap();
+        std::fs::copy(&replica, &copy).unwrap();
+
+        let (_db, conn) = open(&working).await;
+        assert_eq!(install(&conn, &[]).await.unwrap(), vec!["m".to_string(), "t".to_string()]);
+        conn.execute_batch(
+            "UPDATE t SET name = 'B' WHERE id = 2;
+             UPDATE t SET name = 'b' WHERE id = 2;
+             UPDATE t SET score = 30.0 WHERE id = 3;
+             DELETE FROM t WHERE id = 1;
+             INSERT INTO t VALUES (4, 'd', NULL);
+             INSERT INTO t VALUES (5, 'gone', NULL);
+             DELETE FROM t WHERE id = 5;
+             UPDATE m SET b = 3 WHERE a = 'it''s';
+             INSERT INTO m VALUES ('y', 1, 'new');",
+        )
+        .await
+        .unwrap();
+
+        let (_base_db, base) = open(&replica).await;
+        let captured = capture(&base, &conn).await.unwrap();
+        assert_eq!(captured.logged, 10);
+        let ops: Vec<(RowOp, &str)> = captured
+            .changes
+            .iter()
+            .map(|c| match c {
+                Change::Row(row) => (row.op, row.table.as_str()),
+                Change::Schema(_) => panic!("schema change captured"),
+            })
+            .collect();
+        // Row 2 was changed back and row 5 never existed before, so neither is pushed
+        assert_eq!(
+            ops,
+            vec![
+                (RowOp::Update, "t"),
+                (RowOp::Delete, "t"),
+                (RowOp::Insert, "t"),
+                (RowOp::Delete, "m"),
+                (RowOp::Insert, "m"),
+                (RowOp::Insert, "m"),
+            ]
+        );
+
+        apply(&copy, &captured.changes).await;
+        let remaining = diff::diff_databases(&copy, &working).await.unwrap();
+        assert!(remaining.is_empty(), "left after applying: {:?}", remaining);
+
+        acknowledge(&conn, captured.last_seq).await.unwrap();
+        assert!(capture(&base, &conn).await.unwrap().changes.is_empty());
+        assert_eq!(uninstall(&conn).await.unwrap(), 6);
+        assert!(!is_installed(&conn).await.unwrap());
+    }
+}
@@ -0,0 +1,174 @@
+use anyhow::{Context, Result};
+use libsql::{Connection, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::change::quote_ident;
+use crate::diff;
+use crate::track;
+
+/// Row count and content hash of one table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableChecksum {
+    pub rows: u64,
+    /// Hex SHA-256 over the column names and every row in key order
+    pub hash: String,
+}
+
+/// How a table differs between the two databases
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// The table only exists on the remote
+    MissingLocally,
+    /// The table only exists locally
+    MissingRemotely,
+    RowCount {
+        local: u64,
+        remote: u64,
+    },
+    /// Same number of rows, but different columns or values
+    Content {
+        rows: u64,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Divergence::MissingLocally => write!(f, "only exists on the remote"),
+            Divergence::MissingRemotely => write!(f, "missing on the remote"),
+            Divergence::RowCount { local, remote } => write!(f, "{} rows locally, {} on the remote", local, remote),
+            Divergence::Content { rows } => write!(f, "content differs ({} rows on both sides)", rows),
+        }
+    }
+}
+
+/// Checksum every user table in `conn`.
+///
+/// Rows are read in key order and hashed with a type tag and length for every value,
+/// so the result only depends on the data, not on the order rows were written in.
+/// It is stricter than `diff`, which treats an INTEGER and an equal REAL as the same.
+pub async fn checksums(conn: &Connection) -> Result<BTreeMap<String, TableChecksum>> {
+    let mut checksums = BTreeMap::new();
+    for table in track::user_tables(conn).await? {
+        let info = diff::table_info(conn, &table).await?;
+        let key: Vec<String> = info.key.iter().map(|c| quote_ident(c)).collect();
+        let columns: Vec<String> = info.columns.iter().map(|c| quote_ident(c)).collect();
+        let sql = format!(
+            "SELECT {} FROM {} ORDER BY {}",
+            key.iter().chain(columns.iter()).cloned().collect::<Vec<_>>().join(", "),
+            quote_ident(&table),
+            key.join(", ")
+        );
+
+        let mut hasher = Sha256::new();
+        for column in &info.columns {
+            hash_bytes(&mut hasher, column.as_bytes());
+        }
+        let mut rows = conn.query(&sql, ()).await.with_context(|| format!("Failed to read rows of {}", table))?;
+        let mut count = 0;
+        while let Some(row) = rows.next().await? {
+            for i in 0..row.column_count() {
+                hash_value(&mut hasher, &row.get_value(i)?);
+            }
+            count += 1;
+        }
+        let hash = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        checksums.insert(table, TableChecksum { rows: count, hash });
+    }
+    Ok(checksums)
+}
+
+/// Tables that differ between the two sets of checksums, in name order
+pub fn compare(
+    local: &BTreeMap<String, TableChecksum>,
+    remote: &BTreeMap<String, TableChecksum>,
+) -> Vec<(String, Divergence)> {
+    let tables: BTreeSet<&String> = local.keys().chain(remote.keys()).collect();
+    tables
+        .into_iter()
+        .filter_map(|table| {
+            let divergence = match (local.get(table), remote.get(table)) {
+                (None, _) => Divergence::MissingLocally,
+                (_, None) => Divergence::MissingRemotely,
+                (Some(l), Some(r)) if l.rows != r.rows => Divergence::RowCount { local: l.rows, remote: r.rows },
+                (Some(l), Some(r)) if l.hash != r.hash => Divergence::Content { rows: l.rows },
+                _ => return None,
+            };
+            Some((table.clone(), divergence))
+        })
+        .collect()
+}
+
+fn hash_bytes(hasher: &mut Sha256, bytes: &[u8]) {
+    hasher.update((bytes.len() as u64).to_be_bytes());
+    hasher.update(bytes);
+}
+
+/// Feed `value` to `hasher` behind a tag for its type
+pub fn hash_value(hasher: &mut Sha256, value: &Value) {
+    match value {
+        Value::Null => hasher.update([0]),
+        Value::Integer(i) => {
+            hasher.update([1]);
+            hasher.update(i.to_be_bytes());
+        }
+        Value::Real(f) => {
+            hasher.update([2]);
+            hasher.update(f.to_bits().to_be_bytes());
+        }
+        Value::Text(text) => {
+            hasher.update([3]);
+            hash_bytes(hasher, text.as_bytes());
+        }
+        Value::Blob(bytes) => {
+            hasher.update([4]);
+            hash_bytes(hasher, bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{exec, open, temp_dir};
+
+    #[tokio::test]
+    async fn reports_the_tables_that_diverge() {
+        let dir = temp_dir();
+        let (local, remote) = (dir.path("local.db"), dir.path("remote.db"));
+        let schema = "CREATE TABLE a(id INTEGER PRIMARY KEY, v);
+                      CREATE TABLE b(k TEXT PRIMARY KEY, v) WITHOUT ROWID;
+                      CREATE TABLE c(v);";
+        exec(&local, schema).await;
+        exec(&remote, schema).await;
+        // Same rows inserted in another order hash alike
+        exec(&local, "INSERT INTO a VALUES (1, 'x'), (2, 2.5); INSERT INTO b VALUES ('p', X'00'), ('q', NULL);").await;
+        exec(&remote, "INSERT INTO b VALUES ('q', NULL), ('p', X'00'); INSERT INTO a VALUES (2, 2.5), (1, 'x');").await;
+        exec(&local, "INSERT INTO c VALUES ('only here'); CREATE TABLE d(x);").await;
+
+        let (_l, local_conn) = open(&local).await;
+        let (_r, remote_conn) = open(&remote).await;
+        let (mut l, mut r) = (checksums(&local_conn).await.unwrap(), checksums(&remote_conn).await.unwrap());
+        assert_eq!(l["a"], r["a"]);
+        assert_eq!(l["b"].rows, 2);
+        assert_eq!(
+            compare(&l, &r),
+            vec![
+                ("c".to_string(), Divergence::RowCount { local: 1, remote: 0 }),
+                ("d".to_string(), Divergence::MissingRemotely),
+            ]
+        );
+
+        // A value that differs only in type (text '2.5' vs real 2.5) is a content difference
+        exec(&remote, "UPDATE a SET v = '2.5' WHERE id = 2; INSERT INTO c VALUES ('only here'); CREATE TABLE d(x);")
+            .await;
+        r = checksums(&remote_conn).await.unwrap();
+        l.remove("b");
+        assert_eq!(
+            compare(&l, &r),
+            vec![("a".to_string(), Divergence::Content { rows: 2 }), ("b".to_string(), Divergence::MissingLocally),]
+        );
+    }
+}
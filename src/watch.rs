@@ -0,0 +1,114 @@
+use std::fs;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Modification time and size of a database file and its `-wal`, `None` for a missing file
+type Fingerprint = [Option<(SystemTime, u64)>; 2];
+
+/// Notices writes to a database by polling its file and WAL, and reports them once
+/// they have stopped for the debounce period.
+///
+/// SQLite in WAL mode appends to `-wal` and only touches the database file at
+/// checkpoints, so both are compared. Polling needs no platform support and works
+/// the same for files written by another process.
+#[derive(Debug)]
+pub struct Watcher {
+    path: String,
+    debounce: Duration,
+    fingerprint: Fingerprint,
+    /// When the files were last seen to change, while those changes are not handled
+    changed_at: Option<Instant>,
+    /// Pending changes are not reported again until the next write or [`Watcher::release`]
+    held: bool,
+}
+
+impl Watcher {
+    pub fn new(path: &str, debounce: Duration) -> Self {
+        Watcher { path: path.to_string(), debounce, fingerprint: fingerprint(path), changed_at: None, held: false }
+    }
+
+    /// Look at the files again. Returns true when they changed since the last
+    /// [`Watcher::reset`] and have not changed for the debounce period before `now`.
+    pub fn poll(&mut self, now: Instant) -> bool {
+        let current = fingerprint(&self.path);
+        if current != self.fingerprint {
+            self.fingerprint = current;
+            self.changed_at = Some(now);
+            self.held = false;
+            return false;
+        }
+        !self.held && self.changed_at.is_some_and(|at| now.duration_since(at) >= self.debounce)
+    }
+
+    /// Whether there are changes that have not been handled yet
+    pub fn pending(&self) -> bool {
+        self.changed_at.is_some()
+    }
+
+    /// Treat the files as they were at the last [`Watcher::poll`] as handled. Anything
+    /// written since, by the caller while handling them or by anyone else, is a new change.
+    pub fn reset(&mut self) {
+        self.changed_at = None;
+        self.held = false;
+    }
+
+    /// Keep the changes pending after handling them failed, but stop reporting them
+    /// until the files change again or [`Watcher::release`] is called
+    pub fn hold(&mut self) {
+        self.held = true;
+    }
+
+    /// Report held changes again at the next poll
+    pub fn release(&mut self) {
+        self.held = false;
+    }
+}
+
+fn fingerprint(path: &str) -> Fingerprint {
+    let stat = |path: &str| fs::metadata(path).ok().map(|m| (m.modified().unwrap_or(SystemTime::UNIX_EPOCH), m.len()));
+    [stat(path), stat(&format!("{}-wal", path))]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_dir;
+
+    #[test]
+    fn reports_changes_once_writes_have_stopped() {
+        let dir = temp_dir();
+        let path = dir.path("working.db");
+        fs::write(&path, "a").unwrap();
+        let mut watcher = Watcher::new(&path, Duration::from_secs(5));
+        let start = Instant::now();
+        assert!(!watcher.poll(start));
+        assert!(!watcher.pending());
+
+        // A write to the WAL counts, and every further write restarts the quiet period
+        fs::write(format!("{}-wal", path), "frame").unwrap();
+        assert!(!watcher.poll(start));
+        assert!(watcher.pending());
+        assert!(!watcher.poll(start + Duration::from_secs(4)));
+        fs::write(&path, "ab").unwrap();
+        assert!(!watcher.poll(start + Duration::from_secs(4)));
+        assert!(!watcher.poll(start + Duration::from_secs(8)));
+        assert!(watcher.poll(start + Duration::from_secs(9)));
+
+        // Held after a failure: pending, but only reported again once released
+        watcher.hold();
+        assert!(!watcher.poll(start + Duration::from_secs(10)));
+        assert!(watcher.pending());
+        watcher.release();
+        assert!(watcher.poll(start + Duration::from_secs(11)));
+
+        watcher.reset();
+        assert!(!watcher.pending());
+        assert!(!watcher.poll(start + Duration::from_secs(20)));
+
+        // A write made while the reported changes were being handled is not lost
+        fs::write(format!("{}-wal", path), "another frame").unwrap();
+        watcher.reset();
+        assert!(!watcher.poll(start + Duration::from_secs(21)));
+        assert!(watcher.pending());
+        assert!(watcher.poll(start + Duration::from_secs(26)));
+    }
+}
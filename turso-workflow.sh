@@ -65,16 +65,6 @@ build_rust() {
     fi
 }
 
-# Check if sqldiff is available
-check_sqldiff() {
-    if ! command -v sqldiff &> /dev/null; then
-        print_error "sqldiff command not found. Please install SQLite tools."
-        print_info "On macOS: brew install sqlite"
-        print_info "On Ubuntu: sudo apt-get install sqlite3"
-        exit 1
-    fi
-}
-
 # Check if bc is available (for timing calculations)
 check_bc() {
     if ! command -v bc &> /dev/null; then
@@ -92,7 +82,6 @@ init() {
     
     check_env
     build_rust
-    check_sqldiff
     
     print_info "Syncing from Turso to local replica..."
     $RUST_BINARY sync --replica-path "$REPLICA_DB"
@@ -111,7 +100,6 @@ push() {
     
     check_env
     build_rust
-    check_sqldiff
     
     if [ ! -f "$REPLICA_DB" ]; then
         print_error "Local replica not found. Run './turso-workflow.sh init' first."
@@ -250,7 +238,7 @@ reset() {
         build_rust
         
         $RUST_BINARY sync --replica-path "$REPLICA_DB"
-        $RUST_BINARY copy --source "$REPLICA_DB" --dest "$WORKING_DB"
+        $RUST_BINARY copy --source "$REPLICA_DB" --dest "$WORKING_DB" --force
         
         print_info "✅ Working copy reset to match Turso"
     else
@@ -260,7 +248,7 @@ reset() {
 
 # Show diff between working copy and replica
 diff() {
-    check_sqldiff
+    build_rust
     
     if [ ! -f "$REPLICA_DB" ] || [ ! -f "$WORKING_DB" ]; then
         print_error "Database files not found. Run './turso-workflow.sh init' first."
@@ -269,7 +257,7 @@ diff() {
     
     print_info "Generating diff between local replica and working copy..."
     
-    sqldiff --transaction "$REPLICA_DB" "$WORKING_DB" > "$DIFF_FILE"
+    $RUST_BINARY diff --replica-path "$REPLICA_DB" --working-path "$WORKING_DB" --diff-file "$DIFF_FILE"
     
     if [ -s "$DIFF_FILE" ]; then
         print_info "Changes detected:"
@@ -294,6 +282,20 @@ workflow() {
     $RUST_BINARY workflow --replica-path "$REPLICA_DB" --working-path "$WORKING_DB"
 }
 
+# Sync, run a command on a fresh working copy and push what it changed
+run_command() {
+    if [ $# -eq 0 ]; then
+        print_error "Usage: ./turso-workflow.sh run <command> [args...]"
+        exit 1
+    fi
+    
+    check_env
+    build_rust
+    
+    print_info "Running $1 on a fresh working copy..."
+    $RUST_BINARY run --replica-path "$REPLICA_DB" --working-path "$WORKING_DB" --diff-file "$DIFF_FILE" -- "$@"
+}
+
 # Status check
 status() {
     print_info "Turso Sync Status:"
@@ -319,12 +321,6 @@ status() {
     
     echo "=================="
     
-    if command -v sqldiff &> /dev/null; then
-        echo "✅ sqldiff: available"
-    else
-        echo "❌ sqldiff: not found"
-    fi
-    
     if [ -f "$RUST_BINARY" ]; then
         echo "✅ Rust binary: built"
     else
@@ -360,7 +356,7 @@ usage() {
     echo ""
     echo "Commands:"
     echo "  init        - Initialize: sync from Turso and create working copy"
-    echo "  push        - Push local changes to Turso using sqldiff (legacy method)"
+    echo "  push        - Push local changes to Turso using the built-in diff (legacy method)"
     echo "  pull        - Pull latest changes from Turso (preserves local changes)"
     echo "  reset       - Reset working copy to match Turso (discards local changes)"
     echo "  diff        - Show differences between working copy and replica"
@@ -371,6 +367,7 @@ usage() {
     echo "  libsql-sync [db-path]   - Bidirectional sync using libSQL sync (recommended)"
     echo "              db-path: path to database file (default: working_copy.db)"
     echo "  workflow    - Start background periodic sync (every 5 minutes)"
+    echo "  run <command> [args...] - Sync, run the command on a fresh working copy and push its changes"
     echo "  status      - Show current status of databases and tools"
     echo ""
     echo "Environment variables required:"
@@ -429,6 +426,10 @@ case "${1:-}" in
     workflow)
         workflow
         ;;
+    run)
+        shift
+        run_command "$@"
+        ;;
     status)
         status
         ;;